@@ -22,7 +22,7 @@ use bevy::{
     prelude::{
         Added, Asset, AssetApp, AssetEvent, AssetId, Assets, Bundle, Commands, Component,
         DespawnRecursiveExt, Entity, EventReader, GlobalTransform, Handle, Image, Plugin, Query,
-        Res, Transform, Update,
+        RemovedComponents, Res, Transform, Update,
     },
     reflect::TypePath,
     utils::HashMap,
@@ -38,7 +38,7 @@ impl Plugin for TiledMapPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.init_asset::<TiledMap>()
             .register_asset_loader(TiledLoader)
-            .add_systems(Update, process_loaded_maps);
+            .add_systems(Update, (process_loaded_maps, despawn_removed_maps));
     }
 }
 
@@ -59,6 +59,12 @@ pub struct TiledLayersStorage {
     pub storage: HashMap<u32, Entity>,
 }
 
+// The map entity a layer was spawned for.
+#[derive(Component)]
+pub struct TiledMapLayer {
+    pub map: Entity,
+}
+
 #[derive(Default, Bundle)]
 pub struct TiledMapBundle {
     pub tiled_map: Handle<TiledMap>,
@@ -146,7 +152,9 @@ impl AssetLoader for TiledLoader {
                                     .parent()
                                     .expect("The asset load context was empty.");
                                 let tile_path = tmx_dir.join(&img.source);
-                                let asset_path = AssetPath::from(tile_path);
+                                // Images come from the same asset source as the map
+                                let asset_path = AssetPath::from(tile_path)
+                                    .with_source(load_context.asset_path().source().clone_owned());
                                 log::info!("Loading tile image from {asset_path:?} as image ({tileset_index}, {tile_id})");
                                 let texture: Handle<Image> = load_context.load(asset_path.clone());
                                 tile_image_offsets
@@ -166,7 +174,9 @@ impl AssetLoader for TiledLoader {
                         .parent()
                         .expect("The asset load context was empty.");
                     let tile_path = tmx_dir.join(&img.source);
-                    let asset_path = AssetPath::from(tile_path);
+                    // Images come from the same asset source as the map
+                    let asset_path = AssetPath::from(tile_path)
+                        .with_source(load_context.asset_path().source().clone_owned());
                     let texture: Handle<Image> = load_context.load(asset_path.clone());
 
                     TilemapTexture::Single(texture.clone())
@@ -199,6 +209,7 @@ pub fn process_loaded_maps(
     maps: Res<Assets<TiledMap>>,
     tile_storage_query: Query<(Entity, &TileStorage)>,
     mut map_query: Query<(
+        Entity,
         &Handle<TiledMap>,
         &mut TiledLayersStorage,
         &TilemapRenderSettings,
@@ -233,7 +244,7 @@ pub fn process_loaded_maps(
     }
 
     for changed_map in changed_maps.iter() {
-        for (map_handle, mut layer_storage, render_settings, transform) in map_query.iter_mut() {
+        for (map_entity, map_handle, mut layer_storage, render_settings, transform) in map_query.iter_mut() {
             // only deal with currently changed map
             if map_handle.id() != *changed_map {
                 continue;
@@ -371,7 +382,7 @@ pub fn process_loaded_maps(
                             }
                         }
 
-                        commands.entity(layer_entity).insert(TilemapBundle {
+                        commands.entity(layer_entity).insert((TiledMapLayer { map: map_entity }, TilemapBundle {
                             grid_size,
                             size: map_size,
                             storage: tile_storage,
@@ -382,7 +393,7 @@ pub fn process_loaded_maps(
                             map_type,
                             render_settings: *render_settings,
                             ..Default::default()
-                        });
+                        }));
 
                         layer_storage
                             .storage
@@ -393,3 +404,24 @@ pub fn process_loaded_maps(
         }
     }
 }
+
+// Despawns the layers and tiles of maps whose entity was despawned or lost its map handle.
+pub fn despawn_removed_maps(
+    mut commands: Commands,
+    mut removed_maps: RemovedComponents<Handle<TiledMap>>,
+    layer_query: Query<(Entity, &TiledMapLayer, &TileStorage)>,
+) {
+    let removed: Vec<Entity> = removed_maps.read().collect();
+    if removed.is_empty() {
+        return;
+    }
+    for (layer_entity, layer, tile_storage) in layer_query.iter() {
+        if !removed.contains(&layer.map) {
+            continue;
+        }
+        for tile in tile_storage.iter().flatten() {
+            commands.entity(*tile).despawn_recursive();
+        }
+        commands.entity(layer_entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,208 @@
+// Tiled World files (`.world`), which place several maps next to each other. Maps are either
+// listed with their position, or matched by file name with a regular expression whose two
+// captures are multiplied into a position.
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt},
+    prelude::{Asset, AssetApp, Plugin},
+    reflect::TypePath,
+};
+use regex::Regex;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Default)]
+pub struct TiledWorldPlugin;
+
+impl Plugin for TiledWorldPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_asset::<TiledWorld>()
+            .register_asset_loader(TiledWorldLoader);
+    }
+}
+
+#[derive(TypePath, Asset, Deserialize, Clone, Debug, Default)]
+pub struct TiledWorld {
+    #[serde(default)]
+    pub maps: Vec<TiledWorldMap>,
+
+    #[serde(default)]
+    pub patterns: Vec<TiledWorldPattern>,
+}
+
+// A map placed by hand in the World view. Positions are in pixels, y pointing down.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TiledWorldMap {
+    // Relative to the world file
+    pub file_name: String,
+    pub x: i32,
+    pub y: i32,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+}
+
+// Every map in the world file's directory whose name matches `regexp` is placed at its first
+// capture times `multiplier_x` plus `offset_x`, and its second capture times `multiplier_y` plus
+// `offset_y`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TiledWorldPattern {
+    pub regexp: String,
+    pub multiplier_x: i32,
+    pub multiplier_y: i32,
+    #[serde(default)]
+    pub offset_x: i32,
+    #[serde(default)]
+    pub offset_y: i32,
+}
+
+// Where a map of the world is, in pixels with y pointing down.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TiledWorldPlacement {
+    // Relative to the world file
+    pub file_name: String,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Debug, Error)]
+pub enum TiledWorldError {
+    /// An [IO](std::io) Error
+    #[error("Could not load Tiled world: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse Tiled world: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid pattern {0:?} in Tiled world: {1}")]
+    Pattern(String, regex::Error),
+}
+
+impl TiledWorld {
+    pub fn from_json(bytes: &[u8]) -> Result<Self, TiledWorldError> {
+        let world: TiledWorld = serde_json::from_slice(bytes)?;
+        for pattern in world.patterns.iter() {
+            Regex::new(&pattern.regexp).map_err(|e| TiledWorldError::Pattern(pattern.regexp.clone(), e))?;
+        }
+        Ok(world)
+    }
+
+    // Where each map is: the listed maps, then the files among `file_names` (the names in the
+    // world file's directory) that match a pattern and aren't listed. The first matching pattern
+    // places a file.
+    pub fn placements<'a>(&self, file_names: impl IntoIterator<Item = &'a str>) -> Vec<TiledWorldPlacement> {
+        let mut placements: Vec<TiledWorldPlacement> = self
+            .maps
+            .iter()
+            .map(|map| TiledWorldPlacement {
+                file_name: map.file_name.clone(),
+                x: map.x,
+                y: map.y,
+            })
+            .collect();
+        let patterns: Vec<(&TiledWorldPattern, Regex)> = self
+            .patterns
+            .iter()
+            .filter_map(|pattern| Some((pattern, Regex::new(&pattern.regexp).ok()?)))
+            .collect();
+        for file_name in file_names {
+            if placements.iter().any(|placement| placement.file_name == file_name) {
+                continue;
+            }
+            let placed = patterns.iter().find_map(|(pattern, regex)| {
+                let captures = regex.captures(file_name)?;
+                let x: i32 = captures.get(1)?.as_str().parse().ok()?;
+                let y: i32 = captures.get(2)?.as_str().parse().ok()?;
+                Some(TiledWorldPlacement {
+                    file_name: file_name.to_string(),
+                    x: x * pattern.multiplier_x + pattern.offset_x,
+                    y: y * pattern.multiplier_y + pattern.offset_y,
+                })
+            });
+            placements.extend(placed);
+        }
+        placements
+    }
+}
+
+// Loads the world file only, the maps are loaded on their own. Patterns are resolved with
+// TiledWorld::placements, as the loader can't list directories.
+pub struct TiledWorldLoader;
+
+impl AssetLoader for TiledWorldLoader {
+    type Asset = TiledWorld;
+    type Settings = ();
+    type Error = TiledWorldError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a Self::Settings,
+        _load_context: &'a mut bevy::asset::LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        TiledWorld::from_json(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        static EXTENSIONS: &[&str] = &["world"];
+        EXTENSIONS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORLD: &str = r#"{
+        "maps": [
+            { "fileName": "start.tmx", "x": 0, "y": 0, "width": 2048, "height": 2048 },
+            { "fileName": "map_1_0.tmx", "x": -2048, "y": 4096 }
+        ],
+        "patterns": [
+            { "regexp": "map_(-?\\d+)_(-?\\d+)\\.tmx", "multiplierX": 2048, "multiplierY": 2048, "offsetY": 1024 },
+            { "regexp": "cave(\\d+)_(\\d+)\\.tmx", "multiplierX": 100, "multiplierY": 10 }
+        ],
+        "type": "world"
+    }"#;
+
+    fn placement(file_name: &str, x: i32, y: i32) -> TiledWorldPlacement {
+        TiledWorldPlacement { file_name: file_name.to_string(), x, y }
+    }
+
+    #[test]
+    fn listed_maps_come_first() {
+        let world = TiledWorld::from_json(WORLD.as_bytes()).unwrap();
+        assert_eq!(world.maps[0].width, 2048);
+        assert_eq!(world.maps[1].height, 0);
+        assert_eq!(world.placements([]), vec![placement("start.tmx", 0, 0), placement("map_1_0.tmx", -2048, 4096)]);
+    }
+
+    #[test]
+    fn patterns_multiply_and_offset_their_captures() {
+        let world = TiledWorld::from_json(WORLD.as_bytes()).unwrap();
+        let placements = world.placements(["map_1_0.tmx", "map_2_-1.tmx", "cave3_4.tmx", "notes.txt", "map_x_1.tmx"]);
+        assert_eq!(placements[2..], [placement("map_2_-1.tmx", 4096, -1024), placement("cave3_4.tmx", 300, 40)]);
+    }
+
+    #[test]
+    fn the_first_matching_pattern_places_a_file() {
+        let world = TiledWorld::from_json(br#"{ "patterns": [
+            { "regexp": "(\\d+)_(\\d+)", "multiplierX": 1, "multiplierY": 1 },
+            { "regexp": "(\\d+)_(\\d+)", "multiplierX": 2, "multiplierY": 2 }
+        ] }"#).unwrap();
+        assert_eq!(world.placements(["3_4.tmx"]), vec![placement("3_4.tmx", 3, 4)]);
+        // Without two numeric captures a pattern places nothing
+        let world = TiledWorld::from_json(br#"{ "patterns": [{ "regexp": "(\\w+)", "multiplierX": 1, "multiplierY": 1 }] }"#).unwrap();
+        assert!(world.placements(["map.tmx"]).is_empty());
+    }
+
+    #[test]
+    fn invalid_worlds_are_refused() {
+        assert!(matches!(TiledWorld::from_json(b"{ \"maps\": 3 }"), Err(TiledWorldError::Json(_))));
+        let invalid_pattern = br#"{ "patterns": [{ "regexp": "map_(", "multiplierX": 1, "multiplierY": 1 }] }"#;
+        assert!(matches!(TiledWorld::from_json(invalid_pattern), Err(TiledWorldError::Pattern(pattern, _)) if pattern == "map_("));
+    }
+}
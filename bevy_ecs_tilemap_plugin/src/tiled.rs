@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_ecs_tilemap::prelude::*;
 
-use crate::helpers::tiled;
+use crate::helpers::{tiled, tiled_world};
 
 pub struct TilesPlugin;
 
@@ -9,6 +9,7 @@ impl Plugin for TilesPlugin {
     fn build(&self, app: &mut App) {
         // Add your network systems, resources, etc. here
         app.add_plugins(TilemapPlugin)
-            .add_plugins(tiled::TiledMapPlugin);
+            .add_plugins(tiled::TiledMapPlugin)
+            .add_plugins(tiled_world::TiledWorldPlugin);
     }
 }
\ No newline at end of file
@@ -8,6 +8,15 @@ use std::sync::Mutex;
 #[derive(Default)]
 pub struct LuaAPIProvider;
 
+/// Sent when a script calls `set_state(key, value)`, a nil value removes the key.
+/// The game forwards it to the server, which keeps the values by script name.
+#[derive(Event, Clone, Debug)]
+pub struct ScriptStateChanged {
+    pub script: String,
+    pub key: String,
+    pub value: Option<String>,
+}
+
 /// the custom Lua api, world is provided via a global pointer,
 /// and callbacks are defined only once at script creation
 impl APIProvider for LuaAPIProvider {
@@ -46,9 +55,34 @@ impl APIProvider for LuaAPIProvider {
 
     fn setup_script(
         &mut self,
-        _: &ScriptData,
-        _: &mut Self::ScriptContext,
+        script_data: &ScriptData,
+        ctx: &mut Self::ScriptContext,
     ) -> Result<(), ScriptError> {
+        // every script has its own context, so `set_state` knows which script called it
+        let ctx = ctx.get_mut().unwrap();
+        let script = script_data.name.to_string();
+
+        ctx.globals()
+            .set(
+                "set_state",
+                ctx.create_function(move |ctx, (key, value): (String, Option<String>)| {
+                    let world = ctx.get_world()?;
+                    let mut world = world.write();
+
+                    let mut events: Mut<Events<ScriptStateChanged>> =
+                        world.get_resource_mut().unwrap();
+                    events.send(ScriptStateChanged {
+                        script: script.clone(),
+                        key,
+                        value,
+                    });
+
+                    Ok(())
+                })
+                .map_err(ScriptError::new_other)?,
+            )
+            .map_err(ScriptError::new_other)?;
+
         Ok(())
     }
 }
@@ -166,6 +200,7 @@ impl Plugin for ScriptPlugin {
             // register bevy_console commands
             .add_console_command::<RunScriptCmd, _>(run_script_cmd)
             .add_console_command::<DeleteScriptCmd, _>(delete_script_cmd)
+            .add_event::<ScriptStateChanged>()
             // choose and register the script hosts you want to use
             .add_script_host::<LuaScriptHost<()>>(PostUpdate)
             .add_api_provider::<LuaScriptHost<()>>(Box::new(LuaAPIProvider))
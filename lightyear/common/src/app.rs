@@ -114,6 +114,12 @@ pub enum Apps {
 impl Apps {
     /// Build the apps with the given settings and CLI options.
     pub fn new(settings: Settings, cli: Cli) -> Self {
+        Self::new_with_client_setup(settings, cli, |_| {})
+    }
+
+    /// Build the apps like [`Apps::new`], calling `client_setup` on the app that runs the client
+    /// before the `DefaultPlugins` are added (e.g. to register asset sources).
+    pub fn new_with_client_setup(settings: Settings, cli: Cli, client_setup: impl Fn(&mut App)) -> Self {
         match cli {
             #[cfg(not(target_family = "wasm"))]
             Cli::HostServer { client_id } => {
@@ -121,7 +127,7 @@ impl Apps {
                     id: client_id.unwrap_or(settings.client.client_id),
                 };
                 let (app, client_config, server_config) =
-                    combined_app(settings, vec![], client_net_config);
+                    combined_app(settings, vec![], client_net_config, &client_setup);
                 Apps::HostServer {
                     app,
                     client_config,
@@ -147,7 +153,7 @@ impl Apps {
                     &settings.shared,
                     transport_config,
                 );
-                let (client_app, client_config) = client_app(settings.clone(), net_config);
+                let (client_app, client_config) = client_app(settings.clone(), net_config, &client_setup);
 
                 // create server app
                 let extra_transport_configs = vec![server::ServerTransport::Channels {
@@ -175,7 +181,7 @@ impl Apps {
                 // use the cli-provided client id if it exists, otherwise use the settings client id
                 let client_id = client_id.unwrap_or(settings.client.client_id);
                 let net_config = get_client_net_config(&settings, client_id);
-                let (app, config) = client_app(settings, net_config);
+                let (app, config) = client_app(settings, net_config, &client_setup);
                 Apps::Client { app, config }
             }
         }
@@ -385,8 +391,14 @@ impl Apps {
 
 /// Build the client app with the `ClientPlugins` added.
 /// Takes in a `net_config` parameter so that we configure the network transport.
-fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, ClientConfig) {
+fn client_app(
+    settings: Settings,
+    net_config: client::NetConfig,
+    client_setup: &dyn Fn(&mut App),
+) -> (App, ClientConfig) {
     let mut app = App::new();
+    app.insert_resource(settings.clone());
+    client_setup(&mut app);
 
     app.add_plugins(
         DefaultPlugins
@@ -424,6 +436,7 @@ fn server_app(
     extra_transport_configs: Vec<server::ServerTransport>,
 ) -> (App, ServerConfig) {
     let mut app = App::new();
+    app.insert_resource(settings.clone());
     if !settings.server.headless {
         app.add_plugins(DefaultPlugins.build().disable::<LogPlugin>());
     } else {
@@ -463,8 +476,11 @@ fn combined_app(
     settings: Settings,
     extra_transport_configs: Vec<server::ServerTransport>,
     client_net_config: client::NetConfig,
+    client_setup: &dyn Fn(&mut App),
 ) -> (App, ClientConfig, ServerConfig) {
     let mut app = App::new();
+    app.insert_resource(settings.clone());
+    client_setup(&mut app);
     app.add_plugins(DefaultPlugins.build().set(LogPlugin {
         level: Level::INFO,
         filter: "wgpu=error,bevy_render=info,bevy_ecs=warn".to_string(),
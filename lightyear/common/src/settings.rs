@@ -93,6 +93,52 @@ pub struct ServerSettings {
 
     /// Which transport to use
     pub transport: Vec<ServerTransports>,
+
+    /// What to do with a remote file upload that was based on an outdated version of the file
+    #[serde(default)]
+    pub remote_file_conflicts: ConflictResolution,
+
+    /// Bytes of remote file data sent to each client per second
+    #[serde(default = "default_remote_file_bandwidth")]
+    pub remote_file_bandwidth: u32,
+
+    /// Bytes of unfinished remote file uploads buffered for each client, further uploads are
+    /// refused until some have finished
+    #[serde(default = "default_remote_file_max_pending_uploads")]
+    pub remote_file_max_pending_uploads: u64,
+
+    /// Seconds a level stays spawned after the last client could see its room
+    #[serde(default = "default_level_idle_timeout")]
+    pub level_idle_timeout: f32,
+
+    /// Seconds between saves of the world state, it's also saved when the server shuts down
+    #[serde(default = "default_world_save_interval")]
+    pub world_save_interval: f32,
+}
+
+fn default_level_idle_timeout() -> f32 {
+    60.0
+}
+
+fn default_world_save_interval() -> f32 {
+    30.0
+}
+
+fn default_remote_file_bandwidth() -> u32 {
+    256 * 1024
+}
+
+fn default_remote_file_max_pending_uploads() -> u64 {
+    128 * 1024 * 1024
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ConflictResolution {
+    /// Refuse the upload, the editor has to merge the latest version by hand
+    Reject,
+    /// Keep the latest version, and save the upload next to it as a conflict copy
+    #[default]
+    KeepBoth,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -117,6 +163,18 @@ pub struct ClientSettings {
 
     /// Possibly add a conditioner to simulate network conditions
     pub(crate) conditioner: Option<Conditioner>,
+
+    /// Bytes of remote files kept in the client cache, the least recently used are removed first
+    #[serde(default = "default_remote_file_cache_size")]
+    pub remote_file_cache_size: u64,
+
+    /// If true, never upload local edits to remote files, they're only reported as divergences
+    #[serde(default)]
+    pub remote_file_read_only: bool,
+}
+
+fn default_remote_file_cache_size() -> u64 {
+    256 * 1024 * 1024
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
@@ -127,10 +185,21 @@ pub struct SharedSettings {
     /// a 32-byte array to authenticate via the Netcode.io protocol
     pub private_key: [u8; 32],
 
+    /// Ed25519 public key clients check the signature of every remote file against
+    pub remote_file_public_key: [u8; 32],
+
+    /// Biggest remote file transfer in bytes, larger ones are refused before anything is buffered
+    #[serde(default = "default_remote_file_max_size")]
+    pub remote_file_max_size: u64,
+
     /// compression options
     pub(crate) compression: CompressionConfig,
 }
 
+fn default_remote_file_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
 #[derive(Resource, Debug, Clone, Deserialize, Serialize)]
 pub struct Settings {
     pub server: ServerSettings,
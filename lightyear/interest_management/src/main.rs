@@ -9,11 +9,16 @@ use lightyear_examples_common::app::Apps;
 use lightyear_examples_common::settings::{read_settings, Settings};
 
 pub fn plugin_main() -> Apps {
+    plugin_main_with_client_setup(|_| {})
+}
+
+// `client_setup` is called on the client app before the DefaultPlugins are added
+pub fn plugin_main_with_client_setup(client_setup: impl Fn(&mut App)) -> Apps {
     let cli = lightyear_examples_common::app::cli();
     let settings_str = include_str!("../assets/settings.ron");
     let settings = read_settings::<Settings>(settings_str);
     // build the bevy app (this adds common plugin such as the DefaultPlugins)
-    let mut apps = Apps::new(settings, cli);
+    let mut apps = Apps::new_with_client_setup(settings, cli, client_setup);
     // add `ClientPlugins` and `ServerPlugins` plugin groups
     apps.add_lightyear_plugins()
         // add our plugins
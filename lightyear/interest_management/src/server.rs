@@ -166,6 +166,22 @@ pub fn get_room_id_from_grid_position(grid_position: Vec2) -> RoomId {
     RoomId(cantor_pairing(bijective_map(grid_position.x as i64), bijective_map(grid_position.y as i64)) as u64)
 }
 
+/// Inverse of `get_room_id_from_grid_position`
+pub fn get_grid_position_from_room_id(room_id: RoomId) -> Vec2 {
+    fn cantor_unpairing(z: i64) -> (i64, i64) {
+        let w = ((((8 * z + 1) as f64).sqrt() - 1.0) / 2.0).floor() as i64;
+        let b = z - (w * w + w) / 2;
+        (w - b, b)
+    }
+
+    fn bijective_unmap(n: i64) -> i64 {
+        if n % 2 == 0 { n / 2 } else { -(n + 1) / 2 }
+    }
+
+    let (x, y) = cantor_unpairing(room_id.0 as i64);
+    Vec2::new(bijective_unmap(x) as f32, bijective_unmap(y) as f32)
+}
+
 /// Here we perform more "immediate" interest management: we will make a circle visible to a client
 /// depending on the distance to the client's entity
 pub(crate) fn interest_management(
@@ -0,0 +1,52 @@
+use std::{io::BufRead, sync::{mpsc::{channel, Receiver}, Mutex}};
+
+use bevy::prelude::*;
+
+// A line typed into the server's terminal. Systems that handle a command read these and pick
+// out the lines starting with their command name.
+#[derive(Event, Clone, Debug)]
+pub struct ServerConsoleLine(pub String);
+
+impl ServerConsoleLine {
+    // The arguments of the line if it's the given command, e.g. `rollback a.tmx 1f2e` for `rollback`
+    pub fn command(&self, name: &str) -> Option<Vec<&str>> {
+        let mut words = self.0.split_whitespace();
+        (words.next()? == name).then(|| words.collect())
+    }
+}
+
+// Lines read from stdin on a background thread, so the server loop never blocks on input
+#[derive(Resource)]
+struct ServerConsoleInput(Mutex<Receiver<String>>);
+
+// ################################################################################################
+
+pub struct ServerConsolePlugin;
+
+impl Plugin for ServerConsolePlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        app.insert_resource(ServerConsoleInput(Mutex::new(receiver)));
+        app.add_event::<ServerConsoleLine>();
+        app.add_systems(PreUpdate, console_read);
+    }
+}
+
+fn console_read(input: Res<ServerConsoleInput>, mut lines: EventWriter<ServerConsoleLine>) {
+    for line in input.0.lock().unwrap().try_iter() {
+        let line = line.trim();
+        if !line.is_empty() {
+            lines.send(ServerConsoleLine(line.to_string()));
+        }
+    }
+}
@@ -1,10 +1,21 @@
-use bevy::prelude::*;
+use std::sync::Arc;
+
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
 use bevy_ecs_tilemap_plugin::helpers::tiled;
-use interest_management::{client::{ComponentSyncMode, ConnectionManager, Interpolated, Predicted}, server::{get_grid_position, get_room_id_from_grid_position, GRID_SIZE}, shared::{LastPosition, Position}};
-use lightyear::{prelude::{server::{Replicate, RoomManager, SyncTarget}, AppComponentExt, ChannelDirection, NetworkRelevanceMode, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
+use interest_management::{client::{ComponentSyncMode, ConnectionManager, Interpolated, Predicted}, server::{get_grid_position, get_grid_position_from_room_id, get_room_id_from_grid_position, Global, GRID_SIZE}, shared::{LastPosition, Position}};
+use lightyear::{prelude::{server::{Replicate, RoomId, RoomManager, SyncTarget}, AppComponentExt, ChannelDirection, NetworkRelevanceMode, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
+use lightyear_examples_common::settings::Settings;
 use serde::{Deserialize, Serialize};
 
-use crate::{remote_file::{remotefile_modified, RemoteFileBundle}, script::ScriptBundle};
+use crate::{remote_file::{remote_asset_path, RemoteFileBundle, RemoteFileLoading, RemoteFileName, RemoteFileParent, RemoteFileStore}, script::ScriptBundle};
+
+mod generator;
+mod world;
+
+pub use generator::{LevelGenerator, NoiseLevelGenerator};
+use generator::ServerLevelGenerator;
+pub use world::{GeneratedLevels, WorldLayout, WorldLevel, WorldManifest};
+use world::WORLD_PATH;
 
 // Level
 #[derive(Bundle)]
@@ -76,47 +87,289 @@ impl Plugin for LevelSharedPlugin {
 
 // ################################################################################################
 
-pub struct LevelServerPlugin;
+// Generic over where the maps are kept, the same store as RemoteFileServerPlugin's
+pub struct LevelServerPlugin<S: RemoteFileStore> {
+    store: S,
+    generator: Arc<dyn LevelGenerator<S>>,
+}
+
+impl<S: RemoteFileStore> LevelServerPlugin<S> {
+    pub fn new(store: S) -> Self {
+        Self { store, generator: Arc::new(NoiseLevelGenerator::default()) }
+    }
 
-impl Plugin for LevelServerPlugin {
+    // Replace the generator of the maps that aren't authored, used when the world manifest
+    // turns `generated` on
+    pub fn with_generator(mut self, generator: impl LevelGenerator<S>) -> Self {
+        self.generator = Arc::new(generator);
+        self
+    }
+}
+
+impl<S: RemoteFileStore> Plugin for LevelServerPlugin<S> {
     fn build(&self, app: &mut App) {
         app.init_resource::<ConnectionManager>();
-        app.add_systems(Startup, init);
-    }
-}
-
-pub(crate) fn init(mut commands: Commands, mut room_manager: ResMut<RoomManager>) {
-    const NUM_LEVELS: i32 = 3;
-    for x in -NUM_LEVELS..=NUM_LEVELS {
-        for y in -NUM_LEVELS..=NUM_LEVELS {
-            let position = Vec2::new((x * GRID_SIZE) as f32, (y * GRID_SIZE) as f32);
-            let room_id = get_room_id_from_grid_position(get_grid_position(position));
-            
-            let level_filename = format!("map_{}.tmx", room_id.0);
-            let level_entity = commands.spawn(
-                LevelBundle::new(position, level_filename.clone())
-            ).id();
-            let level_file_entity = commands.spawn(
-                RemoteFileBundle::new(level_filename.clone(), level_entity)
-            ).id();
-
-            let script_filename = format!("scripts/map_{}.lua", room_id.0);
-            let script_entity = commands.spawn(
-                ScriptBundle::new(script_filename.clone(), level_entity)
-            ).id();
-            let script_file_entity = commands.spawn(
-                RemoteFileBundle::new(script_filename.clone(), script_entity)
-            ).id();
-
-            info!("Level spawned, added to room: {:?} {:?}", room_id.0, position);
-            room_manager.add_entity(level_entity, room_id);
-            room_manager.add_entity(level_file_entity, room_id);
-            room_manager.add_entity(script_entity, room_id);
-            room_manager.add_entity(script_file_entity, room_id);
+        app.insert_resource(self.store.clone());
+        app.insert_resource(WorldManifest::new(WORLD_PATH));
+        app.insert_resource(ServerLevelGenerator(self.generator.clone()));
+        app.init_resource::<ActiveLevels>();
+        app.init_resource::<SavedLevels>();
+        app.add_event::<LevelActivated>();
+        app.add_event::<LevelDeactivated>();
+        app.add_systems(Startup, init::<S>.in_set(LevelInit));
+        app.add_systems(Update, (world_reload::<S>, level_activation::<S>).chain().in_set(LevelActivation));
+    }
+}
+
+// The server's level setup at Startup, the world state is restored before it
+#[derive(SystemSet, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LevelInit;
+
+// Spawning and despawning the server's levels. Manifests are sent after it, so a room's manifest
+// has the files of a level that was just spawned.
+#[derive(SystemSet, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LevelActivation;
+
+// Sent on the server when a level was spawned because a client can see its room
+#[derive(Event, Clone, Debug)]
+pub struct LevelActivated {
+    pub level: Entity,
+    pub cell: IVec2,
+    pub room_id: RoomId,
+}
+
+// Sent on the server when a level was despawned, after its room was empty for
+// `level_idle_timeout` seconds or it was removed from the world manifest
+#[derive(Event, Clone, Debug)]
+pub struct LevelDeactivated {
+    pub level: Entity,
+    pub cell: IVec2,
+    pub room_id: RoomId,
+}
+
+// The levels that are spawned, only those whose room a client can see or saw recently
+#[derive(Resource, Default)]
+pub(crate) struct ActiveLevels {
+    cells: HashMap<IVec2, ActiveLevel>,
+}
+
+impl ActiveLevels {
+    pub(crate) fn records(&self) -> Vec<LevelRecord> {
+        self.cells.values()
+            .map(|active_level| LevelRecord { level: active_level.level.clone(), idle: active_level.idle })
+            .collect()
+    }
+}
+
+struct ActiveLevel {
+    level: WorldLevel,
+    // The level and its script, their remotefiles are found by RemoteFileParent
+    entities: Vec<Entity>,
+    // Seconds since a client could last see the room
+    idle: f32,
+}
+
+// An active level as it's saved in the world state
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct LevelRecord {
+    level: WorldLevel,
+    idle: f32,
+}
+
+// The levels that were active when the world state was saved, spawned again by init
+#[derive(Resource, Default)]
+pub(crate) struct SavedLevels(pub(crate) Vec<LevelRecord>);
+
+// Load the world, and spawn the saved levels that are still the same in it. They keep the idle
+// time they had, so they're despawned as usual if nobody comes back.
+pub(crate) fn init<S: RemoteFileStore>(
+    mut commands: Commands,
+    mut room_manager: ResMut<RoomManager>,
+    mut active: ResMut<ActiveLevels>,
+    mut saved: ResMut<SavedLevels>,
+    mut activated: EventWriter<LevelActivated>,
+    mut world: ResMut<WorldManifest>,
+    asset_root: Res<S>,
+) {
+    world.reload(&*asset_root);
+    for LevelRecord { level, idle } in saved.0.drain(..) {
+        let cell = level.cell();
+        if world.level(cell).as_ref() != Some(&level) {
+            info!("Saved level {:?} in cell {:?} isn't in the world anymore", level.map, cell);
+            continue;
         }
+        if asset_root.metadata(&RemoteFileName(level.map.clone())).is_err() {
+            warn!("Saved level {:?} in cell {:?} has no map anymore", level.map, cell);
+            continue;
+        }
+        let entities = spawn_level(&mut commands, &mut room_manager, &level);
+        activated.send(LevelActivated { level: entities[0], cell, room_id: cell_room_id(cell) });
+        active.cells.insert(cell, ActiveLevel { level, entities, idle });
     }
 }
 
+// Levels that were changed or removed in the world manifest are deactivated, changed ones come
+// back with their new files if their room is still in view
+fn world_reload<S: RemoteFileStore>(
+    mut commands: Commands,
+    mut active: ResMut<ActiveLevels>,
+    mut world: ResMut<WorldManifest>,
+    mut deactivated: EventWriter<LevelDeactivated>,
+    time: Res<Time>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(Entity, &RemoteFileParent)>,
+) {
+    if !world.tick(time.delta(), &*asset_root) {
+        return;
+    }
+    let changed: Vec<IVec2> = active.cells.iter()
+        .filter(|(cell, active_level)| world.level(**cell).as_ref() != Some(&active_level.level))
+        .map(|(cell, _)| *cell)
+        .collect();
+    for cell in changed {
+        info!("Level of cell {:?} changed in the world manifest", cell);
+        if let Some(active_level) = active.cells.remove(&cell) {
+            deactivated.send(despawn_level(&mut commands, cell, active_level, &remotefile_query));
+        }
+    }
+}
+
+// Spawn the levels of the rooms clients can see, and despawn the ones nobody has seen for a while.
+// Levels whose map doesn't exist, and can't be generated, aren't spawned until it does.
+#[allow(clippy::too_many_arguments)]
+fn level_activation<S: RemoteFileStore>(
+    mut commands: Commands,
+    mut room_manager: ResMut<RoomManager>,
+    mut active: ResMut<ActiveLevels>,
+    mut activated: EventWriter<LevelActivated>,
+    mut deactivated: EventWriter<LevelDeactivated>,
+    world: Res<WorldManifest>,
+    global: Res<Global>,
+    settings: Res<Settings>,
+    time: Res<Time>,
+    asset_root: Res<S>,
+    generator: Res<ServerLevelGenerator<S>>,
+    remotefile_query: Query<(Entity, &RemoteFileParent)>,
+    mut missing: Local<HashSet<IVec2>>,
+) {
+    let visible: HashSet<IVec2> = global.room_id_to_client_ids.iter()
+        .filter(|(_, client_ids)| !client_ids.is_empty())
+        .map(|(room_id, _)| get_grid_position_from_room_id(*room_id).as_ivec2())
+        .collect();
+
+    for cell in visible.iter() {
+        if let Some(active_level) = active.cells.get_mut(cell) {
+            active_level.idle = 0.0;
+            continue;
+        }
+        let Some(level) = world.level(*cell) else {
+            continue;
+        };
+        if let Some(generated) = world.generated() {
+            generate_missing_map(&*asset_root, &*generator.0, generated.seed, &level);
+        }
+        if asset_root.metadata(&RemoteFileName(level.map.clone())).is_err() {
+            if missing.insert(*cell) {
+                warn!("Map {:?} of cell {:?} doesn't exist, not spawning its level", level.map, cell);
+            }
+            continue;
+        }
+        missing.remove(cell);
+        let entities = spawn_level(&mut commands, &mut room_manager, &level);
+        activated.send(LevelActivated { level: entities[0], cell: *cell, room_id: cell_room_id(*cell) });
+        active.cells.insert(*cell, ActiveLevel { level, entities, idle: 0.0 });
+    }
+
+    let idle_timeout = settings.server.level_idle_timeout;
+    let mut idle_cells = Vec::new();
+    for (cell, active_level) in active.cells.iter_mut().filter(|(cell, _)| !visible.contains(*cell)) {
+        active_level.idle += time.delta_seconds();
+        if active_level.idle >= idle_timeout {
+            idle_cells.push(*cell);
+        }
+    }
+    for cell in idle_cells {
+        info!("Nobody has seen cell {:?} for {} seconds", cell, idle_timeout);
+        if let Some(active_level) = active.cells.remove(&cell) {
+            deactivated.send(despawn_level(&mut commands, cell, active_level, &remotefile_query));
+        }
+    }
+}
+
+// Write a generated map where the level's map should be if there's nothing there. Once written it's
+// an ordinary file, it's kept when the seed changes and can be edited like an authored map.
+fn generate_missing_map<S: RemoteFileStore>(asset_root: &S, generator: &dyn LevelGenerator<S>, seed: u64, level: &WorldLevel) {
+    let map = RemoteFileName(level.map.clone());
+    if asset_root.metadata(&map).is_ok() {
+        return;
+    }
+    let data = match generator.generate(asset_root, seed, level.cell(), &map) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to generate the map of cell {:?}: {}", level.cell(), e);
+            return;
+        }
+    };
+    match asset_root.write(&map, &data) {
+        Ok(()) => info!("Generated map {:?} for cell {:?} with seed {}", map.0, level.cell(), seed),
+        Err(e) => error!("Failed to write the generated map {:?}: {:?}", map.0, e),
+    }
+}
+
+fn cell_room_id(cell: IVec2) -> RoomId {
+    get_room_id_from_grid_position(cell.as_vec2())
+}
+
+// Spawns the level and its script, returns the level entity first
+fn spawn_level(commands: &mut Commands, room_manager: &mut RoomManager, level: &WorldLevel) -> Vec<Entity> {
+    let position = (level.cell() * GRID_SIZE).as_vec2();
+    let room_id = cell_room_id(level.cell());
+
+    let level_entity = commands.spawn(
+        LevelBundle::new(position, level.map.clone())
+    ).id();
+    let level_file_entity = commands.spawn(
+        RemoteFileBundle::new(level.map.clone(), level_entity, room_id)
+    ).id();
+    room_manager.add_entity(level_entity, room_id);
+    room_manager.add_entity(level_file_entity, room_id);
+    let mut entities = vec![level_entity];
+
+    if let Some(script_filename) = &level.script {
+        let script_entity = commands.spawn(
+            ScriptBundle::new(script_filename.clone(), level_entity)
+        ).id();
+        let script_file_entity = commands.spawn(
+            RemoteFileBundle::new(script_filename.clone(), script_entity, room_id)
+        ).id();
+        room_manager.add_entity(script_entity, room_id);
+        room_manager.add_entity(script_file_entity, room_id);
+        entities.push(script_entity);
+    }
+
+    info!("Level {:?} activated in room: {:?} {:?}", level.map, room_id.0, position);
+    entities
+}
+
+// Despawns the level, its script and their remotefiles, including the files they depend on
+fn despawn_level(
+    commands: &mut Commands,
+    cell: IVec2,
+    active_level: ActiveLevel,
+    remotefile_query: &Query<(Entity, &RemoteFileParent)>,
+) -> LevelDeactivated {
+    for (entity, parent) in remotefile_query.iter() {
+        if active_level.entities.contains(&parent.0) {
+            commands.entity(entity).despawn();
+        }
+    }
+    for entity in active_level.entities.iter() {
+        commands.entity(*entity).despawn();
+    }
+    info!("Level {:?} deactivated in cell {:?}", active_level.level.map, cell);
+    LevelDeactivated { level: active_level.entities[0], cell, room_id: cell_room_id(cell) }
+}
+
 // ################################################################################################
 
 pub struct LevelClientPlugin;
@@ -126,24 +379,31 @@ impl Plugin for LevelClientPlugin {
         app
         .add_systems(
             Update,
-        (level_spawn, remotefile_modified::<tiled::TiledMap>)
+        level_spawn
         );
     }
 }
 
+// Load the map once every file of the level's room matches the server, so an outdated version
+// isn't shown and then replaced
 fn level_spawn(
     mut commands: Commands,
-    mut level_query: Query<
+    level_query: Query<
         (Entity, &LevelFileName, &Position),
-        Or<(Added<Interpolated>, Added<Predicted>)>,
+        (Or<(With<Interpolated>, With<Predicted>)>, Without<Handle<tiled::TiledMap>>),
     >,
+    loading: Res<RemoteFileLoading>,
     asset_server: Res<AssetServer>,
 ) {
-    for (entity, level_file_name, position) in &mut level_query {
+    for (entity, level_file_name, position) in &level_query {
+        let room_id = get_room_id_from_grid_position(get_grid_position(position.0));
+        if !loading.is_room_ready(room_id) {
+            continue;
+        }
         info!("Spawning level: {:?}, position: {:?}", level_file_name.0, position);
         
-        // Load the Tiled map using the level file name
-        let map_handle: Handle<tiled::TiledMap> = asset_server.load(&level_file_name.0);
+        // Load the Tiled map the server sent, not our own copy
+        let map_handle: Handle<tiled::TiledMap> = asset_server.load(remote_asset_path(&level_file_name.0));
 
         // Spawn the Tiled map bundle
         commands.entity(entity).insert(tiled::TiledMapBundle {
@@ -0,0 +1,301 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use interest_management::server::GRID_SIZE;
+
+use crate::remote_file::{RemoteFileName, RemoteFileStore};
+
+// Makes the maps of cells nobody authored. The map is written to the asset store like any other
+// file, so it's synced to clients as a RemoteFile and can be opened in Tiled and edited after.
+pub trait LevelGenerator<S: RemoteFileStore>: Send + Sync + 'static {
+    // The TMX of the map of a cell. `map` is where it will be written, relative to assets/, and
+    // the same seed and cell must give the same map.
+    fn generate(&self, asset_root: &S, seed: u64, cell: IVec2, map: &RemoteFileName) -> Result<Vec<u8>, String>;
+}
+
+// The generator the server uses, see LevelServerPlugin::with_generator
+#[derive(Resource)]
+pub(crate) struct ServerLevelGenerator<S: RemoteFileStore>(pub(crate) Arc<dyn LevelGenerator<S>>);
+
+// Where the map of a cell that isn't in the world manifest is written
+pub(crate) fn generated_map_name(cell: IVec2) -> String {
+    format!("generated_{}_{}.tmx", cell.x, cell.y)
+}
+
+// ################################################################################################
+
+// Terrain from value noise, continuous across cells since it's sampled in world tile coordinates
+pub struct NoiseLevelGenerator {
+    // The tileset the map uses, embedded in the map since the Tiled loader can't read external
+    // tilesets
+    pub tileset: RemoteFileName,
+    // Size of the biggest features, in tiles
+    pub feature_size: f32,
+    // Noise value below which each tile is used, the last one is used for everything above
+    pub terrain: Vec<(f32, u32)>,
+}
+
+impl Default for NoiseLevelGenerator {
+    fn default() -> Self {
+        Self {
+            tileset: RemoteFileName("tiled/atlas_32.tsx".to_string()),
+            feature_size: 24.0,
+            // Tile ids of atlas_32.tsx, from the colour swatches and the grey brick
+            terrain: vec![
+                (0.36, 1),  // water
+                (0.42, 5),  // sand
+                (0.62, 6),  // grass
+                (0.74, 7),  // light grass
+                (1.0, 96),  // rock
+            ],
+        }
+    }
+}
+
+impl<S: RemoteFileStore> LevelGenerator<S> for NoiseLevelGenerator {
+    fn generate(&self, asset_root: &S, seed: u64, cell: IVec2, map: &RemoteFileName) -> Result<Vec<u8>, String> {
+        let tileset = asset_root.read(&self.tileset)
+            .map_err(|e| format!("can't read the tileset {:?}: {}", self.tileset.0, e))?;
+        let tileset = String::from_utf8_lossy(&tileset);
+        let tileset = embedded_tileset(&tileset, &self.tileset, map)?;
+        let tile_width = xml_attribute(&tileset, "tilewidth").and_then(|value| value.parse::<i32>().ok())
+            .ok_or_else(|| format!("{:?} has no tilewidth", self.tileset.0))?;
+        let tile_height = xml_attribute(&tileset, "tileheight").and_then(|value| value.parse::<i32>().ok())
+            .ok_or_else(|| format!("{:?} has no tileheight", self.tileset.0))?;
+        let width = GRID_SIZE / tile_width;
+        let height = GRID_SIZE / tile_height;
+
+        // Rows go down in TMX and up on the grid, row 0 is the top of the cell
+        let mut rows = Vec::with_capacity(height as usize);
+        for row in 0..height {
+            let y = (cell.y + 1) * height - 1 - row;
+            let tiles: Vec<String> = (0..width)
+                .map(|column| {
+                    let x = cell.x * width + column;
+                    let value = fractal_noise(seed, x as f32 / self.feature_size, y as f32 / self.feature_size);
+                    let tile = self.terrain.iter()
+                        .find(|(below, _)| value < *below)
+                        .or(self.terrain.last())
+                        .map_or(0, |(_, tile)| *tile);
+                    // Global tile ids start at the tileset's firstgid, 1
+                    (tile + 1).to_string()
+                })
+                .collect();
+            rows.push(tiles.join(","));
+        }
+
+        let tmx = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<map version=\"1.10\" tiledversion=\"1.10.2\" orientation=\"orthogonal\" renderorder=\"right-down\" ",
+                "width=\"{width}\" height=\"{height}\" tilewidth=\"{tile_width}\" tileheight=\"{tile_height}\" ",
+                "infinite=\"0\" nextlayerid=\"2\" nextobjectid=\"1\">\n",
+                " <properties>\n",
+                "  <property name=\"generated_seed\" value=\"{seed}\"/>\n",
+                " </properties>\n",
+                " {tileset}\n",
+                " <layer id=\"1\" name=\"Terrain\" width=\"{width}\" height=\"{height}\">\n",
+                "  <data encoding=\"csv\">\n{data}\n</data>\n",
+                " </layer>\n",
+                "</map>\n",
+            ),
+            width = width,
+            height = height,
+            tile_width = tile_width,
+            tile_height = tile_height,
+            seed = seed,
+            tileset = tileset,
+            data = rows.join(",\n"),
+        );
+        Ok(tmx.into_bytes())
+    }
+}
+
+// The <tileset> element of a TSX file as it's written inside a map, with its image relative to
+// the map instead of the tileset
+fn embedded_tileset(tsx: &str, tileset: &RemoteFileName, map: &RemoteFileName) -> Result<String, String> {
+    let start = tsx.find("<tileset").ok_or_else(|| format!("{:?} has no <tileset>", tileset.0))?;
+    let end = tsx.rfind("</tileset>").ok_or_else(|| format!("{:?} has no </tileset>", tileset.0))?;
+    let mut element = format!("<tileset firstgid=\"1\"{}", &tsx[start + "<tileset".len()..end + "</tileset>".len()]);
+
+    let no_source = || format!("The image of {:?} has no source", tileset.0);
+    let image = element.find("<image").ok_or_else(|| format!("{:?} has no <image>", tileset.0))?;
+    let source_start = image + element[image..].find(" source=\"").ok_or_else(no_source)? + " source=\"".len();
+    let source_end = source_start + element[source_start..].find('"').ok_or_else(no_source)?;
+    let source = relative_path(&join(parent(&tileset.0), &element[source_start..source_end]), parent(&map.0));
+    element.replace_range(source_start..source_end, &source);
+    Ok(element)
+}
+
+// The value of the first `name="..."` attribute
+fn xml_attribute(element: &str, name: &str) -> Option<String> {
+    let value = element.split_once(&format!(" {}=\"", name))?.1;
+    Some(value[..value.find('"')?].to_string())
+}
+
+fn parent(file_name: &str) -> &str {
+    file_name.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+// A path relative to a directory, both relative to the asset root, resolving `..`
+fn join(dir: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = dir.split('/').filter(|part| !part.is_empty()).collect();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+// A path relative to the asset root as seen from a directory, e.g. `../tiled/a.png` from `maps`
+fn relative_path(path: &str, from_dir: &str) -> String {
+    let path: Vec<&str> = path.split('/').collect();
+    let from_dir: Vec<&str> = from_dir.split('/').filter(|part| !part.is_empty()).collect();
+    // Only directories are compared, the last part is the file
+    let common = path[..path.len() - 1].iter().zip(from_dir.iter()).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from_dir.len() - common];
+    parts.extend(&path[common..]);
+    parts.join("/")
+}
+
+// ################################################################################################
+
+// A few octaves of value noise, between 0 and 1
+fn fractal_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let mut value = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    let mut total = 0.0;
+    for octave in 0..4 {
+        value += amplitude * value_noise(seed.wrapping_add(octave), x * frequency, y * frequency);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    value / total
+}
+
+// Random values on integer coordinates, smoothly interpolated in between
+fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (smoothstep(x - x0), smoothstep(y - y0));
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = lerp(lattice(seed, x0, y0), lattice(seed, x0 + 1, y0), fx);
+    let bottom = lerp(lattice(seed, x0, y0 + 1), lattice(seed, x0 + 1, y0 + 1), fx);
+    lerp(top, bottom, fy)
+}
+
+// A random value between 0 and 1 for a lattice point, splitmix64 of the seed and coordinates
+fn lattice(seed: u64, x: i64, y: i64) -> f32 {
+    let mut h = seed
+        ^ (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_file::MemoryRemoteFileStore;
+
+    const TILESET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.10" name="atlas" tilewidth="32" tileheight="64" tilecount="4" columns="2">
+ <image source="atlas_32.png" width="64" height="128"/>
+</tileset>
+"#;
+
+    fn name(file_name: &str) -> RemoteFileName {
+        RemoteFileName(file_name.to_string())
+    }
+
+    #[test]
+    fn paths_are_joined_and_made_relative() {
+        assert_eq!(join("maps", "../tiled/atlas.png"), "tiled/atlas.png");
+        assert_eq!(join("", "./tiled/./atlas.png"), "tiled/atlas.png");
+        assert_eq!(join("maps/caves", "..\\atlas.png"), "maps/atlas.png");
+        assert_eq!(join("", "../atlas.png"), "atlas.png");
+
+        assert_eq!(relative_path("tiled/atlas.png", "maps"), "../tiled/atlas.png");
+        assert_eq!(relative_path("tiled/atlas.png", ""), "tiled/atlas.png");
+        assert_eq!(relative_path("maps/atlas.png", "maps"), "atlas.png");
+        assert_eq!(relative_path("maps/caves/atlas.png", "maps"), "caves/atlas.png");
+        assert_eq!(relative_path("atlas.png", "maps/caves"), "../../atlas.png");
+    }
+
+    #[test]
+    fn attributes_are_matched_by_whole_name() {
+        let element = r#"<map tilewidth="32" width="64">"#;
+        assert_eq!(xml_attribute(element, "width").as_deref(), Some("64"));
+        assert_eq!(xml_attribute(element, "tilewidth").as_deref(), Some("32"));
+        assert_eq!(xml_attribute(element, "height"), None);
+    }
+
+    #[test]
+    fn embedded_tilesets_point_at_their_image_from_the_map() {
+        let tileset = embedded_tileset(TILESET, &name("tiled/atlas_32.tsx"), &name("generated_0_0.tmx")).unwrap();
+        assert!(tileset.starts_with(r#"<tileset firstgid="1" version="1.10""#));
+        assert!(tileset.contains(r#"<image source="tiled/atlas_32.png""#));
+        assert!(tileset.ends_with("</tileset>"));
+        let tileset = embedded_tileset(TILESET, &name("tiled/atlas_32.tsx"), &name("maps/cave.tmx")).unwrap();
+        assert!(tileset.contains(r#"<image source="../tiled/atlas_32.png""#));
+
+        let no_image = r#"<tileset name="atlas"></tileset>"#;
+        assert!(embedded_tileset(no_image, &name("tiled/atlas_32.tsx"), &name("generated_0_0.tmx")).is_err());
+        assert!(embedded_tileset("<map/>", &name("tiled/atlas_32.tsx"), &name("generated_0_0.tmx")).is_err());
+    }
+
+    #[test]
+    fn noise_depends_only_on_seed_and_position() {
+        for (x, y) in [(0.0, 0.0), (0.5, 0.25), (-3.7, 12.1), (1000.3, -42.9)] {
+            let value = fractal_noise(42, x, y);
+            assert_eq!(value, fractal_noise(42, x, y));
+            assert!((0.0..=1.0).contains(&value));
+        }
+        // The lattice points are the random values themselves
+        assert_eq!(value_noise(42, 3.0, -2.0), lattice(42, 3, -2));
+        let differs = (0..16).any(|x| fractal_noise(42, x as f32 * 0.3, 0.0) != fractal_noise(43, x as f32 * 0.3, 0.0));
+        assert!(differs);
+    }
+
+    #[test]
+    fn the_same_seed_and_cell_give_the_same_map() {
+        let asset_root = MemoryRemoteFileStore::default();
+        asset_root.write(&name("tiled/atlas_32.tsx"), TILESET.as_bytes()).unwrap();
+        let generator = NoiseLevelGenerator::default();
+        let generate = |seed, cell| generator.generate(&asset_root, seed, cell, &name(&generated_map_name(cell))).unwrap();
+        let map = generate(42, IVec2::new(1, -1));
+        assert_eq!(map, generate(42, IVec2::new(1, -1)));
+        assert_ne!(map, generate(7, IVec2::new(1, -1)));
+        assert_ne!(map, generate(42, IVec2::new(0, -1)));
+
+        let map = String::from_utf8(map).unwrap();
+        assert_eq!(xml_attribute(&map, "width").as_deref(), Some("64"));
+        assert_eq!(xml_attribute(&map, "height").as_deref(), Some("32"));
+        let data = map.split_once("<data encoding=\"csv\">\n").unwrap().1.split_once("\n</data>").unwrap().0;
+        assert_eq!(data.lines().count(), 32);
+        assert_eq!(data.split(',').count(), 64 * 32);
+    }
+
+    #[test]
+    fn generation_needs_the_tileset() {
+        let generator = NoiseLevelGenerator::default();
+        let result = generator.generate(&MemoryRemoteFileStore::default(), 42, IVec2::ZERO, &name("generated_0_0.tmx"));
+        assert!(result.unwrap_err().contains("atlas_32.tsx"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use bevy::{asset::ron, prelude::*, utils::HashMap};
+use bevy_ecs_tilemap_plugin::helpers::tiled_world::{TiledWorld, TiledWorldPlacement};
+use interest_management::server::GRID_SIZE;
+use serde::{Deserialize, Serialize};
+
+use crate::remote_file::{relative_to, RemoteFileName, RemoteFileStore};
+
+use super::generator::generated_map_name;
+
+// Server-side file with the layout of the world, kept out of assets/ so it can't be uploaded
+pub(crate) const WORLD_PATH: &str = "world.ron";
+
+// How often the world file is checked for changes
+const RELOAD_INTERVAL_SECS: f32 = 2.0;
+
+// One cell of the grid, e.g. `(x: -1, y: 0, map: "map_1.tmx", script: Some("scripts/map_1.lua"))`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorldLevel {
+    pub x: i32,
+    pub y: i32,
+    // Relative to assets/
+    pub map: String,
+    #[serde(default)]
+    pub script: Option<String>,
+}
+
+impl WorldLevel {
+    pub fn cell(&self) -> IVec2 {
+        IVec2::new(self.x, self.y)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct WorldLayout {
+    #[serde(default)]
+    pub levels: Vec<WorldLevel>,
+    // A Tiled `.world` file in assets/ with more levels. Levels listed above win over its maps.
+    #[serde(default)]
+    pub tiled_world: Option<String>,
+    // Generate the maps of the cells without a level, and of listed levels whose map doesn't
+    // exist yet, e.g. `generated: Some((seed: 42))`
+    #[serde(default)]
+    pub generated: Option<GeneratedLevels>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct GeneratedLevels {
+    pub seed: u64,
+}
+
+// The levels of the world by grid cell. Cells that aren't listed have no level, unless levels
+// are generated.
+#[derive(Resource)]
+pub struct WorldManifest {
+    path: PathBuf,
+    loaded: bool,
+    // Modification times of the files the levels come from, to only reload when one changed
+    modified: Vec<Option<SystemTime>>,
+    tiled_world: Option<RemoteFileName>,
+    levels: HashMap<IVec2, WorldLevel>,
+    generated: Option<GeneratedLevels>,
+    reload_timer: Timer,
+}
+
+impl WorldManifest {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            loaded: false,
+            modified: Vec::new(),
+            tiled_world: None,
+            levels: HashMap::new(),
+            generated: None,
+            reload_timer: Timer::from_seconds(RELOAD_INTERVAL_SECS, TimerMode::Repeating),
+        }
+    }
+
+    // Check the world files for changes every few seconds, returns whether the levels changed
+    pub(crate) fn tick(&mut self, delta: Duration, asset_root: &impl RemoteFileStore) -> bool {
+        self.reload_timer.tick(delta).just_finished() && self.reload(asset_root)
+    }
+
+    // Re-read the world files if one changed, returns whether the levels changed. On a parse
+    // error the previous levels are kept.
+    pub(crate) fn reload(&mut self, asset_root: &impl RemoteFileStore) -> bool {
+        let modified = self.modified(asset_root);
+        if self.loaded && modified == self.modified {
+            return false;
+        }
+        self.loaded = true;
+        self.modified = modified;
+
+        let layout = match std::fs::read_to_string(&self.path) {
+            Ok(layout) => layout,
+            Err(e) => {
+                warn!("Failed to read {:?}, the world is empty: {:?}", self.path, e);
+                self.tiled_world = None;
+                return self.set_levels(Vec::new(), None);
+            }
+        };
+        let layout = match ron::de::from_str::<WorldLayout>(&layout) {
+            Ok(layout) => layout,
+            Err(e) => {
+                error!("Failed to parse {:?}, keeping the previous levels: {:?}", self.path, e);
+                return false;
+            }
+        };
+        let mut levels = layout.levels;
+        self.tiled_world = layout.tiled_world.map(RemoteFileName);
+        if let Some(tiled_world) = &self.tiled_world {
+            match tiled_world_levels(asset_root, tiled_world) {
+                Ok(tiled_levels) => levels.extend(tiled_levels),
+                Err(e) => error!("Failed to load the levels of {:?}: {}", tiled_world.0, e),
+            }
+            // The Tiled world may have been read for the first time, take its modification time
+            self.modified = self.modified(asset_root);
+        }
+        let changed = self.set_levels(levels, layout.generated);
+        if changed {
+            info!("Loaded {} levels from {:?}", self.levels.len(), self.path);
+        }
+        changed
+    }
+
+    // The world file, and the Tiled world and its directory, where new maps may match a pattern
+    fn modified(&self, asset_root: &impl RemoteFileStore) -> Vec<Option<SystemTime>> {
+        let mut modified = vec![std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()];
+        if let Some(tiled_world) = &self.tiled_world {
+            modified.push(asset_root.metadata(tiled_world).ok().and_then(|metadata| metadata.modified));
+            modified.push(
+                tiled_world_dir(asset_root, tiled_world)
+                    .and_then(|dir| std::fs::metadata(dir).and_then(|metadata| metadata.modified()).ok()),
+            );
+        }
+        modified
+    }
+
+    fn set_levels(&mut self, levels: Vec<WorldLevel>, generated: Option<GeneratedLevels>) -> bool {
+        let mut by_cell: HashMap<IVec2, WorldLevel> = HashMap::new();
+        for level in levels {
+            if let Some(other) = by_cell.get(&level.cell()) {
+                warn!("Cell {:?} has more than one level in {:?}, keeping {:?}", level.cell(), self.path, other.map);
+                continue;
+            }
+            by_cell.insert(level.cell(), level);
+        }
+        let changed = by_cell != self.levels || generated != self.generated;
+        self.levels = by_cell;
+        self.generated = generated;
+        changed
+    }
+
+    // The level of a cell, a generated one if it isn't listed and levels are generated
+    pub fn level(&self, cell: IVec2) -> Option<WorldLevel> {
+        if let Some(level) = self.levels.get(&cell) {
+            return Some(level.clone());
+        }
+        self.generated.map(|_| WorldLevel { x: cell.x, y: cell.y, map: generated_map_name(cell), script: None })
+    }
+
+    pub fn generated(&self) -> Option<GeneratedLevels> {
+        self.generated
+    }
+
+    // Whether a level's map or script, or the Tiled world, is the file. Renaming it would leave
+    // the world pointing at a file that's gone.
+    pub fn refers_to(&self, file_name: &RemoteFileName) -> bool {
+        self.tiled_world.as_ref() == Some(file_name)
+            || self.levels.values().any(|level| level.map == file_name.0 || level.script.as_ref() == Some(&file_name.0))
+    }
+}
+
+// The directory of the Tiled world on disk, if the asset root is on disk
+fn tiled_world_dir(asset_root: &impl RemoteFileStore, tiled_world: &RemoteFileName) -> Option<PathBuf> {
+    let dir = Path::new(&tiled_world.0).parent().unwrap_or(Path::new(""));
+    Some(asset_root.local_path()?.join(dir))
+}
+
+// A level for every map of a Tiled world. Map positions are rounded down to the grid, and a map
+// gets the script with the same name in scripts/ if there is one, e.g. `scripts/map_3.lua`.
+fn tiled_world_levels(asset_root: &impl RemoteFileStore, tiled_world: &RemoteFileName) -> Result<Vec<WorldLevel>, String> {
+    let data = asset_root.read(tiled_world).map_err(|e| e.to_string())?;
+    let world = TiledWorld::from_json(&data).map_err(|e| e.to_string())?;
+
+    // Patterns are matched against the files next to the world file
+    let mut file_names = Vec::new();
+    if !world.patterns.is_empty() {
+        match tiled_world_dir(asset_root, tiled_world) {
+            Some(dir) => {
+                let entries = std::fs::read_dir(&dir).map_err(|e| format!("can't list {:?}: {}", dir, e))?;
+                file_names.extend(entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()));
+            }
+            None => warn!("The maps of {:?} matched by patterns are skipped, the asset root isn't on disk", tiled_world.0),
+        }
+    }
+    let levels = world.placements(file_names.iter().map(String::as_str)).into_iter()
+        .filter(|placement| placement.file_name.ends_with(".tmx"))
+        .filter_map(|TiledWorldPlacement { file_name, x, y }| {
+            // Map paths are relative to the world file, e.g. `../maps/map_3.tmx`
+            let Some(map) = relative_to(tiled_world, &file_name) else {
+                warn!("Map {:?} in {:?} is outside the asset root", file_name, tiled_world.0);
+                return None;
+            };
+            if x % GRID_SIZE != 0 || y % GRID_SIZE != 0 {
+                warn!("Map {:?} at ({}, {}) in {:?} isn't aligned to the {} pixel grid", map.0, x, y, tiled_world.0, GRID_SIZE);
+            }
+            let stem = map.0.rsplit_once('/').map_or(map.0.as_str(), |(_, name)| name).trim_end_matches(".tmx");
+            let script = RemoteFileName(format!("scripts/{}.lua", stem));
+            Some(WorldLevel {
+                // Tiled's y axis points down, the grid's points up
+                x: x.div_euclid(GRID_SIZE),
+                y: -y.div_euclid(GRID_SIZE),
+                map: map.0,
+                script: asset_root.metadata(&script).is_ok().then_some(script.0),
+            })
+        })
+        .collect();
+    Ok(levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_file::MemoryRemoteFileStore;
+
+    #[test]
+    fn tiled_world_maps_are_relative_to_the_world_file() {
+        let asset_root = MemoryRemoteFileStore::default();
+        let tiled_world = RemoteFileName("worlds/main.world".to_string());
+        let world = r#"{ "maps": [
+            { "fileName": "../maps/map_3.tmx", "x": 0, "y": 0 },
+            { "fileName": "cave.tmx", "x": 2048, "y": -4096 },
+            { "fileName": "../../outside.tmx", "x": 4096, "y": 0 }
+        ] }"#;
+        asset_root.write(&tiled_world, world.as_bytes()).unwrap();
+        asset_root.write(&RemoteFileName("scripts/map_3.lua".to_string()), b"").unwrap();
+
+        let levels = tiled_world_levels(&asset_root, &tiled_world).unwrap();
+        assert_eq!(levels, vec![
+            WorldLevel { x: 0, y: 0, map: "maps/map_3.tmx".to_string(), script: Some("scripts/map_3.lua".to_string()) },
+            WorldLevel { x: 1, y: 2, map: "worlds/cave.tmx".to_string(), script: None },
+        ]);
+    }
+}
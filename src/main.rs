@@ -1,25 +1,43 @@
 use interest_management::main as networking;
 use bevy_mod_scripting_plugin::console_integration::ScriptPlugin;
 use bevy_ecs_tilemap_plugin::tiled::TilesPlugin;
+use console::ServerConsolePlugin;
 use player::{PlayerClientPlugin, PlayerServerPlugin, PlayerSharedPlugin};
 use level::{LevelClientPlugin, LevelServerPlugin, LevelSharedPlugin};
-use remote_file::{RemoteFileClientPlugin, RemoteFileServerPlugin, RemoteFileSharedPlugin};
+use persistence::PersistenceServerPlugin;
+use lightyear_examples_common::settings::Settings;
+use remote_file::{RemoteFileAppExt, RemoteFileClientPlugin, RemoteFileContentStore, RemoteFileServerPlugin, RemoteFileSharedPlugin, SandboxedAssetRoot, REMOTE_FILE_CACHE_PATH};
 use script::{ScriptClientPlugin, ScriptServerPlugin, ScriptSharedPlugin};
 
+pub mod console;
 pub mod player;
 pub mod remote_file;
 pub mod level;
+pub mod persistence;
 pub mod script;
+#[cfg(test)]
+mod test_dir;
 fn main() {
     println!("Running in directory: {}", std::env::current_dir().unwrap().display());
 
-    let mut apps = networking::plugin_main();
+    // Levels and scripts are loaded from the files the server sent, through `remote://`
+    let remote_file_cache = RemoteFileContentStore::open(REMOTE_FILE_CACHE_PATH, u64::MAX);
+    let mut apps = networking::plugin_main_with_client_setup(|app| {
+        remote_file_cache.set_max_size(app.world().resource::<Settings>().client.remote_file_cache_size);
+        app.register_remote_file_source(remote_file_cache.clone());
+    });
     apps
         .add_user_client_plugins(ScriptPlugin)
         .add_user_client_plugins(TilesPlugin)
-        .add_user_plugins(RemoteFileClientPlugin, RemoteFileServerPlugin, RemoteFileSharedPlugin)
+        .add_user_server_plugins(ServerConsolePlugin)
+        .add_user_plugins(
+            RemoteFileClientPlugin::new(remote_file_cache, SandboxedAssetRoot::new("assets")),
+            RemoteFileServerPlugin::new(SandboxedAssetRoot::new("assets")),
+            RemoteFileSharedPlugin,
+        )
         .add_user_plugins(PlayerClientPlugin, PlayerServerPlugin, PlayerSharedPlugin)
-        .add_user_plugins(LevelClientPlugin, LevelServerPlugin, LevelSharedPlugin)
-        .add_user_plugins(ScriptClientPlugin, ScriptServerPlugin, ScriptSharedPlugin);
+        .add_user_plugins(LevelClientPlugin, LevelServerPlugin::new(SandboxedAssetRoot::new("assets")), LevelSharedPlugin)
+        .add_user_plugins(ScriptClientPlugin, ScriptServerPlugin, ScriptSharedPlugin)
+        .add_user_server_plugins(PersistenceServerPlugin);
     apps.run();
 }
\ No newline at end of file
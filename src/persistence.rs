@@ -0,0 +1,228 @@
+use std::{path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use bevy::{app::AppExit, asset::ron, prelude::*};
+use lightyear_examples_common::settings::Settings;
+use serde::{Deserialize, Serialize};
+
+use crate::{console::ServerConsoleLine, level::{ActiveLevels, LevelInit, LevelRecord, SavedLevels}, player::PlayerRecords, script::ScriptState};
+
+// Server-side snapshot of the world, next to Cargo.toml like world.ron
+pub(crate) const WORLD_STATE_PATH: &str = "world_state.ron";
+
+// Everything on the server that should survive a restart. Room membership isn't saved, it follows
+// from where the players are once they reconnect.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct WorldState {
+    #[serde(default)]
+    levels: Vec<LevelRecord>,
+    #[serde(default)]
+    players: PlayerRecords,
+    #[serde(default)]
+    scripts: ScriptState,
+}
+
+#[derive(Resource)]
+pub(crate) struct WorldStateFile {
+    path: PathBuf,
+    save_timer: Timer,
+}
+
+impl WorldStateFile {
+    fn new(path: impl Into<PathBuf>, save_interval: f32) -> Self {
+        Self {
+            path: path.into(),
+            save_timer: Timer::from_seconds(save_interval, TimerMode::Repeating),
+        }
+    }
+
+    fn load(&self) -> Option<WorldState> {
+        let data = match std::fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Failed to read {:?}, starting with a new world: {:?}", self.path, e);
+                return None;
+            }
+        };
+        match ron::de::from_str(&data) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                error!("Failed to parse {:?}, starting with a new world: {:?}", self.path, e);
+                None
+            }
+        }
+    }
+
+    // Written next to the file and then moved over it, so a crash while saving keeps the last save
+    fn save(&self, state: &WorldState) {
+        let data = match ron::ser::to_string_pretty(state, ron::ser::PrettyConfig::default()) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to serialize the world state: {:?}", e);
+                return;
+            }
+        };
+        let temp_path = self.path.with_extension("ron.tmp");
+        if let Err(e) = std::fs::write(&temp_path, data).and_then(|_| std::fs::rename(&temp_path, &self.path)) {
+            error!("Failed to save the world state to {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+// Set by Ctrl+C or SIGTERM, which shut the server down through AppExit so the world state is saved
+#[derive(Resource, Default)]
+struct ShutdownSignal(Arc<AtomicBool>);
+
+// ################################################################################################
+
+pub struct PersistenceServerPlugin;
+
+impl Plugin for PersistenceServerPlugin {
+    fn build(&self, app: &mut App) {
+        let save_interval = app.world().resource::<Settings>().server.world_save_interval;
+        app.insert_resource(WorldStateFile::new(WORLD_STATE_PATH, save_interval));
+
+        // A second signal while the server is still shutting down stops it without saving
+        let signal = ShutdownSignal::default();
+        let received = signal.0.clone();
+        let handler = ctrlc::set_handler(move || {
+            if received.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+        });
+        if let Err(e) = handler {
+            warn!("Failed to handle Ctrl+C, stopping the server that way won't save the world state: {:?}", e);
+        }
+        app.insert_resource(signal);
+
+        app.add_systems(Startup, world_state_restore.before(LevelInit));
+        app.add_systems(Update, (world_state_save, world_state_console, world_state_shutdown_signal));
+        app.add_systems(Last, world_state_save_on_exit);
+    }
+}
+
+fn world_state_restore(
+    file: Res<WorldStateFile>,
+    mut saved_levels: ResMut<SavedLevels>,
+    mut players: ResMut<PlayerRecords>,
+    mut scripts: ResMut<ScriptState>,
+) {
+    let Some(state) = file.load() else {
+        return;
+    };
+    info!(
+        "Restored the world state from {:?}: {} levels, {} players",
+        file.path, state.levels.len(), state.players.0.len()
+    );
+    saved_levels.0 = state.levels;
+    *players = state.players;
+    *scripts = state.scripts;
+}
+
+fn world_state_save(
+    mut file: ResMut<WorldStateFile>,
+    time: Res<Time>,
+    active: Res<ActiveLevels>,
+    players: Res<PlayerRecords>,
+    scripts: Res<ScriptState>,
+) {
+    if file.save_timer.tick(time.delta()).just_finished() {
+        file.save(&snapshot(&active, &players, &scripts));
+    }
+}
+
+fn world_state_shutdown_signal(signal: Res<ShutdownSignal>, mut exit: EventWriter<AppExit>, mut sent: Local<bool>) {
+    if signal.0.load(Ordering::Relaxed) && !*sent {
+        info!("Shutting down");
+        exit.send(AppExit::Success);
+        *sent = true;
+    }
+}
+
+fn world_state_save_on_exit(
+    mut exit: EventReader<AppExit>,
+    file: Res<WorldStateFile>,
+    active: Res<ActiveLevels>,
+    players: Res<PlayerRecords>,
+    scripts: Res<ScriptState>,
+) {
+    if exit.read().last().is_some() {
+        info!("Saving the world state to {:?}", file.path);
+        file.save(&snapshot(&active, &players, &scripts));
+    }
+}
+
+// `save` saves the world state now, `quit` shuts the server down, which saves it too
+fn world_state_console(
+    mut lines: EventReader<ServerConsoleLine>,
+    mut exit: EventWriter<AppExit>,
+    file: Res<WorldStateFile>,
+    active: Res<ActiveLevels>,
+    players: Res<PlayerRecords>,
+    scripts: Res<ScriptState>,
+) {
+    for line in lines.read() {
+        if line.command("save").is_some() {
+            file.save(&snapshot(&active, &players, &scripts));
+            info!("Saved the world state to {:?}", file.path);
+        }
+        if line.command("quit").is_some() {
+            exit.send(AppExit::Success);
+        }
+    }
+}
+
+fn snapshot(active: &ActiveLevels, players: &PlayerRecords, scripts: &ScriptState) -> WorldState {
+    WorldState {
+        levels: active.records(),
+        players: players.clone(),
+        scripts: scripts.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{player::PlayerRecord, test_dir::TestDir};
+
+    #[test]
+    fn world_state_round_trips() {
+        let dir = TestDir::new();
+        let file = WorldStateFile::new(dir.join(WORLD_STATE_PATH), 30.0);
+        let level: LevelRecord = ron::de::from_str(r#"(level: (x: -1, y: 0, map: "map_1.tmx", script: Some("scripts/map_1.lua")), idle: 12.5)"#).unwrap();
+        let mut players = PlayerRecords::default();
+        players.0.insert(7, PlayerRecord { position: Vec2::new(-100.0, 2500.0) });
+        let mut scripts = ScriptState::default();
+        scripts.set("scripts/map_1.lua", "door", "open");
+        scripts.set("scripts/map_1.lua", "visits", "3");
+        file.save(&WorldState { levels: vec![level.clone()], players, scripts });
+
+        let state = file.load().unwrap();
+        assert_eq!(state.levels, vec![level]);
+        assert_eq!(state.players.0[&7].position, Vec2::new(-100.0, 2500.0));
+        let values: Vec<(&str, &str)> = state.scripts.values("scripts/map_1.lua").collect();
+        assert_eq!(values, [("door", "open"), ("visits", "3")]);
+        // Only the saved file is left
+        assert!(!file.path.with_extension("ron.tmp").exists());
+    }
+
+    #[test]
+    fn missing_or_unreadable_state_starts_a_new_world() {
+        let dir = TestDir::new();
+        let file = WorldStateFile::new(dir.join(WORLD_STATE_PATH), 30.0);
+        assert!(file.load().is_none());
+        std::fs::write(&file.path, "(levels: [").unwrap();
+        assert!(file.load().is_none());
+    }
+
+    #[test]
+    fn missing_parts_are_empty() {
+        let dir = TestDir::new();
+        let file = WorldStateFile::new(dir.join(WORLD_STATE_PATH), 30.0);
+        std::fs::write(&file.path, "(players: ({3: (position: (1.0, 2.0))}))").unwrap();
+        let state = file.load().unwrap();
+        assert!(state.levels.is_empty());
+        assert_eq!(state.players.0[&3].position, Vec2::new(1.0, 2.0));
+        assert_eq!(state.scripts.values("scripts/map_1.lua").count(), 0);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
+
 use bevy::{ecs::entity::MapEntities, prelude::*, render::RenderPlugin};
 use client::{ComponentSyncMode, Confirmed};
 use leafwing_input_manager::action_state::ActionState;
 use leafwing_input_manager::input_map::InputMap;
-use interest_management::{client::{ClientConnection, Interpolated, NetClient, Predicted}, server::get_room_id_from_grid_position, shared::{Inputs, LastPosition, PlayerId, Position}};
+use interest_management::{client::{ClientConnection, Interpolated, NetClient, Predicted}, server::{get_grid_position, get_room_id_from_grid_position}, shared::{Inputs, LastPosition, PlayerId, Position}};
 use lightyear::prelude::ReplicationGroup;
 use lightyear::prelude::server::{ControlledBy, Replicate, SyncTarget};
 use lightyear::prelude::*;
@@ -253,19 +255,33 @@ pub struct PlayerServerPlugin;
 
 impl Plugin for PlayerServerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, handle_connections);
+        app.init_resource::<PlayerRecords>();
+        app.add_systems(Update, (handle_connections, player_records_update));
     }
 }
 
+// What the server remembers of every player that has connected, by client id, kept in the world
+// state so players come back where they left
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PlayerRecords(pub BTreeMap<u64, PlayerRecord>);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerRecord {
+    pub position: Vec2,
+}
+
 /// Server connection system, create a player upon connection
 pub(crate) fn handle_connections(
     mut room_manager: ResMut<RoomManager>,
     mut connections: EventReader<lightyear::server::events::ConnectEvent>,
     mut commands: Commands,
+    records: Res<PlayerRecords>,
 ) {
     for connection in connections.read() {
-        let position = Vec2::ZERO + Vec2::new(100.0, 100.0);
         let client_id = connection.client_id;
+        let position = records.0.get(&client_id.to_bits())
+            .map(|record| record.position)
+            .unwrap_or(Vec2::ZERO + Vec2::new(100.0, 100.0));
         let entity = commands.spawn(
             PlayerBundle::new(client_id, position)
         ).id();
@@ -273,11 +289,21 @@ pub(crate) fn handle_connections(
             AnimationBundle::new(client_id, entity)
         ).id();
 
-        let room_id = get_room_id_from_grid_position(position);
+        let room_id = get_room_id_from_grid_position(get_grid_position(position));
         room_manager.add_entity(entity, room_id);
     }
 }
 
+// Remember where players are, the entity is gone by the time their disconnect is handled
+fn player_records_update(
+    mut records: ResMut<PlayerRecords>,
+    player_query: Query<(&PlayerId, &Position), Changed<Position>>,
+) {
+    for (player_id, position) in &player_query {
+        records.0.insert(player_id.0.to_bits(), PlayerRecord { position: position.0 });
+    }
+}
+
 // ################################################################################################
 
 pub struct PlayerClientPlugin;
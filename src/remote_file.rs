@@ -1,11 +1,58 @@
-use bevy::{ecs::entity::MapEntities, prelude::*};
-use interest_management::{client::{ComponentSyncMode, ConnectionManager, Interpolated, Predicted}, server::Global};
-use lightyear::{prelude::{server::{Replicate, SyncTarget}, AppComponentExt, AppMessageExt, ChannelDirection, NetworkRelevanceMode, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
+use std::{path::PathBuf, sync::Arc};
+
+use bevy::{ecs::entity::MapEntities, prelude::*, utils::{HashMap, HashSet}};
+use interest_management::{client::{ComponentSyncMode, ConnectionManager}, server::{get_grid_position, get_grid_position_from_room_id, Global}, shared::{PlayerId, Position}};
+use lightyear::{prelude::{server::{Replicate, RoomId, RoomManager, SyncTarget}, AppChannelExt, AppComponentExt, AppMessageExt, Channel, ChannelDirection, ChannelMode, ChannelSettings, NetworkRelevanceMode, ReliableSettings, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
 use serde::{Deserialize, Serialize};
 use lightyear::connection::id::ClientId;
 use sha2::{Digest, Sha256};
 
-use crate::player::Channel1;
+use lightyear_examples_common::settings::{ConflictResolution, Settings};
+
+use crate::{console::ServerConsoleLine, level::{LevelActivation, LevelFileName, WorldManifest}, player::Channel1, script::ScriptFileName};
+
+mod audit;
+mod codec;
+mod content_store;
+mod delta;
+mod dependencies;
+mod history;
+mod loading;
+mod manifest;
+mod origin;
+mod permissions;
+mod prefetch;
+mod sandbox;
+mod source;
+mod store;
+mod transfer;
+mod watcher;
+
+pub use audit::{RemoteFileAuditDecision, RemoteFileAuditEntry, RemoteFileAuditFilter, RemoteFileAuditLog, RemoteFileAuditOperation};
+use audit::AUDIT_LOG_PATH;
+pub use codec::{RemoteFileCodec, RemoteFileCodecs};
+pub use content_store::RemoteFileContentStore;
+pub use delta::{FileSignature, RemoteFileDelta};
+use delta::{apply_delta, MAX_DELTA_DATA_SIZE, MAX_SIGNATURE_FILE_SIZE};
+pub(crate) use dependencies::relative_to;
+use dependencies::with_dependencies;
+pub use history::{RemoteFileHistory, RemoteFileRevision};
+use history::HISTORY_PATH;
+pub use loading::RemoteFileLoading;
+use loading::{remotefile_loading_overlay, remotefile_loading_overlay_spawn, remotefile_loading_progress};
+pub use manifest::{AssetManifest, AssetManifestDiff, AssetManifestEntry, AssetManifestLeft, RemoteFileHashCache};
+pub use permissions::RemoteFilePermissions;
+pub use sandbox::{RemoteFilePathError, SandboxedAssetRoot};
+pub use source::{remote_asset_path, RemoteFileCache, REMOTE_FILE_CACHE_PATH, REMOTE_FILE_SOURCE};
+use source::remotefile_reload;
+pub use store::{MemoryRemoteFileStore, RemoteFileMetadata, RemoteFileStore};
+use origin::{load_signing_key, RemoteFileSigner, SIGNING_KEY_PATH};
+use permissions::{remotefile_permissions_reload, PERMISSIONS_PATH};
+use prefetch::{remotefile_prefetch, RemoteFilePrefetch};
+pub use transfer::{RemoteFileChunk, RemoteFileResume};
+use transfer::{IncomingTransfer, OutgoingTransfer, RemoteFilePayload, CHUNKS_PER_UPDATE, CHUNK_SIZE};
+pub use watcher::{RemoteFileAppExt, RemoteFileIgnore, RemoteFileWatcher, RemoteFileWorkspace};
+use watcher::RemoteFileChange;
 
 // RemoteFile
 #[derive(Bundle)]
@@ -13,10 +60,11 @@ pub(crate) struct RemoteFileBundle {
     parent: RemoteFileParent,
     replicate: Replicate,
     filename: RemoteFileName,
+    room: RemoteFileRoom,
 }
 
 impl RemoteFileBundle {
-    pub(crate) fn new(filename: String, parent: Entity) -> Self {
+    pub(crate) fn new(filename: String, parent: Entity, room_id: RoomId) -> Self {
         let sync_target = SyncTarget {
             prediction: NetworkTarget::All,
             ..default()
@@ -30,36 +78,115 @@ impl RemoteFileBundle {
         Self {
             parent: RemoteFileParent(parent),
             replicate,
-            filename: RemoteFileName(filename)
+            filename: RemoteFileName(filename),
+            room: RemoteFileRoom(room_id),
         }
     }
 }
 
 // 
-#[derive(Default, Component, Serialize, Deserialize, Debug, PartialEq, Clone, Reflect)]
+#[derive(Default, Component, Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Reflect)]
 pub struct RemoteFileName(pub String);
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct RemoteFileHash {
     hash: String,
     file_name: RemoteFileName,
+    // Part of a newer version the client already received before being interrupted
+    resume: Option<RemoteFileResume>,
+    // Block signatures of the client's version, so the server can answer with a delta
+    signature: Option<FileSignature>,
 }
 
+// Sent back for a RemoteFileHash that already matches the server's version, so the client stops
+// waiting for the file
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct RemoteFile {
-    data: Vec<u8>,
-    file_name: RemoteFileName,
+pub struct RemoteFileUnchanged {
+    pub file_name: RemoteFileName,
 }
 
-impl Default for RemoteFile {
-    fn default() -> Self {
-        RemoteFile {
-            data: Vec::new(),
-            file_name: RemoteFileName::default(),
-        }
-    }
+// Sent back to a client whose upload was not accepted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileRejected {
+    pub file_name: RemoteFileName,
+    pub reason: RemoteFileRejection,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RemoteFileRejection {
+    // The permission rules don't allow this client to write the file
+    PermissionDenied,
+    // The file name is not a safe path inside the asset root
+    InvalidPath(RemoteFilePathError),
+    // A delta was made against a different version than the server has, send the whole file
+    // instead, with the version the delta was made from as its base
+    DeltaBaseMismatch { base_hash: String },
+    // A file can't be renamed over another file
+    AlreadyExists,
+    // A file from the server wasn't signed with the server's key
+    InvalidSignature,
+    // The client is read-only and may not change any file
+    ReadOnly,
+    // The file isn't in a room the client is in or was sent the prefetch manifest of
+    NotReadable,
+    // The file is a level's map or script, or the Tiled world, and can't be renamed
+    Referenced,
+    // The server couldn't read the file that was asked for
+    Unavailable,
+    // The file is bigger than remote_file_max_size
+    TooLarge { size: u64, max_size: u64 },
+    // The client's unfinished uploads already take up remote_file_max_pending_uploads. The edit
+    // is uploaded again the next time the file changes.
+    TooManyUploads,
+}
+
+// Sent back to a client whose upload was written, with the hash of the version the server now has
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileAccepted {
+    pub file_name: RemoteFileName,
+    pub hash: String,
+}
+
+// Sent by a client when it connects, with whether it wants to be read-only, and answered by the
+// server with whether it is. A read-only client never uploads, deletes or renames anything.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileRole {
+    pub read_only: bool,
+}
+
+// A file was deleted, sent by the client that deleted it and broadcast by the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileDeleted {
+    pub file_name: RemoteFileName,
+}
+
+// A file was moved, sent by the client that moved it and broadcast by the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileRenamed {
+    pub from: RemoteFileName,
+    pub to: RemoteFileName,
+}
+
+// Sent to both editors when an upload was made from an older version than the server has
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileConflict {
+    pub file_name: RemoteFileName,
+    // The client whose upload conflicted
+    pub uploader: u64,
+    // The client that made the version the upload would have overwritten, if known
+    pub editor: Option<u64>,
+    // Where the conflicting upload was saved, None if it was rejected
+    pub conflict_copy: Option<RemoteFileName>,
+    // Hash of the conflicting upload
+    pub hash: String,
 }
 
+// File transfers get their own channel, so a large file doesn't stall Channel1 while it's being sent.
+// Chunks are reassembled by offset, so they don't need to arrive in order, and anything older than
+// the newest transfer of a file that arrived is dropped.
+#[derive(Channel)]
+pub struct RemoteFileChannel;
+
 // and deriving the `MapEntities` trait for the component.
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq, Reflect)]
 pub struct RemoteFileParent(pub Entity);
@@ -70,6 +197,33 @@ impl MapEntities for RemoteFileParent {
     }
 }
 
+// The room a remotefile is replicated to. Server only, used for permission checks and manifests.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct RemoteFileRoom(pub RoomId);
+
+// A remotefile that's only in its room because another file of the room refers to it. Server
+// only, it's despawned once nothing in the room refers to it anymore.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RemoteFileDependency;
+
+// Sent on the client for every chunk of a download that arrives. Sizes are of the compressed
+// payload, the download is done once received == total.
+#[derive(Event, Clone, Debug)]
+pub struct RemoteFileProgress {
+    pub file_name: RemoteFileName,
+    pub received: u64,
+    pub total: u64,
+}
+
+// Sent on the client for every local edit that isn't uploaded because the client is read-only.
+// The workspace file then differs from the server's version until it's changed back.
+#[derive(Event, Clone, Debug)]
+pub struct RemoteFileDiverged(pub RemoteFileName);
+
+// Sent on the server after a client's upload was written to disk
+#[derive(Event, Clone, Debug)]
+pub struct RemoteFileUpdated(pub RemoteFileName);
+
 // ################################################################################################
 
 #[derive(Clone)]
@@ -81,231 +235,2209 @@ impl Plugin for RemoteFileSharedPlugin {
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
 
-        app.register_message::<RemoteFile>(ChannelDirection::Bidirectional);
+        app.register_message::<RemoteFileChunk>(ChannelDirection::Bidirectional);
+
+        app.register_message::<RemoteFileDelta>(ChannelDirection::Bidirectional);
 
         app.register_message::<RemoteFileHash>(ChannelDirection::ClientToServer);
 
+        app.register_message::<AssetManifest>(ChannelDirection::ServerToClient);
+
+        app.register_message::<AssetManifestDiff>(ChannelDirection::ClientToServer);
+
+        app.register_message::<AssetManifestLeft>(ChannelDirection::ServerToClient);
+
+        app.register_message::<RemoteFileUnchanged>(ChannelDirection::ServerToClient);
+
+        app.register_message::<RemoteFileRejected>(ChannelDirection::Bidirectional);
+
+        app.register_message::<RemoteFileAccepted>(ChannelDirection::ServerToClient);
+
+        app.register_message::<RemoteFileConflict>(ChannelDirection::ServerToClient);
+
+        app.register_message::<RemoteFileDeleted>(ChannelDirection::Bidirectional);
+
+        app.register_message::<RemoteFileRenamed>(ChannelDirection::Bidirectional);
+
+        app.register_message::<RemoteFileCodecs>(ChannelDirection::Bidirectional);
+
+        app.register_message::<RemoteFileRole>(ChannelDirection::Bidirectional);
+
         app.register_component::<RemoteFileParent>(ChannelDirection::ServerToClient)
             .add_map_entities()
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
+
+        app.add_channel::<RemoteFileChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+            // let player replication go first when the connection is saturated
+            priority: 0.5,
+            ..default()
+        });
+
+        app.init_resource::<RemoteFileHashCache>();
     }
 }
 
+pub(crate) fn hash_bytes(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
 fn remotefile_get_hash(
+    asset_root: &impl RemoteFileStore,
     remote_file_name: RemoteFileName,
+    with_signature: bool,
 ) -> RemoteFileHash {
     // Get the file contents from the map_handle, hash it, and send to the server so we can remotefile_hash_check it
-    let file_path = format!("assets/{}", remote_file_name.0);
-    if let Ok(file_data) = std::fs::read(&file_path) {
+    if let Ok(file_data) = asset_root.read(&remote_file_name) {
         // Hash the file_data and send it to the server
-        let mut hasher = Sha256::new();
-        hasher.update(file_data);
         return RemoteFileHash {
-            hash: format!("{:x}", hasher.finalize()),
+            hash: hash_bytes(&file_data),
             file_name: remote_file_name,
+            resume: None,
+            signature: remotefile_signature(&file_data).filter(|_| with_signature),
         }
     }
     return RemoteFileHash {
         hash: "".to_string(),
         file_name: remote_file_name,
+        resume: None,
+        signature: None,
+    }
+}
+
+fn remotefile_watch<S: RemoteFileStore>(mut commands: Commands, asset_root: Res<S>) {
+    // Only files on disk can be edited outside of the game
+    let Some(root) = asset_root.local_path() else {
+        return;
+    };
+    match RemoteFileWatcher::new(root) {
+        Ok(watcher) => commands.insert_resource(watcher),
+        Err(e) => error!("Failed to watch {:?}, local changes won't be sent: {:?}", root, e),
+    }
+}
+
+// Block signatures of a file, if it's small enough to be worth syncing with deltas
+fn remotefile_signature(file_data: &[u8]) -> Option<FileSignature> {
+    if file_data.is_empty() || file_data.len() > MAX_SIGNATURE_FILE_SIZE {
+        return None;
     }
+    Some(delta::signature(file_data))
+}
+
+// Describe new_data as changes to the version with the signature, if that's smaller than sending it whole
+fn remotefile_delta(file_name: RemoteFileName, base_hash: String, signature: &FileSignature, new_data: &[u8]) -> Option<RemoteFileDelta> {
+    let delta = RemoteFileDelta {
+        transfer_id: 0,
+        file_name,
+        base_hash,
+        hash: hash_bytes(new_data),
+        server_signature: Vec::new(),
+        block_size: signature.block_size,
+        codec: RemoteFileCodec::None,
+        ops: delta::delta(signature, new_data),
+    };
+    let data_len = delta.data_len();
+    (data_len <= MAX_DELTA_DATA_SIZE && data_len < new_data.len() / 2).then_some(delta)
 }
 
 // ################################################################################################
 
-pub struct RemoteFileServerPlugin;
+// Generic over where the files are kept, e.g. SandboxedAssetRoot for the asset directory
+pub struct RemoteFileServerPlugin<S: RemoteFileStore> {
+    store: S,
+    // Server-side files, outside of the store so clients can't upload them
+    permissions_path: PathBuf,
+    history_path: PathBuf,
+    audit_log_path: PathBuf,
+    signing_key_path: PathBuf,
+}
+
+impl<S: RemoteFileStore> RemoteFileServerPlugin<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            permissions_path: PERMISSIONS_PATH.into(),
+            history_path: HISTORY_PATH.into(),
+            audit_log_path: AUDIT_LOG_PATH.into(),
+            signing_key_path: SIGNING_KEY_PATH.into(),
+        }
+    }
+
+    // The upload rules, `remote_file_permissions.ron` next to Cargo.toml by default
+    pub fn with_permissions_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.permissions_path = path.into();
+        self
+    }
+
+    // The directory of every accepted version, `remote_file_history/` by default
+    pub fn with_history_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.history_path = path.into();
+        self
+    }
+
+    // The log of every upload, `remote_file_audit.jsonl` by default
+    pub fn with_audit_log_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_log_path = path.into();
+        self
+    }
+
+    // The seed of the key files are signed with, `remote_file_signing_key` by default. The
+    // REMOTE_FILE_SIGNING_KEY environment variable takes precedence.
+    pub fn with_signing_key_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.signing_key_path = path.into();
+        self
+    }
+}
 
-impl Plugin for RemoteFileServerPlugin {
+impl<S: RemoteFileStore> Plugin for RemoteFileServerPlugin<S> {
     fn build(&self, app: &mut App) {
+        // Clients refuse unsigned files, so there's no point in running without the key
+        let public_key = app.world().resource::<Settings>().shared.remote_file_public_key;
+        let signer = load_signing_key(&self.signing_key_path)
+            .and_then(|seed| RemoteFileSigner::new(&seed, &public_key))
+            .unwrap_or_else(|e| panic!("Can't sign remotefiles, refusing to start: {}", e));
+        app.insert_resource(self.store.clone());
+        app.insert_resource(RemoteFileServerTransfers { signer: Some(signer), ..default() });
+        app.init_resource::<RemoteFileManifestsSent>();
+        app.init_resource::<RemoteFilePrefetch>();
+        app.init_resource::<RemoteFileEditors>();
+        app.add_event::<RemoteFileUpdated>();
+        app.insert_resource(RemoteFilePermissions::new(self.permissions_path.clone()));
+        app.insert_resource(RemoteFileHistory::new(self.history_path.clone()));
+        app.insert_resource(RemoteFileAuditLog::new(self.audit_log_path.clone()));
         app.add_systems(
             Update,
-        (remotefile_uploaded, remotefile_hash_check),
+        (remotefile_uploaded::<S>, remotefile_hash_check::<S>, (remotefile_spawn_dependencies::<S>, remotefile_send_manifests::<S>).chain().after(LevelActivation), remotefile_prefetch::<S>, remotefile_manifest_diff::<S>, remotefile_send_chunks, remotefile_disconnected, remotefile_permissions_reload, remotefile_rejected_by_client, remotefile_console::<S>, remotefile_file_ops::<S>, remotefile_codecs, remotefile_role),
         );
+        app.add_systems(Startup, remotefile_watch::<S>);
         app.init_resource::<ConnectionManager>();
     }
 }
 
-fn remotefile_hash_check(
-    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileHash>>,
+// Transfers in flight on the server
+#[derive(Resource, Default)]
+pub(crate) struct RemoteFileServerTransfers {
+    next_transfer_id: u64,
+    // Files being sent to each client
+    outgoing: HashMap<ClientId, Vec<OutgoingTransfer>>,
+    // Files being uploaded by each client
+    incoming: HashMap<(ClientId, RemoteFileName), IncomingTransfer>,
+    // The newest transfer id of each file each client uploaded
+    newest_uploads: HashMap<(ClientId, RemoteFileName), u64>,
+    // The codec negotiated with each client
+    codecs: HashMap<ClientId, RemoteFileCodec>,
+    // Compressed files by hash and codec, kept while a transfer uses them so a file sent to a
+    // whole room is only compressed once
+    payloads: HashMap<(String, RemoteFileCodec), RemoteFilePayload>,
+    // Bytes each client may still be sent, refilled every update
+    budgets: HashMap<ClientId, f32>,
+    // Signs every file sent. Always set by the plugin, which refuses to start without a key.
+    signer: Option<RemoteFileSigner>,
+}
+
+impl RemoteFileServerTransfers {
+    // Queue a file to be sent to a client, replacing any older version of it that is still queued
+    pub(crate) fn send(&mut self, client_id: ClientId, file_name: RemoteFileName, data: Arc<Vec<u8>>, resume: Option<&RemoteFileResume>) {
+        self.next_transfer_id += 1;
+        self.payloads.retain(|_, payload| payload.is_shared());
+        let codec = self.codec(&client_id);
+        let payload = self.payloads.entry((hash_bytes(&data), codec))
+            .or_insert_with(|| RemoteFilePayload::new(&file_name, &data, codec))
+            .clone();
+        let mut transfer = OutgoingTransfer::new(self.next_transfer_id, file_name, payload);
+        let server_signature = self.server_signature(&transfer.file_name, &transfer.hash);
+        transfer = transfer.with_server_signature(server_signature);
+        if let Some(resume) = resume.filter(|resume| resume.hash == transfer.hash && resume.codec == transfer.codec) {
+            info!("Resuming remotefile {:?} for client {:?} at offset {}", transfer.file_name.0, client_id, resume.offset);
+            transfer = transfer.resume_from(resume.offset);
+        }
+        self.queue(client_id, transfer);
+    }
+
+    // Queue a delta to be sent to a client, replacing any older version of the file that is still queued
+    pub(crate) fn send_delta(&mut self, client_id: ClientId, delta: RemoteFileDelta) {
+        self.next_transfer_id += 1;
+        let transfer = OutgoingTransfer::delta(self.next_transfer_id, delta);
+        self.queue(client_id, transfer);
+    }
+
+    fn queue(&mut self, client_id: ClientId, transfer: OutgoingTransfer) {
+        let transfers = self.outgoing.entry(client_id).or_default();
+        transfers.retain(|queued| queued.file_name != transfer.file_name);
+        transfers.push(transfer);
+    }
+
+    // Whether an upload is older than the newest one of the file the client started, e.g. the
+    // last chunks of a version a delta already replaced
+    fn is_stale_upload(&mut self, client_id: ClientId, file_name: &RemoteFileName, transfer_id: u64) -> bool {
+        let newest = self.newest_uploads.entry((client_id, file_name.clone())).or_default();
+        if transfer_id < *newest {
+            return true;
+        }
+        *newest = transfer_id;
+        false
+    }
+
+    // Drop the rest of a refused upload, a newer version of the file is checked again
+    fn refuse_upload(&mut self, client_id: ClientId, file_name: &RemoteFileName, transfer_id: u64) {
+        self.newest_uploads.insert((client_id, file_name.clone()), transfer_id.saturating_add(1));
+    }
+
+    // Clients refuse files without a valid signature, so without a key nothing can be sent
+    pub(crate) fn server_signature(&self, file_name: &RemoteFileName, hash: &str) -> Vec<u8> {
+        match &self.signer {
+            Some(signer) => signer.sign(file_name, hash),
+            None => Vec::new(),
+        }
+    }
+
+    // Whether a file is queued for a room the client is in, rather than for a prefetch
+    fn is_queued(&self, client_id: ClientId, file_name: &RemoteFileName) -> bool {
+        let queued = self.outgoing.get(&client_id).into_iter().flatten();
+        queued.filter(|transfer| transfer.file_name == *file_name).any(|transfer| !transfer.prefetch)
+    }
+
+    // Send the transfers of a file queued after the transfer id only once the client's other
+    // files are sent, unless the file was already queued for a room the client is in
+    pub(crate) fn set_prefetch(&mut self, client_id: ClientId, file_name: &RemoteFileName, after: u64, was_queued: bool) {
+        if was_queued {
+            return;
+        }
+        let queued = self.outgoing.get_mut(&client_id).into_iter().flatten();
+        for transfer in queued.filter(|transfer| transfer.file_name == *file_name && transfer.transfer_id > after) {
+            transfer.prefetch = true;
+        }
+    }
+
+    pub(crate) fn codec(&self, client_id: &ClientId) -> RemoteFileCodec {
+        self.codecs.get(client_id).copied().unwrap_or_default()
+    }
+
+    // A codec every one of the clients can decode, for messages sent to all of them at once
+    pub(crate) fn common_codec(&self, client_ids: &[ClientId]) -> RemoteFileCodec {
+        let codec = client_ids.first().map(|client_id| self.codec(client_id)).unwrap_or_default();
+        if client_ids.iter().all(|client_id| self.codec(client_id) == codec) {
+            codec
+        } else {
+            RemoteFileCodec::None
+        }
+    }
+}
+
+// Remember which clients asked to be read-only, and tell each client whether it is
+fn remotefile_role(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileRole>>,
+    mut permissions: ResMut<RemoteFilePermissions>,
     mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
 ) {
     for event in reader.read() {
-        let server_remotefile_hash = remotefile_get_hash(event.message.file_name.clone());
-        // If the client's file hash doesn't match the server's, send the file to the client
-        if server_remotefile_hash.hash != event.message.hash {
-            info!("RemoteFile hash mismatch ({:?}): {:?}", server_remotefile_hash.hash, event.message.file_name.0);
-            // Read the file before sending it to the client
-            match std::fs::read(format!("assets/{}", event.message.file_name.0)) {
-                Ok(file_data) => {
-                    let mut message = RemoteFile {
-                        data: file_data,
-                        file_name: server_remotefile_hash.file_name.clone(),
-                    };
-                    match connection.send_message_to_target::<Channel1, _>(
-                        &mut message,
-                        NetworkTarget::Single(*event.context()),
-                    ) {
-                        Ok(_) => {
-                            info!("Message sent successfully.");
-                        }
-                        Err(e) => {
-                            error!("Failed to send message: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to read file: {:?}", e);
-                }
-            }
+        let client_id = *event.context();
+        permissions.set_requested_read_only(client_id, event.message.read_only);
+        let mut message = RemoteFileRole { read_only: permissions.is_read_only(client_id) };
+        info!("Client {:?} is {}", client_id, if message.read_only { "read-only" } else { "allowed to upload" });
+        if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Single(client_id)) {
+            error!("Failed to send message: {:?}", e);
         }
     }
 }
 
-pub(crate) fn remotefile_uploaded(
-    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFile>>,
+// Pick the best codec the client can decode, and tell the client which ones we can decode
+fn remotefile_codecs(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileCodecs>>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
     mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
-    global: ResMut<Global>,
 ) {
     for event in reader.read() {
-        let client_id: ClientId = *event.context();
+        let codec = event.message.negotiate();
+        info!("Client {:?} supports {:?}, sending remotefiles with {:?}", event.context(), event.message.0, codec);
+        transfers.codecs.insert(*event.context(), codec);
+        let mut message = RemoteFileCodecs::supported();
+        if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Single(*event.context())) {
+            error!("Failed to send message: {:?}", e);
+        }
+    }
+}
 
-        // TODO - Check permissions
+// Tell a client why their message about a file was refused
+fn remotefile_reject(
+    connection: &mut lightyear::server::connection::ConnectionManager,
+    client_id: ClientId,
+    file_name: RemoteFileName,
+    reason: RemoteFileRejection,
+) {
+    warn!("Rejected remotefile {:?} from client {:?}: {:?}", file_name.0, client_id, reason);
+    let mut message = RemoteFileRejected { file_name, reason };
+    if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Single(client_id)) {
+        error!("Failed to send message: {:?}", e);
+    }
+}
 
-        // Save the remotefile file to the disk
-        match std::fs::write(format!("assets/{}", event.message.file_name.0), &event.message.data) {
-            Ok(_) => {
-                info!("RemoteFile downloaded: {:?}", event.message.file_name.0);
+// Whether a client may upload a file, checked before anything of the upload is buffered and again
+// once it has arrived. Returns the sanitized file name.
+fn remotefile_check_upload(
+    client_id: ClientId,
+    file_name: &RemoteFileName,
+    permissions: &RemoteFilePermissions,
+    global: &Global,
+    remotefile_query: &Query<(&RemoteFileName, &RemoteFileRoom)>,
+) -> Result<RemoteFileName, RemoteFileRejection> {
+    let file_name = SandboxedAssetRoot::sanitize(file_name).map_err(RemoteFileRejection::InvalidPath)?;
+    let (room_ids, file_room_ids) = remotefile_upload_rooms(client_id, &file_name, global, remotefile_query);
+    if !permissions.can_write(client_id, &file_name, &room_ids, &file_room_ids) {
+        let reason = if permissions.is_read_only(client_id) {
+            RemoteFileRejection::ReadOnly
+        } else {
+            RemoteFileRejection::PermissionDenied
+        };
+        return Err(reason);
+    }
+    Ok(file_name)
+}
+
+// The rooms the uploader is in, and those of the file
+fn remotefile_upload_rooms(
+    client_id: ClientId,
+    file_name: &RemoteFileName,
+    global: &Global,
+    remotefile_query: &Query<(&RemoteFileName, &RemoteFileRoom)>,
+) -> (Vec<RoomId>, Vec<RoomId>) {
+    let room_ids = global.client_id_to_room_ids.get(&client_id).cloned().unwrap_or_default();
+    let file_room_ids = remotefile_query.iter()
+        .filter(|(remote_file_name, _)| *remote_file_name == file_name)
+        .map(|(_, room)| room.0)
+        .collect();
+    (room_ids, file_room_ids)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn remotefile_hash_check<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileHash>>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    prefetch: Res<RemoteFilePrefetch>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    global: Res<Global>,
+) {
+    for event in reader.read() {
+        let readable = readable_rooms(*event.context(), &global);
+        remotefile_answer_hash(*event.context(), &event.message, &readable, &prefetch, &remotefile_query, &mut transfers, &mut connection, &mut hash_cache, &*asset_root);
+    }
+}
+
+// The rooms a client is in, the client may read their files
+fn readable_rooms(client_id: ClientId, global: &Global) -> HashSet<RoomId> {
+    global.client_id_to_room_ids.get(&client_id).into_iter().flatten().copied().collect()
+}
+
+// Send a file to a client if their version of it doesn't match the server's. Only files listed in
+// the manifest of a room the client is in, or in a prefetch manifest they were sent, are
+// answered, anything else on the server stays private.
+#[allow(clippy::too_many_arguments)]
+fn remotefile_answer_hash(
+    client_id: ClientId,
+    message: &RemoteFileHash,
+    readable_rooms: &HashSet<RoomId>,
+    prefetch: &RemoteFilePrefetch,
+    remotefile_query: &Query<(&RemoteFileName, &RemoteFileRoom)>,
+    transfers: &mut RemoteFileServerTransfers,
+    connection: &mut lightyear::server::connection::ConnectionManager,
+    hash_cache: &mut RemoteFileHashCache,
+    asset_root: &impl RemoteFileStore,
+) {
+    let file_name = match SandboxedAssetRoot::sanitize(&message.file_name) {
+        Ok(file_name) => file_name,
+        Err(e) => {
+            remotefile_reject(connection, client_id, message.file_name.clone(), RemoteFileRejection::InvalidPath(e));
+            return;
+        }
+    };
+    let readable = prefetch.was_sent(client_id, &file_name) || remotefile_query.iter()
+        .any(|(name, room)| *name == file_name && readable_rooms.contains(&room.0));
+    if !readable {
+        remotefile_reject(connection, client_id, file_name, RemoteFileRejection::NotReadable);
+        return;
+    }
+    let server_hash = hash_cache.get(asset_root, &file_name).map(|cached| cached.hash).unwrap_or_default();
+    // If the client's file hash doesn't match the server's, send the file to the client
+    if server_hash == message.hash {
+        let mut unchanged = RemoteFileUnchanged { file_name };
+        if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut unchanged, NetworkTarget::Single(client_id)) {
+            error!("Failed to send message: {:?}", e);
+        }
+        return;
+    }
+    info!("RemoteFile hash mismatch ({:?}): {:?}", server_hash, file_name.0);
+    // Read the file before sending it to the client
+    match asset_root.read(&file_name) {
+        Ok(file_data) => {
+            // Only send the changed blocks if the client has an older version
+            let delta = message.signature.as_ref().and_then(|signature| {
+                remotefile_delta(file_name.clone(), message.hash.clone(), signature, &file_data)
+            });
+            if let Some(delta) = delta {
+                info!("Sending remotefile {:?} as a delta of {} bytes", file_name.0, delta.data_len());
+                let server_signature = transfers.server_signature(&file_name, &delta.hash);
+                let delta = RemoteFileDelta { server_signature, ..delta }.compressed(transfers.codec(&client_id));
+                transfers.send_delta(client_id, delta);
+                return;
             }
-            Err(e) => {
-                error!("Failed to download remotefile: {:?}", e);
+            transfers.send(client_id, file_name, Arc::new(file_data), message.resume.as_ref());
+        }
+        Err(e) => {
+            error!("Failed to read file: {:?}", e);
+            remotefile_reject(connection, client_id, file_name, RemoteFileRejection::Unavailable);
+        }
+    }
+}
+
+// The rooms each client has been sent the manifest of
+#[derive(Resource, Default)]
+pub(crate) struct RemoteFileManifestsSent {
+    rooms: HashMap<ClientId, HashSet<RoomId>>,
+}
+
+// The room's remotefiles, which include every file they depend on
+fn room_manifest(
+    room_id: RoomId,
+    remotefile_query: &Query<(&RemoteFileName, &RemoteFileRoom)>,
+    hash_cache: &mut RemoteFileHashCache,
+    asset_root: &impl RemoteFileStore,
+) -> AssetManifest {
+    AssetManifest {
+        room: room_id.0,
+        prefetch: false,
+        entries: remotefile_query.iter()
+            .filter(|(_, room)| room.0 == room_id)
+            .filter_map(|(file_name, _)| {
+                let cached = hash_cache.get(asset_root, file_name)?;
+                Some(AssetManifestEntry { file_name: file_name.clone(), size: cached.size, hash: cached.hash })
+            })
+            .collect(),
+    }
+}
+
+// Send the AssetManifest of every room a client entered since the last update
+fn remotefile_send_manifests<S: RemoteFileStore>(
+    mut manifests_sent: ResMut<RemoteFileManifestsSent>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    added_query: Query<&RemoteFileRoom, Added<RemoteFileRoom>>,
+    global: Res<Global>,
+) {
+    // Forget rooms clients have left, so they get the manifest again if they come back, and
+    // rooms with new files, e.g. of a level that was just spawned
+    let added_rooms: HashSet<RoomId> = added_query.iter().map(|room| room.0).collect();
+    let mut left = Vec::new();
+    manifests_sent.rooms.retain(|client_id, rooms| {
+        let Some(client_rooms) = global.client_id_to_room_ids.get(client_id) else {
+            return false;
+        };
+        rooms.retain(|room_id| {
+            if !client_rooms.contains(room_id) {
+                left.push((*client_id, *room_id));
+                return false;
             }
+            !added_rooms.contains(room_id)
+        });
+        true
+    });
+    for (client_id, room_id) in left {
+        let mut message = AssetManifestLeft { room: room_id.0 };
+        if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Single(client_id)) {
+            error!("Failed to send message: {:?}", e);
         }
+    }
 
-        // Get rooms the uploader is in
-        let room_ids = global.client_id_to_room_ids.get(&client_id).unwrap();
-        // Get all clients in those rooms
-        let client_ids: Vec<_> = room_ids.iter()
-            .flat_map(|room_id| global.room_id_to_client_ids.get(room_id).unwrap())
-            .filter(|&&room_client_id| room_client_id != client_id)
-            .cloned()
-            .collect::<std::collections::HashSet<_>>()
-            .into_iter()
-            .collect();
-        // Broadcast the message to all clients in the rooms
-        if !client_ids.is_empty() {
-            info!("Client {:?} uploaded remotefile file {:?}, broadcasting to clients {:?}", client_id, event.message.clone().file_name, client_ids);
-            match connection.send_message_to_target::<Channel1, _>(
-                &mut event.message.clone(),
-                NetworkTarget::Only(client_ids),
-            ) {
-                Ok(_) => {
-                    info!("Message sent successfully.");
-                }
-                Err(e) => {
-                    error!("Failed to send message: {:?}", e);
-                }
+    for (client_id, client_rooms) in global.client_id_to_room_ids.iter() {
+        for room_id in client_rooms {
+            if !manifests_sent.rooms.entry(*client_id).or_default().insert(*room_id) {
+                continue;
+            }
+            let mut manifest = room_manifest(*room_id, &remotefile_query, &mut hash_cache, &*asset_root);
+            info!("Sending manifest of room {:?} ({} files) to client {:?}", room_id.0, manifest.entries.len(), client_id);
+            if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut manifest, NetworkTarget::Single(*client_id)) {
+                error!("Failed to send message: {:?}", e);
             }
         }
     }
 }
 
-// ################################################################################################
+// Replicate the files each remotefile refers to (tilesets, images, required scripts) as
+// remotefiles of their own, in the same room. Files are scanned when they're spawned and again
+// whenever a client uploads a new version of them, which also drops the dependencies nothing in
+// the room refers to anymore.
+#[allow(clippy::too_many_arguments)]
+fn remotefile_spawn_dependencies<S: RemoteFileStore>(
+    mut commands: Commands,
+    mut room_manager: ResMut<RoomManager>,
+    mut manifests_sent: ResMut<RemoteFileManifestsSent>,
+    mut updates: EventReader<RemoteFileUpdated>,
+    permissions: Res<RemoteFilePermissions>,
+    asset_root: Res<S>,
+    added_query: Query<&RemoteFileName, Added<RemoteFileName>>,
+    remotefile_query: Query<(Entity, &RemoteFileName, &RemoteFileParent, &RemoteFileRoom, Has<RemoteFileDependency>)>,
+) {
+    let updated: HashSet<RemoteFileName> = updates.read().map(|update| update.0.clone()).collect();
+    let mut scan: HashSet<RemoteFileName> = added_query.iter().cloned().collect();
+    scan.extend(updated.iter().cloned());
+    if scan.is_empty() {
+        return;
+    }
 
-pub struct RemoteFileClientPlugin;
+    let mut existing: HashSet<(RemoteFileName, RoomId)> = remotefile_query.iter()
+        .map(|(_, file_name, _, room, _)| (file_name.clone(), room.0))
+        .collect();
+    let can_read = |dependency: &RemoteFileName| {
+        let readable = permissions.can_read(dependency);
+        if !readable {
+            warn!("A remotefile depends on {:?}, which is private", dependency.0);
+        }
+        readable
+    };
+    for (_, file_name, parent, room, _) in remotefile_query.iter().filter(|(_, file_name, _, _, _)| scan.contains(*file_name)) {
+        // Dependencies of dependencies too, so the room's next manifest has all of them
+        for dependency in with_dependencies(&*asset_root, [file_name.clone()], &can_read) {
+            if !existing.insert((dependency.clone(), room.0)) {
+                continue;
+            }
+            info!("Remotefile {:?} depends on {:?}, adding it to room {:?}", file_name.0, dependency.0, room.0);
+            let entity = commands.spawn((
+                RemoteFileBundle::new(dependency.0, parent.0, room.0),
+                RemoteFileDependency,
+            )).id();
+            room_manager.add_entity(entity, room.0);
+            // Clients already in the room get the new file with the room's next manifest
+            for rooms in manifests_sent.rooms.values_mut() {
+                rooms.remove(&room.0);
+            }
+        }
+    }
 
-// Used to prevent a remotefile_download from calling remotefile_modified
-#[derive(Resource, Default)]
-pub struct RemoteFileDownloads {
-    pub file_names: Vec<RemoteFileName>
+    // Only an upload can remove references, spawned files refer to everything they did before
+    let changed_rooms: HashSet<RoomId> = remotefile_query.iter()
+        .filter(|(_, file_name, _, _, _)| updated.contains(*file_name))
+        .map(|(_, _, _, room, _)| room.0)
+        .collect();
+    for room_id in changed_rooms {
+        let room_files: Vec<_> = remotefile_query.iter().filter(|(_, _, _, room, _)| room.0 == room_id).collect();
+        // Everything the room's own files refer to, directly or through other dependencies
+        let own_files = room_files.iter()
+            .filter(|(_, _, _, _, dependency)| !dependency)
+            .map(|(_, file_name, _, _, _)| (*file_name).clone());
+        let used = with_dependencies(&*asset_root, own_files, |dependency| permissions.can_read(dependency));
+        for (entity, file_name, _, _, _) in room_files.iter().filter(|(_, _, _, _, dependency)| *dependency) {
+            if !used.contains(*file_name) {
+                info!("Nothing in room {:?} depends on {:?} anymore, removing it", room_id.0, file_name.0);
+                commands.entity(*entity).despawn();
+            }
+        }
+    }
 }
 
-impl Plugin for RemoteFileClientPlugin {
-    fn build(&self, app: &mut App) {
-        app
-        .insert_resource(RemoteFileDownloads::default())
-        .add_systems(
-            Update,
-        (remotefile_spawn, remotefile_download),
-        );
+// Send every file a client listed as out of date
+#[allow(clippy::too_many_arguments)]
+fn remotefile_manifest_diff<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<AssetManifestDiff>>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    prefetch: Res<RemoteFilePrefetch>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    global: Res<Global>,
+) {
+    for event in reader.read() {
+        info!("Client {:?} needs {} files of room {:?}", event.context(), event.message.stale.len(), event.message.room);
+        let readable = readable_rooms(*event.context(), &global);
+        for message in event.message.stale.iter() {
+            let after = transfers.next_transfer_id;
+            let was_queued = transfers.is_queued(*event.context(), &message.file_name);
+            remotefile_answer_hash(*event.context(), message, &readable, &prefetch, &remotefile_query, &mut transfers, &mut connection, &mut hash_cache, &*asset_root);
+            if event.message.prefetch {
+                transfers.set_prefetch(*event.context(), &message.file_name, after, was_queued);
+            }
+        }
     }
 }
 
-fn remotefile_spawn(
-    mut client: ResMut<ConnectionManager>,
-    mut remotefile_query: Query<
-        (Entity, &RemoteFileName),
-        Or<(Added<Interpolated>, Added<Predicted>)>,
-    >,
-) {
-    for (entity, remote_file_name) in &mut remotefile_query {
-        info!("Spawning remotefile: {:?}", remote_file_name.0);
+// The client that last wrote each file, to tell them when someone's upload conflicts with theirs,
+// and the conflict copies saved of each file since the server started, oldest first
+#[derive(Resource, Default)]
+pub(crate) struct RemoteFileEditors {
+    last_editor: HashMap<RemoteFileName, ClientId>,
+    conflict_copies: HashMap<RemoteFileName, Vec<RemoteFileName>>,
+}
 
-        // Send a hash of the remotefile file to the server, so it can check if it's the latest version
-        client.send_message::<Channel1, RemoteFileHash>(&mut remotefile_get_hash(remote_file_name.clone())).unwrap_or_else(|e| {
-            error!("Failed to send message: {:?}", e);
-        });
-    }
+// An upload that arrived completely, either as chunks of the whole file or as a delta
+enum RemoteFileUpload {
+    Full { file_data: Vec<u8>, base_hash: String },
+    Delta(RemoteFileDelta),
 }
 
-// TODO: This currently must be added to the ClientPlugin, to register which Asset
-// type to listen for events on. Need a better solution.
-pub fn remotefile_modified<T: Asset>(
-    mut events: EventReader<AssetEvent<T>>,
-    mut client: ResMut<ConnectionManager>,
-    mut remotefile_downloads: ResMut<RemoteFileDownloads>,
-    asset_server: Res<AssetServer>,
+pub(crate) fn remotefile_uploaded<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileChunk>>,
+    mut delta_reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileDelta>>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    mut editors: ResMut<RemoteFileEditors>,
+    mut history: ResMut<RemoteFileHistory>,
+    mut updates: EventWriter<RemoteFileUpdated>,
+    audit: Res<RemoteFileAuditLog>,
+    permissions: Res<RemoteFilePermissions>,
+    settings: Res<Settings>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    global: ResMut<Global>,
 ) {
-    for event in events.read() {
-        info!("RemoteFile asset event: {:?}", event);
-        // A remotefile was modified
-        if let AssetEvent::Modified { id } = event {
-            if let Some(asset) = asset_server.get_id_handle(*id) {
-                let remote_file_name = RemoteFileName(asset.path().unwrap().path().display().to_string());
-                // 
-                if let Some(pos) = remotefile_downloads.file_names.iter().position(|name| *name == remote_file_name) {
-                    remotefile_downloads.file_names.remove(pos);
-                    info!("RemoteFile asset modified, but not uploaded to the server: {:?}", remote_file_name);
-                }
-                // File was changed, but not from the server. Try uploading to the server, who broadcasts it.
-                else
-                {
-                    info!("RemoteFile asset modified: {:?}", remote_file_name.0);
-                    match std::fs::read(format!("assets/{}", remote_file_name.0)) {
-                        Ok(file_data) => {
-                            let mut message = RemoteFile {
-                                data: file_data,
-                                file_name: remote_file_name,
-                            };
-                            client.send_message::<Channel1, RemoteFile>(&mut message).unwrap_or_else(|e| {
-                                error!("Failed to send message: {:?}", e);
-                            });
-                        }
-                        Err(e) => {
-                            error!("Failed to read file: {:?}", e);
-                        }
+    let mut uploads = Vec::new();
+    for event in reader.read() {
+        let client_id: ClientId = *event.context();
+        let chunk = &event.message;
+
+        if transfers.is_stale_upload(client_id, &chunk.file_name, chunk.transfer_id) {
+            continue;
+        }
+        // Reassemble the upload, starting over if the client began sending a newer version. A new
+        // upload is checked before anything is allocated for it, and the rest of it is dropped if
+        // it's refused.
+        let key = (client_id, chunk.file_name.clone());
+        if !transfers.incoming.get(&key).is_some_and(|upload| upload.matches(chunk)) {
+            transfers.incoming.remove(&key);
+            let pending: u64 = transfers.incoming.iter()
+                .filter(|((id, _), _)| *id == client_id)
+                .map(|(_, upload)| upload.progress().1)
+                .sum();
+            let upload = remotefile_check_upload(client_id, &chunk.file_name, &permissions, &global, &remotefile_query)
+                .and_then(|_| IncomingTransfer::new(chunk, settings.shared.remote_file_max_size))
+                .and_then(|upload| {
+                    if pending + chunk.total_len > settings.server.remote_file_max_pending_uploads {
+                        return Err(RemoteFileRejection::TooManyUploads);
                     }
+                    Ok(upload)
+                });
+            match upload {
+                Ok(upload) => {
+                    transfers.incoming.insert(key.clone(), upload);
+                }
+                Err(reason) => {
+                    transfers.refuse_upload(client_id, &chunk.file_name, chunk.transfer_id);
+                    let entry = RemoteFileAuditEntry::new(Some(client_id.to_bits()), chunk.file_name.clone(), chunk.hash.clone(), chunk.file_len);
+                    audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                    remotefile_reject(&mut connection, client_id, chunk.file_name.clone(), reason);
+                    continue;
                 }
             }
         }
+        let Some(upload) = transfers.incoming.get_mut(&key) else {
+            continue;
+        };
+        if !upload.insert(chunk) {
+            continue;
+        }
+        let Some(file_data) = transfers.incoming.remove(&key).and_then(IncomingTransfer::finish) else {
+            error!("RemoteFile upload {:?} from client {:?} doesn't match its hash", chunk.file_name.0, client_id);
+            continue;
+        };
+        uploads.push((client_id, chunk.file_name.clone(), RemoteFileUpload::Full { file_data, base_hash: chunk.base_hash.clone() }));
+    }
+    for event in delta_reader.read() {
+        if transfers.is_stale_upload(*event.context(), &event.message.file_name, event.message.transfer_id) {
+            continue;
+        }
+        // A delta replaces any older version that was still arriving in chunks
+        transfers.incoming.remove(&(*event.context(), event.message.file_name.clone()));
+        match event.message.clone().decompressed() {
+            Ok(delta) => uploads.push((*event.context(), delta.file_name.clone(), RemoteFileUpload::Delta(delta))),
+            Err(e) => error!("Failed to decompress remotefile delta from client {:?}: {:?}", event.context(), e),
+        }
     }
-}
 
-// System to receive messages on the client
-pub(crate) fn remotefile_download(
-    mut reader: ResMut<Events<lightyear::client::events::MessageEvent<RemoteFile>>>,
-    mut remotefile_downloads: ResMut<RemoteFileDownloads>
-) {
-    for event in reader.drain() {
-        remotefile_downloads.file_names.push(event.message.file_name.clone());
-        // If there is file_data, save it to the disk
-        match std::fs::write(format!("assets/{}", event.message.file_name.0), &event.message.data) {
+    for (client_id, file_name, upload) in uploads {
+        let (new_hash, size) = match &upload {
+            RemoteFileUpload::Full { file_data, .. } => (hash_bytes(file_data), file_data.len()),
+            RemoteFileUpload::Delta(delta) => (delta.hash.clone(), delta.data_len()),
+        };
+        let mut entry = RemoteFileAuditEntry::new(Some(client_id.to_bits()), file_name.clone(), new_hash, size as u64);
+
+        let checked = remotefile_check_upload(client_id, &file_name, &permissions, &global, &remotefile_query);
+        if let Ok(file_name) = &checked {
+            entry.file_name = file_name.clone();
+            entry.old_hash = hash_cache.get(&*asset_root, file_name).map(|cached| cached.hash).unwrap_or_default();
+        }
+        let file_name = match checked {
+            Ok(file_name) => file_name,
+            Err(reason) => {
+                audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                remotefile_reject(&mut connection, client_id, entry.file_name.clone(), reason);
+                continue;
+            }
+        };
+        let server_hash = entry.old_hash.clone();
+        let (room_ids, file_room_ids) = remotefile_upload_rooms(client_id, &file_name, &global, &remotefile_query);
+
+        // Rebuild the file from a delta, which only works if it was made against our version
+        let (file_data, base_hash, delta) = match upload {
+            RemoteFileUpload::Full { file_data, base_hash } => (file_data, base_hash, None),
+            RemoteFileUpload::Delta(delta) => {
+                let file_data = asset_root.read(&file_name).ok()
+                    .filter(|_| server_hash == delta.base_hash)
+                    .and_then(|base| apply_delta(&base, delta.block_size, &delta.ops, settings.shared.remote_file_max_size as usize))
+                    .filter(|file_data| hash_bytes(file_data) == delta.hash);
+                match file_data {
+                    Some(file_data) => (file_data, delta.base_hash.clone(), Some(delta)),
+                    None => {
+                        let reason = RemoteFileRejection::DeltaBaseMismatch { base_hash: delta.base_hash };
+                        audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                        remotefile_reject(&mut connection, client_id, file_name, reason);
+                        continue;
+                    }
+                }
+            }
+        };
+        entry.size = file_data.len() as u64;
+
+        // The uploader edited an older version than ours, writing it would lose someone else's edit
+        if base_hash != server_hash && hash_bytes(&file_data) != server_hash {
+            let editor = editors.last_editor.get(&file_name).copied().filter(|editor| *editor != client_id);
+            // The copy sits next to the file, so it's checked against the file's rooms
+            let conflict_copy = match settings.server.remote_file_conflicts {
+                ConflictResolution::Reject => None,
+                ConflictResolution::KeepBoth => Some(conflict_copy_name(&file_name, client_id))
+                    .filter(|conflict_copy| {
+                        let allowed = permissions.can_write(client_id, conflict_copy, &room_ids, &file_room_ids);
+                        if !allowed {
+                            warn!("Client {:?} may not write the conflict copy {:?}, rejecting their upload", client_id, conflict_copy.0);
+                        }
+                        allowed
+                    }),
+            };
+            let conflict_copy = remotefile_conflict(
+                client_id,
+                editor,
+                file_name,
+                file_data,
+                conflict_copy,
+                &mut editors,
+                &mut transfers,
+                &mut connection,
+                &mut hash_cache,
+                &*asset_root,
+            );
+            audit.record(&entry.decided(RemoteFileAuditDecision::Conflict { conflict_copy }));
+            continue;
+        }
+
+        // Save the remotefile file to the disk
+        let rooms: Vec<u64> = file_room_ids.iter().map(|room_id| room_id.0).collect();
+        remotefile_snapshot(&mut history, &mut hash_cache, &*asset_root, &file_name, rooms.clone());
+        match asset_root.write(&file_name, &file_data) {
             Ok(_) => {
-                info!("RemoteFile downloaded: {:?}", event.message.file_name.0);
+                info!("RemoteFile downloaded: {:?}", file_name.0);
+                hash_cache.update(&*asset_root, &file_name, &file_data);
+                editors.last_editor.insert(file_name.clone(), client_id);
+                updates.send(RemoteFileUpdated(file_name.clone()));
+                let mut message = RemoteFileAccepted { file_name: file_name.clone(), hash: hash_bytes(&file_data) };
+                if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Single(client_id)) {
+                    error!("Failed to send message: {:?}", e);
+                }
+                if let Err(e) = history.record(&file_name, &file_data, Some(client_id.to_bits()), rooms) {
+                    error!("Failed to store remotefile revision: {:?}", e);
+                }
             }
             Err(e) => {
                 error!("Failed to download remotefile: {:?}", e);
+                continue;
             }
         }
+        entry.rooms = room_ids.iter().map(|room_id| room_id.0).collect();
+        audit.record(&entry);
+
+        // Get all clients in the uploader's rooms
+        let client_ids: Vec<_> = room_ids.iter()
+            .flat_map(|room_id| global.room_id_to_client_ids.get(room_id).unwrap())
+            .filter(|&&room_client_id| room_client_id != client_id)
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        // Broadcast the file to all clients in the rooms
+        if !client_ids.is_empty() {
+            info!("Client {:?} uploaded remotefile file {:?}, broadcasting to clients {:?}", client_id, file_name, client_ids);
+            remotefile_broadcast(&mut transfers, client_ids, file_name, file_data, delta);
+        }
     }
-}
\ No newline at end of file
+}
+
+// Send a new version of a file to clients
+fn remotefile_broadcast(
+    transfers: &mut RemoteFileServerTransfers,
+    client_ids: Vec<ClientId>,
+    file_name: RemoteFileName,
+    file_data: Vec<u8>,
+    delta: Option<RemoteFileDelta>,
+) {
+    match delta {
+        // The other clients most likely have the same base version, clients that don't
+        // will ask for the file again
+        Some(delta) => {
+            let server_signature = transfers.server_signature(&file_name, &delta.hash);
+            let delta = RemoteFileDelta { file_name, server_signature, ..delta }.compressed(transfers.common_codec(&client_ids));
+            for client_id in client_ids {
+                transfers.send_delta(client_id, delta.clone());
+            }
+        }
+        None => {
+            let file_data = Arc::new(file_data);
+            for client_id in client_ids {
+                transfers.send(client_id, file_name.clone(), file_data.clone(), None);
+            }
+        }
+    }
+}
+
+// `history <file>` lists the revisions of a file, `rollback <file> <hash>` restores one of them
+// and sends it to every client in the file's rooms, `audit <file|client id>` lists the uploads of
+// a file or a client
+#[allow(clippy::too_many_arguments)]
+fn remotefile_console<S: RemoteFileStore>(
+    mut lines: EventReader<ServerConsoleLine>,
+    mut history: ResMut<RemoteFileHistory>,
+    audit: Res<RemoteFileAuditLog>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    mut editors: ResMut<RemoteFileEditors>,
+    mut updates: EventWriter<RemoteFileUpdated>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    asset_root: Res<S>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    global: Res<Global>,
+) {
+    for line in lines.read() {
+        if let Some(args) = line.command("history") {
+            let [file_name] = args[..] else {
+                warn!("Usage: history <file>");
+                continue;
+            };
+            let file_name = RemoteFileName(file_name.to_string());
+            for revision in history.revisions(&file_name) {
+                info!("{} client {:?} at {} in rooms {:?}", revision.hash, revision.client_id, revision.timestamp, revision.rooms);
+            }
+            continue;
+        }
+        if let Some(args) = line.command("audit") {
+            let [file_or_client] = args[..] else {
+                warn!("Usage: audit <file|client id>");
+                continue;
+            };
+            let filter = match file_or_client.parse::<u64>() {
+                Ok(client_id) => RemoteFileAuditFilter::Client(client_id),
+                Err(_) => RemoteFileAuditFilter::File(RemoteFileName(file_or_client.to_string())),
+            };
+            match audit.query(&filter) {
+                Ok(entries) => {
+                    for entry in entries {
+                        info!(
+                            "{} client {:?} {:?} {:?} {} -> {} ({} bytes) rooms {:?}: {:?}",
+                            entry.timestamp, entry.client_id, entry.operation, entry.file_name.0, entry.old_hash, entry.new_hash, entry.size, entry.rooms, entry.decision
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to read remotefile audit log: {:?}", e),
+            }
+            continue;
+        }
+        let Some(args) = line.command("rollback") else {
+            continue;
+        };
+        let [file_name, hash_prefix] = args[..] else {
+            warn!("Usage: rollback <file> <revision hash>");
+            continue;
+        };
+        let file_name = match SandboxedAssetRoot::sanitize(&RemoteFileName(file_name.to_string())) {
+            Ok(file_name) => file_name,
+            Err(e) => {
+                warn!("Invalid remotefile path {:?}: {:?}", file_name, e);
+                continue;
+            }
+        };
+        let file_data = match history.find(&file_name, hash_prefix) {
+            Ok(revision) => history.read(revision),
+            Err(e) => {
+                warn!("Can't roll back: {}", e);
+                continue;
+            }
+        };
+        let file_data = match file_data {
+            Ok(file_data) => file_data,
+            Err(e) => {
+                error!("Failed to read remotefile revision: {:?}", e);
+                continue;
+            }
+        };
+        let old_hash = hash_cache.get(&*asset_root, &file_name).map(|cached| cached.hash).unwrap_or_default();
+        let room_ids: Vec<RoomId> = remotefile_query.iter()
+            .filter(|(remote_file_name, _)| **remote_file_name == file_name)
+            .map(|(_, room)| room.0)
+            .collect();
+        let rooms: Vec<u64> = room_ids.iter().map(|room_id| room_id.0).collect();
+        remotefile_snapshot(&mut history, &mut hash_cache, &*asset_root, &file_name, rooms.clone());
+        if let Err(e) = asset_root.write(&file_name, &file_data) {
+            error!("Failed to roll back remotefile: {:?}", e);
+            continue;
+        }
+        info!("Rolled back {:?} to revision {}", file_name.0, hash_prefix);
+        hash_cache.update(&*asset_root, &file_name, &file_data);
+        editors.last_editor.remove(&file_name);
+        updates.send(RemoteFileUpdated(file_name.clone()));
+
+        if let Err(e) = history.record(&file_name, &file_data, None, rooms.clone()) {
+            error!("Failed to store remotefile revision: {:?}", e);
+        }
+        let entry = RemoteFileAuditEntry::new(None, file_name.clone(), hash_bytes(&file_data), file_data.len() as u64);
+        audit.record(&RemoteFileAuditEntry { old_hash, rooms, ..entry });
+        let client_ids: Vec<ClientId> = room_ids.iter()
+            .filter_map(|room_id| global.room_id_to_client_ids.get(room_id))
+            .flatten()
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        if !client_ids.is_empty() {
+            remotefile_broadcast(&mut transfers, client_ids, file_name, file_data, None);
+        }
+    }
+}
+
+// Store the server's version of a file before it's overwritten, deleted or renamed, unless it
+// already is the file's latest revision. Files no client changed yet, e.g. the ones shipped with
+// the server, can then be rolled back to as well.
+fn remotefile_snapshot(
+    history: &mut RemoteFileHistory,
+    hash_cache: &mut RemoteFileHashCache,
+    asset_root: &impl RemoteFileStore,
+    file_name: &RemoteFileName,
+    rooms: Vec<u64>,
+) {
+    let Some(cached) = hash_cache.get(asset_root, file_name) else {
+        return;
+    };
+    if history.is_latest(file_name, &cached.hash) {
+        return;
+    }
+    let recorded = asset_root.read(file_name).and_then(|file_data| history.record(file_name, &file_data, None, rooms));
+    if let Err(e) = recorded {
+        error!("Failed to store remotefile revision of {:?}: {:?}", file_name.0, e);
+    }
+}
+
+// A deletion or rename, requested by a client or done by hand in the server's asset root
+enum RemoteFileOp {
+    Delete(RemoteFileName),
+    Rename { from: RemoteFileName, to: RemoteFileName },
+}
+
+// Apply deletions and renames, and pass them on to the other clients in the file's rooms. Client
+// requests go through the same checks as uploads.
+#[allow(clippy::too_many_arguments)]
+fn remotefile_file_ops<S: RemoteFileStore>(
+    mut deleted_reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileDeleted>>,
+    mut renamed_reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileRenamed>>,
+    mut commands: Commands,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut editors: ResMut<RemoteFileEditors>,
+    mut history: ResMut<RemoteFileHistory>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    audit: Res<RemoteFileAuditLog>,
+    permissions: Res<RemoteFilePermissions>,
+    asset_root: Res<S>,
+    watcher: Option<Res<RemoteFileWatcher>>,
+    world: Option<Res<WorldManifest>>,
+    mut remotefile_query: Query<(Entity, &mut RemoteFileName, &RemoteFileRoom)>,
+    level_query: Query<&LevelFileName>,
+    script_query: Query<&ScriptFileName>,
+    global: Res<Global>,
+) {
+    let mut ops: Vec<(Option<ClientId>, RemoteFileOp)> = Vec::new();
+    for event in deleted_reader.read() {
+        ops.push((Some(*event.context()), RemoteFileOp::Delete(event.message.file_name.clone())));
+    }
+    for event in renamed_reader.read() {
+        let (from, to) = (event.message.from.clone(), event.message.to.clone());
+        ops.push((Some(*event.context()), RemoteFileOp::Rename { from, to }));
+    }
+    // Files deleted or moved on the server's disk. Our own changes are skipped, by then their
+    // entities are already gone or renamed.
+    if let Some(watcher) = watcher {
+        for change in watcher.changes() {
+            let replicated = |file_name: &RemoteFileName| remotefile_query.iter().any(|(_, name, _)| name == file_name);
+            match change {
+                RemoteFileChange::Removed(file_name) if replicated(&file_name) => {
+                    ops.push((None, RemoteFileOp::Delete(file_name)));
+                }
+                RemoteFileChange::Renamed { from, to } if replicated(&from) => {
+                    ops.push((None, RemoteFileOp::Rename { from, to }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (client_id, op) in ops {
+        let (file_name, new_name) = match op {
+            RemoteFileOp::Delete(file_name) => (file_name, None),
+            RemoteFileOp::Rename { from, to } => (from, Some(to)),
+        };
+        // Client requests are audited like uploads
+        let audit_entry = |file_name: &RemoteFileName, new_name: Option<&RemoteFileName>| {
+            let operation = match new_name {
+                Some(new_name) => RemoteFileAuditOperation::Rename { to: new_name.clone() },
+                None => RemoteFileAuditOperation::Delete,
+            };
+            let client_id = client_id.map(|client_id| client_id.to_bits());
+            RemoteFileAuditEntry::new(client_id, file_name.clone(), String::new(), 0).with_operation(operation)
+        };
+        let sanitized = SandboxedAssetRoot::sanitize(&file_name).and_then(|file_name| {
+            let new_name = new_name.as_ref().map(SandboxedAssetRoot::sanitize).transpose()?;
+            Ok((file_name, new_name))
+        });
+        let (file_name, new_name) = match sanitized {
+            Ok(names) => names,
+            Err(e) => {
+                if let Some(client_id) = client_id {
+                    let reason = RemoteFileRejection::InvalidPath(e);
+                    let entry = audit_entry(&file_name, new_name.as_ref());
+                    audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                    remotefile_reject(&mut connection, client_id, file_name, reason);
+                }
+                continue;
+            }
+        };
+        let file_room_ids: Vec<RoomId> = remotefile_query.iter()
+            .filter(|(_, remote_file_name, _)| **remote_file_name == file_name)
+            .map(|(_, _, room)| room.0)
+            .collect();
+
+        if let Some(client_id) = client_id {
+            let cached = hash_cache.get(&*asset_root, &file_name);
+            let mut entry = audit_entry(&file_name, new_name.as_ref());
+            entry.old_hash = cached.as_ref().map(|cached| cached.hash.clone()).unwrap_or_default();
+            entry.rooms = file_room_ids.iter().map(|room_id| room_id.0).collect();
+            if let (Some(cached), Some(_)) = (&cached, &new_name) {
+                entry.new_hash = cached.hash.clone();
+                entry.size = cached.size;
+            }
+
+            // Deleting needs write access to the file, moving it to the new name as well. The
+            // file keeps its rooms under the new name.
+            let room_ids = global.client_id_to_room_ids.get(&client_id).cloned().unwrap_or_default();
+            let denied = std::iter::once(&file_name).chain(new_name.as_ref())
+                .find(|name| !permissions.can_write(client_id, name, &room_ids, &file_room_ids));
+            if let Some(denied) = denied {
+                let reason = if permissions.is_read_only(client_id) {
+                    RemoteFileRejection::ReadOnly
+                } else {
+                    RemoteFileRejection::PermissionDenied
+                };
+                audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                remotefile_reject(&mut connection, client_id, denied.clone(), reason);
+                continue;
+            }
+            // Levels refer to their files by name, moving one would break the level
+            let referenced = world.as_ref().is_some_and(|world| world.refers_to(&file_name))
+                || level_query.iter().any(|level| level.0 == file_name.0)
+                || script_query.iter().any(|script| script.0 == file_name.0);
+            if new_name.is_some() && referenced {
+                let reason = RemoteFileRejection::Referenced;
+                audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                remotefile_reject(&mut connection, client_id, file_name, reason);
+                continue;
+            }
+            if let Some(taken) = new_name.as_ref().filter(|new_name| asset_root.metadata(new_name).is_ok()) {
+                let reason = RemoteFileRejection::AlreadyExists;
+                audit.record(&entry.decided(RemoteFileAuditDecision::Rejected(reason.clone())));
+                remotefile_reject(&mut connection, client_id, taken.clone(), reason);
+                continue;
+            }
+            // The file is gone under its name afterwards, keep what it was so it can be restored
+            let rooms = file_room_ids.iter().map(|room_id| room_id.0).collect();
+            remotefile_snapshot(&mut history, &mut hash_cache, &*asset_root, &file_name, rooms);
+            let result = match &new_name {
+                Some(new_name) => asset_root.rename(&file_name, new_name),
+                None => asset_root.remove(&file_name),
+            };
+            if let Err(e) = result {
+                error!("Failed to delete or rename remotefile {:?}: {:?}", file_name.0, e);
+                continue;
+            }
+            audit.record(&entry);
+        }
+
+        let target = NetworkTarget::Only(
+            file_room_ids.iter()
+                .filter_map(|room_id| global.room_id_to_client_ids.get(room_id))
+                .flatten()
+                .filter(|room_client_id| Some(**room_client_id) != client_id)
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect(),
+        );
+        let editor = editors.last_editor.remove(&file_name);
+        let sent = match new_name {
+            Some(new_name) => {
+                info!("RemoteFile {:?} renamed to {:?} by client {:?}", file_name.0, new_name.0, client_id);
+                for (_, mut remote_file_name, _) in remotefile_query.iter_mut().filter(|(_, name, _)| **name == file_name) {
+                    *remote_file_name = new_name.clone();
+                }
+                if let Some(editor) = editor {
+                    editors.last_editor.insert(new_name.clone(), editor);
+                }
+                let mut message = RemoteFileRenamed { from: file_name, to: new_name };
+                connection.send_message_to_target::<Channel1, _>(&mut message, target)
+            }
+            None => {
+                info!("RemoteFile {:?} deleted by client {:?}", file_name.0, client_id);
+                for (entity, _, _) in remotefile_query.iter().filter(|(_, name, _)| **name == file_name) {
+                    commands.entity(entity).despawn();
+                }
+                let mut message = RemoteFileDeleted { file_name };
+                connection.send_message_to_target::<Channel1, _>(&mut message, target)
+            }
+        };
+        if let Err(e) = sent {
+            error!("Failed to send message: {:?}", e);
+        }
+    }
+}
+
+// Resolve an upload that conflicts with the server's version, and tell both editors about it
+#[allow(clippy::too_many_arguments)]
+fn remotefile_conflict(
+    uploader: ClientId,
+    editor: Option<ClientId>,
+    file_name: RemoteFileName,
+    file_data: Vec<u8>,
+    conflict_copy: Option<RemoteFileName>,
+    file_editors: &mut RemoteFileEditors,
+    transfers: &mut RemoteFileServerTransfers,
+    connection: &mut lightyear::server::connection::ConnectionManager,
+    hash_cache: &mut RemoteFileHashCache,
+    asset_root: &impl RemoteFileStore,
+) -> Option<RemoteFileName> {
+    warn!("RemoteFile upload {:?} from client {:?} conflicts with the edit of client {:?}", file_name.0, uploader, editor);
+    let hash = hash_bytes(&file_data);
+    let editors: Vec<ClientId> = std::iter::once(uploader).chain(editor).collect();
+    // Save the upload next to the file, and give both editors the copy and the latest version
+    let conflict_copy = conflict_copy.and_then(|conflict_copy| match asset_root.write(&conflict_copy, &file_data) {
+        Ok(_) => {
+            info!("Saved conflicting upload as {:?}", conflict_copy.0);
+            hash_cache.update(asset_root, &conflict_copy, &file_data);
+            let file_data = Arc::new(file_data);
+            for client_id in editors.iter() {
+                transfers.send(*client_id, conflict_copy.clone(), file_data.clone(), None);
+            }
+            match asset_root.read(&file_name) {
+                Ok(latest) => transfers.send(uploader, file_name.clone(), Arc::new(latest), None),
+                Err(e) => error!("Failed to read file: {:?}", e),
+            }
+            // Only the latest few copies are kept, the editors have had time to merge the others
+            let copies = file_editors.conflict_copies.entry(file_name.clone()).or_default();
+            copies.push(conflict_copy.clone());
+            let expired = copies.len().saturating_sub(MAX_CONFLICT_COPIES);
+            for expired in copies.drain(..expired) {
+                info!("Removing old conflict copy {:?}", expired.0);
+                if let Err(e) = asset_root.remove(&expired) {
+                    error!("Failed to remove conflict copy {:?}: {:?}", expired.0, e);
+                }
+            }
+            Some(conflict_copy)
+        }
+        Err(e) => {
+            error!("Failed to save conflict copy: {:?}", e);
+            None
+        }
+    });
+    let mut message = RemoteFileConflict {
+        file_name,
+        uploader: uploader.to_bits(),
+        editor: editor.map(|editor| editor.to_bits()),
+        conflict_copy,
+        hash,
+    };
+    if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::Only(editors)) {
+        error!("Failed to send message: {:?}", e);
+    }
+    message.conflict_copy
+}
+
+// Conflict copies kept of each file, older ones are removed when a new one is saved
+const MAX_CONFLICT_COPIES: usize = 5;
+
+// `maps/map_3.tmx` becomes `maps/map_3.conflict-2-1729260000.tmx`
+fn conflict_copy_name(file_name: &RemoteFileName, client_id: ClientId) -> RemoteFileName {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let suffix = format!("conflict-{}-{}", client_id.to_bits(), timestamp);
+    let (dir, name) = match file_name.0.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), file_name.0.as_str()),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => RemoteFileName(format!("{}{}.{}.{}", dir, stem, suffix, extension)),
+        _ => RemoteFileName(format!("{}{}.{}", dir, name, suffix)),
+    }
+}
+
+// Send queued transfers within each client's bandwidth budget. The level the client's player is
+// in goes first, then the other levels by distance.
+#[allow(clippy::too_many_arguments)]
+fn remotefile_send_chunks(
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    time: Res<Time>,
+    settings: Res<Settings>,
+    remotefile_query: Query<(&RemoteFileName, &RemoteFileRoom)>,
+    player_query: Query<(&PlayerId, &Position)>,
+) {
+    let bytes_per_second = settings.server.remote_file_bandwidth as f32;
+    let RemoteFileServerTransfers { outgoing, budgets, .. } = &mut *transfers;
+    for (client_id, client_transfers) in outgoing.iter_mut() {
+        // Unused budget carries over for up to a second, and there's always room for one chunk
+        let budget = budgets.entry(*client_id).or_default();
+        *budget = (*budget + bytes_per_second * time.delta_seconds()).min(bytes_per_second.max(CHUNK_SIZE as f32));
+
+        let player_grid_position = player_query.iter()
+            .find(|(player_id, _)| player_id.0 == *client_id)
+            .map(|(_, position)| get_grid_position(position.0));
+        let distance = |transfer: &OutgoingTransfer| -> u32 {
+            let Some(player_grid_position) = player_grid_position else {
+                return 0;
+            };
+            remotefile_query.iter()
+                .filter(|(file_name, _)| **file_name == transfer.file_name)
+                .map(|(_, room)| {
+                    let offset = (get_grid_position_from_room_id(room.0) - player_grid_position).abs();
+                    offset.x.max(offset.y) as u32
+                })
+                .min()
+                // Files outside of any room, like conflict copies, aren't needed to play and go
+                // last, they're charged to the budget like everything else
+                .unwrap_or(u32::MAX)
+        };
+        client_transfers.sort_by_cached_key(|transfer| (transfer.prefetch, distance(transfer), transfer.transfer_id));
+
+        for transfer in client_transfers.iter_mut() {
+            // A delta is sent whole, and charged like the chunks it replaces
+            if *budget > 0.0 {
+                if let Some(mut delta) = transfer.take_delta() {
+                    *budget -= delta.data_len().max(1) as f32;
+                    if let Err(e) = connection.send_message_to_target::<RemoteFileChannel, _>(
+                        &mut delta,
+                        NetworkTarget::Single(*client_id),
+                    ) {
+                        error!("Failed to send message: {:?}", e);
+                    }
+                }
+            }
+            while *budget > 0.0 {
+                let Some(mut chunk) = transfer.next_chunk() else {
+                    break;
+                };
+                *budget -= chunk.data.len().max(1) as f32;
+                if let Err(e) = connection.send_message_to_target::<RemoteFileChannel, _>(
+                    &mut chunk,
+                    NetworkTarget::Single(*client_id),
+                ) {
+                    error!("Failed to send message: {:?}", e);
+                }
+            }
+            if transfer.is_finished() {
+                info!("RemoteFile {:?} sent to client {:?}", transfer.file_name.0, client_id);
+            }
+        }
+        client_transfers.retain(|transfer| !transfer.is_finished());
+    }
+    outgoing.retain(|_, client_transfers| !client_transfers.is_empty());
+}
+
+// Drop the transfers of disconnected clients, they will ask for the files again when they come back
+fn remotefile_disconnected(
+    mut disconnections: EventReader<lightyear::server::events::DisconnectEvent>,
+    mut transfers: ResMut<RemoteFileServerTransfers>,
+    mut manifests_sent: ResMut<RemoteFileManifestsSent>,
+    mut prefetch: ResMut<RemoteFilePrefetch>,
+    mut permissions: ResMut<RemoteFilePermissions>,
+) {
+    for disconnection in disconnections.read() {
+        prefetch.forget(disconnection.client_id);
+        permissions.set_requested_read_only(disconnection.client_id, false);
+        transfers.outgoing.remove(&disconnection.client_id);
+        transfers.codecs.remove(&disconnection.client_id);
+        transfers.budgets.remove(&disconnection.client_id);
+        manifests_sent.rooms.remove(&disconnection.client_id);
+        transfers.incoming.retain(|(client_id, _), _| *client_id != disconnection.client_id);
+        transfers.newest_uploads.retain(|(client_id, _), _| *client_id != disconnection.client_id);
+    }
+}
+
+// A client refused a file we sent them
+fn remotefile_rejected_by_client(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<RemoteFileRejected>>,
+) {
+    for event in reader.read() {
+        warn!("Client {:?} rejected remotefile {:?}: {:?}", event.context(), event.message.file_name.0, event.message.reason);
+    }
+}
+
+// ################################################################################################
+
+// Files from the server are kept in the cache, which should also be registered as the
+// `remote://` asset source. Files edited in the workspace are uploaded.
+pub struct RemoteFileClientPlugin<S: RemoteFileStore> {
+    cache: S,
+    workspace: SandboxedAssetRoot,
+}
+
+impl<S: RemoteFileStore> RemoteFileClientPlugin<S> {
+    pub fn new(cache: S, workspace: SandboxedAssetRoot) -> Self {
+        Self { cache, workspace }
+    }
+}
+
+impl<S: RemoteFileStore> Plugin for RemoteFileClientPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(RemoteFileCache(self.cache.clone()))
+        .insert_resource(RemoteFileWorkspace(self.workspace.clone()))
+        .init_resource::<RemoteFileClientTransfers>()
+        .init_resource::<RemoteFileSyncedVersions>()
+        .init_resource::<RemoteFileIgnore>()
+        .init_resource::<RemoteFileLoading>()
+        .init_resource::<RemoteFileReadOnly>()
+        .add_event::<RemoteFileProgress>()
+        .add_event::<RemoteFileDiverged>()
+        // Every client has its own settings
+        .ignore_remote_files("settings.ron")
+        .add_systems(Startup, (remotefile_watch_workspace, remotefile_read_only_setting, remotefile_loading_overlay_spawn))
+        .add_systems(
+            Update,
+        (remotefile_manifest::<RemoteFileCache<S>>, remotefile_modified::<RemoteFileCache<S>>, remotefile_download::<RemoteFileCache<S>>, remotefile_download_delta::<RemoteFileCache<S>>, remotefile_upload_chunks, remotefile_loading_replies::<RemoteFileCache<S>>.before(remotefile_manifest::<RemoteFileCache<S>>), remotefile_accepted, remotefile_rejected::<RemoteFileCache<S>>, remotefile_conflicted, remotefile_file_ops_received::<RemoteFileCache<S>>, remotefile_connected::<RemoteFileCache<S>>, remotefile_codec_negotiated, remotefile_role_received),
+        )
+        .add_systems(
+            Update,
+        (remotefile_loading_progress, remotefile_loading_overlay).chain().after(remotefile_download::<RemoteFileCache<S>>),
+        );
+    }
+}
+
+// Transfers in flight on the client. Incoming transfers are kept across reconnects so they can resume.
+#[derive(Resource, Default)]
+pub struct RemoteFileClientTransfers {
+    next_transfer_id: u64,
+    outgoing: Vec<OutgoingTransfer>,
+    incoming: HashMap<RemoteFileName, IncomingTransfer>,
+    // The newest transfer id of each file the server sent, forgotten on reconnect since the
+    // server may have restarted
+    newest: HashMap<RemoteFileName, u64>,
+    // The codec negotiated with the server
+    codec: RemoteFileCodec,
+}
+
+impl RemoteFileClientTransfers {
+    // Queue a file to be uploaded to the server, replacing any older version of it that is still
+    // queued. base_hash is the server version the edit was made from.
+    pub(crate) fn upload(&mut self, file_name: RemoteFileName, data: Vec<u8>, base_hash: String) {
+        self.next_transfer_id += 1;
+        self.outgoing.retain(|queued| queued.file_name != file_name);
+        let payload = RemoteFilePayload::new(&file_name, &data, self.codec);
+        let transfer = OutgoingTransfer::new(self.next_transfer_id, file_name, payload).with_base_hash(base_hash);
+        self.outgoing.push(transfer);
+    }
+
+    // Queue a delta to be uploaded, replacing any older version of the file that is still queued
+    pub(crate) fn upload_delta(&mut self, delta: RemoteFileDelta) {
+        self.next_transfer_id += 1;
+        self.outgoing.retain(|queued| queued.file_name != delta.file_name);
+        self.outgoing.push(OutgoingTransfer::delta(self.next_transfer_id, delta));
+    }
+
+    // Whether a download is older than the newest one of the file the server started, e.g. the
+    // last chunks of a version a delta already replaced
+    fn is_stale(&mut self, file_name: &RemoteFileName, transfer_id: u64) -> bool {
+        let newest = self.newest.entry(file_name.clone()).or_default();
+        if transfer_id < *newest {
+            return true;
+        }
+        *newest = transfer_id;
+        false
+    }
+}
+
+// The server version each file in the workspace was last known to match. Edits are sent as a
+// delta against it, and it tells the server which version the edit was made from.
+#[derive(Resource, Default)]
+pub struct RemoteFileSyncedVersions {
+    files: HashMap<RemoteFileName, SyncedVersion>,
+    // Our uploads the server hasn't accepted yet. Further edits are made from them, but they only
+    // become the synced version once the server has them.
+    uploading: HashMap<RemoteFileName, SyncedVersion>,
+}
+
+struct SyncedVersion {
+    hash: String,
+    signature: Option<FileSignature>,
+}
+
+impl SyncedVersion {
+    fn new(file_data: &[u8]) -> Self {
+        Self {
+            hash: hash_bytes(file_data),
+            signature: remotefile_signature(file_data),
+        }
+    }
+}
+
+impl RemoteFileSyncedVersions {
+    fn record(&mut self, file_name: RemoteFileName, file_data: &[u8]) {
+        self.files.insert(file_name, SyncedVersion::new(file_data));
+    }
+
+    fn uploading(&mut self, file_name: RemoteFileName, file_data: &[u8]) {
+        self.uploading.insert(file_name, SyncedVersion::new(file_data));
+    }
+
+    // The version the next edit of a file is made from
+    fn base(&self, file_name: &RemoteFileName) -> Option<&SyncedVersion> {
+        self.uploading.get(file_name).or_else(|| self.files.get(file_name))
+    }
+
+    fn accepted(&mut self, file_name: &RemoteFileName, hash: &str) {
+        if self.uploading.get(file_name).is_some_and(|uploading| uploading.hash == hash) {
+            let uploaded = self.uploading.remove(file_name).unwrap();
+            self.files.insert(file_name.clone(), uploaded);
+        }
+    }
+
+    // The server refused our upload of a file, or the one with the hash if it's given
+    fn refused(&mut self, file_name: &RemoteFileName, hash: Option<&str>) {
+        if self.uploading.get(file_name).is_some_and(|uploading| hash.map_or(true, |hash| uploading.hash == hash)) {
+            self.uploading.remove(file_name);
+        }
+    }
+
+    // Returns whether the server knew the file
+    fn forget(&mut self, file_name: &RemoteFileName) -> bool {
+        let uploading = self.uploading.remove(file_name).is_some();
+        self.files.remove(file_name).is_some() || uploading
+    }
+
+    // Returns whether the server knew the file
+    fn rename(&mut self, from: &RemoteFileName, to: &RemoteFileName) -> bool {
+        let uploading = self.uploading.remove(from);
+        let synced = self.files.remove(from);
+        let known = uploading.is_some() || synced.is_some();
+        if let Some(uploading) = uploading {
+            self.uploading.insert(to.clone(), uploading);
+        }
+        if let Some(synced) = synced {
+            self.files.insert(to.clone(), synced);
+        }
+        known
+    }
+}
+
+// Whether local edits are kept to ourselves instead of being uploaded, see RemoteFileRole
+#[derive(Resource, Default)]
+pub struct RemoteFileReadOnly(pub bool);
+
+fn remotefile_read_only_setting(mut read_only: ResMut<RemoteFileReadOnly>, settings: Res<Settings>) {
+    read_only.0 = settings.client.remote_file_read_only;
+}
+
+fn remotefile_watch_workspace(mut commands: Commands, workspace: Res<RemoteFileWorkspace>) {
+    match RemoteFileWatcher::new(workspace.0.root()) {
+        Ok(watcher) => commands.insert_resource(watcher),
+        Err(e) => error!("Failed to watch {:?}, local changes won't be sent: {:?}", workspace.0.root(), e),
+    }
+}
+
+// Tell the server which codecs we can decode, nothing is compressed until it answers, and whether
+// we want to be read-only
+fn remotefile_connected<S: RemoteFileStore>(
+    mut connections: EventReader<lightyear::client::events::ConnectEvent>,
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut read_only: ResMut<RemoteFileReadOnly>,
+    mut loading: ResMut<RemoteFileLoading>,
+    asset_root: Res<S>,
+    settings: Res<Settings>,
+) {
+    for _ in connections.read() {
+        transfers.codec = RemoteFileCodec::None;
+        for room in loading.rooms() {
+            asset_root.keep(room, Vec::new());
+        }
+        loading.clear();
+        transfers.newest.clear();
+        for download in transfers.incoming.values_mut() {
+            download.reconnected();
+        }
+        read_only.0 = settings.client.remote_file_read_only;
+        client.send_message::<Channel1, RemoteFileCodecs>(&mut RemoteFileCodecs::supported()).unwrap_or_else(|e| {
+            error!("Failed to send message: {:?}", e);
+        });
+        client.send_message::<Channel1, RemoteFileRole>(&mut RemoteFileRole { read_only: read_only.0 }).unwrap_or_else(|e| {
+            error!("Failed to send message: {:?}", e);
+        });
+    }
+}
+
+// The server can make us read-only even if our settings don't
+fn remotefile_role_received(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileRole>>,
+    mut read_only: ResMut<RemoteFileReadOnly>,
+) {
+    for event in reader.read() {
+        if event.message.read_only && !read_only.0 {
+            warn!("The server made this client read-only, local edits won't be uploaded");
+        }
+        read_only.0 = event.message.read_only;
+    }
+}
+
+fn remotefile_codec_negotiated(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileCodecs>>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+) {
+    for event in reader.read() {
+        transfers.codec = event.message.negotiate();
+        info!("Server supports {:?}, sending remotefiles with {:?}", event.message.0, transfers.codec);
+    }
+}
+
+// Compare the files of a room we entered with ours, and ask the server for the ones that differ
+fn remotefile_manifest<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<AssetManifest>>,
+    mut client: ResMut<ConnectionManager>,
+    transfers: Res<RemoteFileClientTransfers>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    mut loading: ResMut<RemoteFileLoading>,
+    asset_root: Res<S>,
+    workspace: Res<RemoteFileWorkspace>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in reader.read() {
+        let manifest = &event.message;
+        let mut diff = AssetManifestDiff { room: manifest.room, prefetch: manifest.prefetch, stale: Vec::new() };
+        if !manifest.prefetch {
+            asset_root.keep(manifest.room, manifest.entries.iter().map(|entry| entry.file_name.clone()).collect());
+        }
+        for entry in manifest.entries.iter() {
+            if let Err(e) = SandboxedAssetRoot::sanitize(&entry.file_name) {
+                warn!("Ignoring manifest entry with invalid path {:?}: {:?}", entry.file_name.0, e);
+                continue;
+            }
+            // If our own copy is the latest, remember it so edits to it can be sent as a delta
+            let synced = synced_versions.files.get(&entry.file_name).is_some_and(|synced| synced.hash == entry.hash);
+            if !synced {
+                if let Ok(file_data) = workspace.0.read(&entry.file_name) {
+                    if hash_bytes(&file_data) == entry.hash {
+                        synced_versions.record(entry.file_name.clone(), &file_data);
+                    }
+                }
+            }
+            let cached = hash_cache.get(&*asset_root, &entry.file_name);
+            if cached.as_ref().is_some_and(|cached| cached.size == entry.size && cached.hash == entry.hash) {
+                continue;
+            }
+            // We had this version before, e.g. in an earlier visit to the room
+            match asset_root.restore(&entry.file_name, &entry.hash) {
+                Ok(true) => {
+                    info!("RemoteFile {:?} restored from the cache", entry.file_name.0);
+                    remotefile_reload(&asset_server, &entry.file_name);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => error!("Failed to restore remotefile from the cache: {:?}", e),
+            }
+            let mut message = remotefile_get_hash(&*asset_root, entry.file_name.clone(), true);
+            // If a download was interrupted, ask the server to continue where it left off
+            message.resume = transfers.incoming.get(&entry.file_name).map(|transfer| RemoteFileResume {
+                hash: transfer.hash.clone(),
+                codec: transfer.codec,
+                offset: transfer.resume_offset(),
+            });
+            diff.stale.push(message);
+        }
+        info!("Room {:?} manifest: {} of {} files out of date", manifest.room, diff.stale.len(), manifest.entries.len());
+        // The room's levels and scripts wait until the stale files are downloaded. A prefetched
+        // room isn't loaded yet, it gets a manifest of its own once we enter it.
+        if !manifest.prefetch {
+            loading.expect(manifest.room, diff.stale.iter().map(|stale| stale.file_name.clone()));
+        }
+        if diff.stale.is_empty() {
+            continue;
+        }
+        client.send_message::<Channel1, AssetManifestDiff>(&mut diff).unwrap_or_else(|e| {
+            error!("Failed to send message: {:?}", e);
+        });
+    }
+}
+
+// Send every change made in the workspace to the server: edited files are uploaded, deleted and
+// renamed files are deleted and renamed on the server too. The cache is changed the same way, as
+// the server won't send our own changes back. A read-only client only reports them.
+fn remotefile_modified<S: RemoteFileStore>(
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    asset_root: Res<S>,
+    workspace: Res<RemoteFileWorkspace>,
+    asset_server: Res<AssetServer>,
+    ignore: Res<RemoteFileIgnore>,
+    read_only: Res<RemoteFileReadOnly>,
+    mut diverged: EventWriter<RemoteFileDiverged>,
+    watcher: Option<Res<RemoteFileWatcher>>,
+) {
+    let Some(watcher) = watcher else {
+        return;
+    };
+    let mut report_divergence = |file_name: RemoteFileName| {
+        warn!("RemoteFile {:?} differs from the server's version, not uploading it as this client is read-only", file_name.0);
+        diverged.send(RemoteFileDiverged(file_name));
+    };
+    for change in watcher.changes() {
+        let remote_file_name = match change {
+            RemoteFileChange::Written(file_name) => file_name,
+            // Only files the server knows about, which also skips the ones the server just deleted
+            RemoteFileChange::Removed(file_name) => {
+                if read_only.0 {
+                    if !ignore.is_ignored(&file_name) && synced_versions.files.contains_key(&file_name) {
+                        report_divergence(file_name);
+                    }
+                    continue;
+                }
+                if !ignore.is_ignored(&file_name) && synced_versions.forget(&file_name) {
+                    info!("RemoteFile deleted: {:?}", file_name.0);
+                    if let Err(e) = asset_root.remove(&file_name) {
+                        error!("Failed to delete cached remotefile: {:?}", e);
+                    }
+                    remotefile_reload(&asset_server, &file_name);
+                    client.send_message::<Channel1, RemoteFileDeleted>(&mut RemoteFileDeleted { file_name }).unwrap_or_else(|e| {
+                        error!("Failed to send message: {:?}", e);
+                    });
+                }
+                continue;
+            }
+            RemoteFileChange::Renamed { from, to } => {
+                if ignore.is_ignored(&from) || ignore.is_ignored(&to) {
+                    continue;
+                }
+                if read_only.0 {
+                    if synced_versions.files.contains_key(&from) {
+                        report_divergence(from);
+                    }
+                    continue;
+                }
+                if synced_versions.rename(&from, &to) {
+                    info!("RemoteFile renamed: {:?} to {:?}", from.0, to.0);
+                    if let Err(e) = asset_root.rename(&from, &to) {
+                        error!("Failed to rename cached remotefile: {:?}", e);
+                    }
+                    remotefile_reload(&asset_server, &from);
+                    remotefile_reload(&asset_server, &to);
+                    client.send_message::<Channel1, RemoteFileRenamed>(&mut RemoteFileRenamed { from, to }).unwrap_or_else(|e| {
+                        error!("Failed to send message: {:?}", e);
+                    });
+                    continue;
+                }
+                // A file the server doesn't have was moved, to the server it's a new file
+                to
+            }
+        };
+        if ignore.is_ignored(&remote_file_name) {
+            continue;
+        }
+        let file_data = match workspace.0.read(&remote_file_name) {
+            Ok(file_data) => file_data,
+            Err(e) => {
+                error!("Failed to read file: {:?}", e);
+                continue;
+            }
+        };
+        // Files saved without changes already match the server's, or are being uploaded
+        let synced = synced_versions.base(&remote_file_name);
+        if synced.is_some_and(|synced| synced.hash == hash_bytes(&file_data)) {
+            continue;
+        }
+        if read_only.0 {
+            report_divergence(remote_file_name);
+            continue;
+        }
+        // File was changed, but not from the server. Try uploading to the server, who broadcasts it.
+        info!("RemoteFile asset modified: {:?}", remote_file_name.0);
+        // Only send the changed blocks if the server has the version we started from
+        let delta = synced.and_then(|synced| {
+            let signature = synced.signature.as_ref()?;
+            remotefile_delta(remote_file_name.clone(), synced.hash.clone(), signature, &file_data)
+        });
+        let base_hash = synced.map(|synced| synced.hash.clone()).unwrap_or_default();
+        synced_versions.uploading(remote_file_name.clone(), &file_data);
+        // Show our edit right away, if the server refuses it we ask for its version again
+        match asset_root.write(&remote_file_name, &file_data) {
+            Ok(_) => {
+                hash_cache.update(&*asset_root, &remote_file_name, &file_data);
+                remotefile_reload(&asset_server, &remote_file_name);
+            }
+            Err(e) => error!("Failed to cache remotefile: {:?}", e),
+        }
+        match delta {
+            Some(delta) => {
+                info!("Uploading remotefile {:?} as a delta of {} bytes", remote_file_name.0, delta.data_len());
+                let delta = delta.compressed(transfers.codec);
+                transfers.upload_delta(delta);
+            }
+            None => transfers.upload(remote_file_name, file_data, base_hash),
+        }
+    }
+}
+
+// Send the next few chunks of every queued upload
+fn remotefile_upload_chunks(
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+) {
+    for transfer in transfers.outgoing.iter_mut() {
+        if let Some(mut delta) = transfer.take_delta() {
+            client.send_message::<RemoteFileChannel, RemoteFileDelta>(&mut delta).unwrap_or_else(|e| {
+                error!("Failed to send message: {:?}", e);
+            });
+        }
+        for mut chunk in std::iter::from_fn(|| transfer.next_chunk()).take(CHUNKS_PER_UPDATE) {
+            client.send_message::<RemoteFileChannel, RemoteFileChunk>(&mut chunk).unwrap_or_else(|e| {
+                error!("Failed to send message: {:?}", e);
+            });
+        }
+    }
+    transfers.outgoing.retain(|transfer| !transfer.is_finished());
+}
+
+// System to receive messages on the client
+pub(crate) fn remotefile_download<S: RemoteFileStore>(
+    mut reader: ResMut<Events<lightyear::client::events::MessageEvent<RemoteFileChunk>>>,
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    mut progress: EventWriter<RemoteFileProgress>,
+    mut loading: ResMut<RemoteFileLoading>,
+    settings: Res<Settings>,
+    asset_root: Res<S>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in reader.drain() {
+        let chunk = event.message;
+        // Don't even buffer a file we could never write, tell the server instead
+        if let Err(e) = SandboxedAssetRoot::sanitize(&chunk.file_name) {
+            if chunk.offset == 0 {
+                warn!("Refusing remotefile with invalid path {:?}: {:?}", chunk.file_name.0, e);
+                let mut message = RemoteFileRejected {
+                    file_name: chunk.file_name.clone(),
+                    reason: RemoteFileRejection::InvalidPath(e),
+                };
+                client.send_message::<Channel1, RemoteFileRejected>(&mut message).unwrap_or_else(|e| {
+                    error!("Failed to send message: {:?}", e);
+                });
+                // Never coming, don't keep the level waiting for it
+                loading.downloaded(&chunk.file_name);
+            }
+            continue;
+        }
+        if transfers.is_stale(&chunk.file_name, chunk.transfer_id) {
+            continue;
+        }
+        // Reassemble the file, starting over if the server began sending a newer version
+        if !transfers.incoming.get(&chunk.file_name).is_some_and(|download| download.matches(&chunk)) {
+            match IncomingTransfer::new(&chunk, settings.shared.remote_file_max_size) {
+                Ok(download) => {
+                    transfers.incoming.insert(chunk.file_name.clone(), download);
+                }
+                Err(e) => {
+                    transfers.incoming.remove(&chunk.file_name);
+                    if chunk.offset == 0 {
+                        warn!("Refusing remotefile {:?}: {:?}", chunk.file_name.0, e);
+                        // Never coming, don't keep the level waiting for it
+                        loading.downloaded(&chunk.file_name);
+                    }
+                    continue;
+                }
+            }
+        }
+        let Some(download) = transfers.incoming.get_mut(&chunk.file_name) else {
+            continue;
+        };
+        let complete = download.insert(&chunk);
+        let (received, total) = download.progress();
+        progress.send(RemoteFileProgress { file_name: chunk.file_name.clone(), received, total });
+        if !complete {
+            continue;
+        }
+        // The hash is checked against the data below, so a valid signature covers the contents
+        if !origin::verify(&settings.shared.remote_file_public_key, &chunk.file_name, &chunk.hash, &chunk.server_signature) {
+            transfers.incoming.remove(&chunk.file_name);
+            remotefile_refuse_unsigned(&mut client, &mut loading, &chunk.file_name);
+            continue;
+        }
+        let Some(file_data) = transfers.incoming.remove(&chunk.file_name).and_then(IncomingTransfer::finish) else {
+            error!("RemoteFile download {:?} doesn't match its hash, requesting the file again", chunk.file_name.0);
+            let mut message = remotefile_get_hash(&*asset_root, chunk.file_name.clone(), true);
+            client.send_message::<Channel1, RemoteFileHash>(&mut message).unwrap_or_else(|e| {
+                error!("Failed to send message: {:?}", e);
+            });
+            continue;
+        };
+
+        // Save the file to the cache, our own copy stays as it is
+        match asset_root.write(&chunk.file_name, &file_data) {
+            Ok(_) => {
+                info!("RemoteFile downloaded: {:?}", chunk.file_name.0);
+                hash_cache.update(&*asset_root, &chunk.file_name, &file_data);
+                remotefile_reload(&asset_server, &chunk.file_name);
+            }
+            Err(e) => {
+                error!("Failed to download remotefile: {:?}", e);
+            }
+        }
+        loading.downloaded(&chunk.file_name);
+    }
+}
+
+// Apply changes the server sent as a delta against our version of a file
+#[allow(clippy::too_many_arguments)]
+fn remotefile_download_delta<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileDelta>>,
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    mut loading: ResMut<RemoteFileLoading>,
+    settings: Res<Settings>,
+    asset_root: Res<S>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in reader.read() {
+        if transfers.is_stale(&event.message.file_name, event.message.transfer_id) {
+            continue;
+        }
+        // A delta replaces any older version that was still arriving in chunks
+        transfers.incoming.remove(&event.message.file_name);
+        let delta = match event.message.clone().decompressed() {
+            Ok(delta) => delta,
+            Err(e) => {
+                error!("Failed to decompress remotefile delta: {:?}", e);
+                continue;
+            }
+        };
+        if !origin::verify(&settings.shared.remote_file_public_key, &delta.file_name, &delta.hash, &delta.server_signature) {
+            remotefile_refuse_unsigned(&mut client, &mut loading, &delta.file_name);
+            continue;
+        }
+        let file_data = asset_root.read(&delta.file_name).ok()
+            .filter(|base| hash_bytes(base) == delta.base_hash)
+            .and_then(|base| apply_delta(&base, delta.block_size, &delta.ops, settings.shared.remote_file_max_size as usize))
+            .filter(|file_data| hash_bytes(file_data) == delta.hash);
+        let Some(file_data) = file_data else {
+            // Our file isn't the version the delta was made for, ask for the latest version again
+            info!("RemoteFile delta for {:?} doesn't apply, requesting the file again", delta.file_name.0);
+            let mut message = remotefile_get_hash(&*asset_root, delta.file_name.clone(), true);
+            client.send_message::<Channel1, RemoteFileHash>(&mut message).unwrap_or_else(|e| {
+                error!("Failed to send message: {:?}", e);
+            });
+            continue;
+        };
+
+        match asset_root.write(&delta.file_name, &file_data) {
+            Ok(_) => {
+                info!("RemoteFile downloaded as a delta: {:?}", delta.file_name.0);
+                hash_cache.update(&*asset_root, &delta.file_name, &file_data);
+                remotefile_reload(&asset_server, &delta.file_name);
+            }
+            Err(e) => {
+                error!("Failed to download remotefile: {:?}", e);
+            }
+        }
+        loading.downloaded(&delta.file_name);
+    }
+}
+
+// A file that doesn't come from the server is never written. It's not asked for again either, the
+// sender would only send the same file back, so the cache keeps the version it had.
+fn remotefile_refuse_unsigned(client: &mut ConnectionManager, loading: &mut RemoteFileLoading, file_name: &RemoteFileName) {
+    warn!("Refusing remotefile {:?}, it isn't signed by the server", file_name.0);
+    let mut message = RemoteFileRejected {
+        file_name: file_name.clone(),
+        reason: RemoteFileRejection::InvalidSignature,
+    };
+    client.send_message::<Channel1, RemoteFileRejected>(&mut message).unwrap_or_else(|e| {
+        error!("Failed to send message: {:?}", e);
+    });
+    // Don't keep the level waiting for it
+    loading.downloaded(file_name);
+}
+
+// The server refused one of our uploads, the local file now differs from the server's, or a file
+// we asked for
+#[allow(clippy::too_many_arguments)]
+fn remotefile_rejected<S: RemoteFileStore>(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileRejected>>,
+    mut client: ResMut<ConnectionManager>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+    mut read_only: ResMut<RemoteFileReadOnly>,
+    mut loading: ResMut<RemoteFileLoading>,
+    asset_root: Res<S>,
+    workspace: Res<RemoteFileWorkspace>,
+) {
+    for event in reader.read() {
+        let file_name = &event.message.file_name;
+        // Asking for the file again would be refused the same way
+        let refused = matches!(
+            event.message.reason,
+            RemoteFileRejection::NotReadable | RemoteFileRejection::InvalidPath(_) | RemoteFileRejection::Unavailable
+        );
+        if refused {
+            warn!("RemoteFile {:?} was refused by the server: {:?}", file_name.0, event.message.reason);
+            synced_versions.refused(file_name, None);
+            loading.downloaded(file_name);
+            continue;
+        }
+        // The server made us read-only since we connected
+        if event.message.reason == RemoteFileRejection::ReadOnly {
+            read_only.0 = true;
+            transfers.outgoing.clear();
+        }
+        if let RemoteFileRejection::DeltaBaseMismatch { base_hash } = &event.message.reason {
+            // The server had a different version than we thought, send the whole file instead
+            match workspace.0.read(file_name) {
+                Ok(file_data) => transfers.upload(file_name.clone(), file_data, base_hash.clone()),
+                Err(e) => error!("Failed to read file: {:?}", e),
+            }
+            continue;
+        }
+        warn!("RemoteFile upload {:?} was rejected by the server: {:?}", file_name.0, event.message.reason);
+        synced_versions.refused(file_name, None);
+        // The cache has our edit, put the server's version back
+        let mut message = remotefile_get_hash(&*asset_root, file_name.clone(), true);
+        client.send_message::<Channel1, RemoteFileHash>(&mut message).unwrap_or_else(|e| {
+            error!("Failed to send message: {:?}", e);
+        });
+    }
+}
+
+// Another client deleted or renamed a file, do the same to the cache. Our own copy is left alone,
+// it's no longer a version of anything the server has.
+fn remotefile_file_ops_received<S: RemoteFileStore>(
+    mut deleted_reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileDeleted>>,
+    mut renamed_reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileRenamed>>,
+    mut transfers: ResMut<RemoteFileClientTransfers>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+    mut loading: ResMut<RemoteFileLoading>,
+    asset_root: Res<S>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in deleted_reader.read() {
+        let file_name = &event.message.file_name;
+        synced_versions.forget(file_name);
+        transfers.incoming.remove(file_name);
+        loading.downloaded(file_name);
+        match asset_root.remove(file_name) {
+            Ok(_) => info!("RemoteFile deleted by the server: {:?}", file_name.0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to delete remotefile: {:?}", e),
+        }
+        remotefile_reload(&asset_server, file_name);
+    }
+    for event in renamed_reader.read() {
+        let RemoteFileRenamed { from, to } = &event.message;
+        synced_versions.forget(from);
+        transfers.incoming.remove(from);
+        loading.downloaded(from);
+        match asset_root.rename(from, to) {
+            Ok(_) => info!("RemoteFile renamed by the server: {:?} to {:?}", from.0, to.0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to rename remotefile: {:?}", e),
+        }
+        remotefile_reload(&asset_server, from);
+        remotefile_reload(&asset_server, to);
+    }
+}
+
+// Rooms out of view aren't ready anymore and the cache may remove their files, and files we
+// asked for that we already have are done
+fn remotefile_loading_replies<S: RemoteFileStore>(
+    mut left_reader: EventReader<lightyear::client::events::MessageEvent<AssetManifestLeft>>,
+    mut unchanged_reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileUnchanged>>,
+    mut loading: ResMut<RemoteFileLoading>,
+    asset_root: Res<S>,
+) {
+    for event in left_reader.read() {
+        loading.left(event.message.room);
+        asset_root.keep(event.message.room, Vec::new());
+    }
+    for event in unchanged_reader.read() {
+        loading.downloaded(&event.message.file_name);
+    }
+}
+
+// The server has our upload, it's the version our next edit is made from
+fn remotefile_accepted(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileAccepted>>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+) {
+    for event in reader.read() {
+        synced_versions.accepted(&event.message.file_name, &event.message.hash);
+    }
+}
+
+// Someone edited a file at the same time as us
+fn remotefile_conflicted(
+    mut reader: EventReader<lightyear::client::events::MessageEvent<RemoteFileConflict>>,
+    mut synced_versions: ResMut<RemoteFileSyncedVersions>,
+) {
+    for event in reader.read() {
+        let conflict = &event.message;
+        // If it was our upload, the server kept its own version
+        synced_versions.refused(&conflict.file_name, Some(&conflict.hash));
+        match &conflict.conflict_copy {
+            Some(conflict_copy) => warn!(
+                "RemoteFile {:?} was edited by clients {:?} and {:?} at the same time, the edit of client {:?} was saved as {:?}",
+                conflict.file_name.0, conflict.editor, conflict.uploader, conflict.uploader, conflict_copy.0
+            ),
+            None => warn!(
+                "RemoteFile {:?} was edited by clients {:?} and {:?} at the same time, the edit of client {:?} was rejected and must be merged by hand",
+                conflict.file_name.0, conflict.editor, conflict.uploader, conflict.uploader
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file that compresses well and is big enough to be sent in several chunks
+    fn level(len: usize) -> Vec<u8> {
+        (0..len).map(|i| b"<tile id=\"7\"/>"[i % 14]).collect()
+    }
+
+    // Send a queued transfer the way the systems do, and reassemble it on the other side
+    fn deliver(transfer: &mut OutgoingTransfer) -> Option<Vec<u8>> {
+        let chunks: Vec<RemoteFileChunk> = std::iter::from_fn(|| transfer.next_chunk()).collect();
+        let mut incoming = IncomingTransfer::new(chunks.first()?, u64::MAX).ok()?;
+        let mut complete = false;
+        for chunk in chunks.iter() {
+            complete = incoming.insert(chunk);
+        }
+        if !complete {
+            return None;
+        }
+        incoming.finish()
+    }
+
+    #[test]
+    fn upload_is_broadcast_to_the_room() {
+        let file_name = RemoteFileName("maps/map_1.tmx".to_string());
+        let server = MemoryRemoteFileStore::default();
+        let other_client = MemoryRemoteFileStore::default();
+        let base = level(CHUNK_SIZE * 5);
+        server.write(&file_name, &base).unwrap();
+
+        let mut edited = base.clone();
+        edited.extend_from_slice(b"<tile id=\"8\"/>");
+        let mut uploads = RemoteFileClientTransfers { codec: RemoteFileCodec::Zstd, ..default() };
+        uploads.upload(file_name.clone(), edited.clone(), hash_bytes(&base));
+        let upload = deliver(&mut uploads.outgoing[0]).unwrap();
+        assert_eq!(upload, edited);
+        server.write(&file_name, &upload).unwrap();
+
+        let mut transfers = RemoteFileServerTransfers::default();
+        let room = [ClientId::Netcode(2), ClientId::Netcode(3)];
+        for client_id in room {
+            transfers.codecs.insert(client_id, RemoteFileCodec::Zstd);
+        }
+        remotefile_broadcast(&mut transfers, room.to_vec(), file_name.clone(), upload, None);
+        // Compressed once for the whole room
+        assert_eq!(transfers.payloads.len(), 1);
+        for client_id in room {
+            let queued = transfers.outgoing.get_mut(&client_id).unwrap();
+            assert_eq!(queued.len(), 1);
+            assert_eq!(queued[0].codec, RemoteFileCodec::Zstd);
+            let download = deliver(&mut queued[0]).unwrap();
+            other_client.write(&file_name, &download).unwrap();
+            assert_eq!(other_client.read(&file_name).unwrap(), server.read(&file_name).unwrap());
+        }
+    }
+
+    #[test]
+    fn delta_upload_is_broadcast_as_a_delta() {
+        let file_name = RemoteFileName("maps/map_1.tmx".to_string());
+        let server = MemoryRemoteFileStore::default();
+        let other_client = MemoryRemoteFileStore::default();
+        let base = level(CHUNK_SIZE * 5);
+        server.write(&file_name, &base).unwrap();
+        other_client.write(&file_name, &base).unwrap();
+
+        // The uploader and the other client both have the server's version
+        let mut edited = base.clone();
+        edited[CHUNK_SIZE..CHUNK_SIZE + 10].copy_from_slice(b"0123456789");
+        let signature = remotefile_signature(&base).unwrap();
+        let delta = remotefile_delta(file_name.clone(), hash_bytes(&base), &signature, &edited).unwrap();
+        let mut uploads = RemoteFileClientTransfers::default();
+        uploads.upload_delta(delta);
+        let upload = uploads.outgoing[0].take_delta().unwrap().decompressed().unwrap();
+        assert_eq!(upload.base_hash, hash_bytes(&server.read(&file_name).unwrap()));
+        let file_data = apply_delta(&server.read(&file_name).unwrap(), upload.block_size, &upload.ops, usize::MAX).unwrap();
+        assert_eq!(hash_bytes(&file_data), upload.hash);
+        server.write(&file_name, &file_data).unwrap();
+
+        let mut transfers = RemoteFileServerTransfers::default();
+        remotefile_broadcast(&mut transfers, vec![ClientId::Netcode(2)], file_name.clone(), file_data, Some(upload));
+        let queued = transfers.outgoing.get_mut(&ClientId::Netcode(2)).unwrap();
+        assert!(queued[0].next_chunk().is_none());
+        let download = queued[0].take_delta().unwrap().decompressed().unwrap();
+        let file_data = apply_delta(&other_client.read(&file_name).unwrap(), download.block_size, &download.ops, usize::MAX).unwrap();
+        other_client.write(&file_name, &file_data).unwrap();
+        assert_eq!(other_client.read(&file_name).unwrap(), edited);
+    }
+
+    #[test]
+    fn prefetch_only_demotes_its_own_transfers() {
+        let tileset = RemoteFileName("tilesets/tiles.tsx".to_string());
+        let map = RemoteFileName("maps/map_2.tmx".to_string());
+        let client_id = ClientId::Netcode(2);
+        let mut transfers = RemoteFileServerTransfers::default();
+        // The tileset is queued for the room the player is in
+        transfers.send(client_id, tileset.clone(), Arc::new(level(100)), None);
+
+        // The room ahead uses it too
+        for file_name in [&tileset, &map] {
+            let after = transfers.next_transfer_id;
+            let was_queued = transfers.is_queued(client_id, file_name);
+            transfers.send(client_id, file_name.clone(), Arc::new(level(200)), None);
+            transfers.set_prefetch(client_id, file_name, after, was_queued);
+        }
+        let queued = &transfers.outgoing[&client_id];
+        let prefetch = |file_name: &RemoteFileName| queued.iter().find(|transfer| transfer.file_name == *file_name).unwrap().prefetch;
+        assert!(!prefetch(&tileset));
+        assert!(prefetch(&map));
+    }
+}
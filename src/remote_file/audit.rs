@@ -0,0 +1,182 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{RemoteFileName, RemoteFileRejection};
+
+// Server-side log of every upload, delete and rename and what was done with it, next to Cargo.toml
+pub(crate) const AUDIT_LOG_PATH: &str = "remote_file_audit.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileAuditEntry {
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    // The client that uploaded the file, None for a rollback on the server
+    pub client_id: Option<u64>,
+    pub file_name: RemoteFileName,
+    // Entries from before deletes and renames were logged are all uploads
+    #[serde(default)]
+    pub operation: RemoteFileAuditOperation,
+    // The server's version before the upload, empty if it had none
+    pub old_hash: String,
+    // Empty for a delete
+    pub new_hash: String,
+    // Size of the new version, or of the delta if it was rejected before being applied, 0 for a
+    // delete
+    pub size: u64,
+    // The rooms the new version was broadcast to
+    pub rooms: Vec<u64>,
+    pub decision: RemoteFileAuditDecision,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum RemoteFileAuditOperation {
+    #[default]
+    Upload,
+    Delete,
+    // The file was moved to `to`, keeping its hash
+    Rename { to: RemoteFileName },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RemoteFileAuditDecision {
+    Accepted,
+    Rejected(RemoteFileRejection),
+    // The upload was made from an older version, conflict_copy is where it was saved if it was
+    Conflict { conflict_copy: Option<RemoteFileName> },
+}
+
+impl RemoteFileAuditEntry {
+    pub(crate) fn new(client_id: Option<u64>, file_name: RemoteFileName, new_hash: String, size: u64) -> Self {
+        Self {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default(),
+            client_id,
+            file_name,
+            operation: RemoteFileAuditOperation::Upload,
+            old_hash: String::new(),
+            new_hash,
+            size,
+            rooms: Vec::new(),
+            decision: RemoteFileAuditDecision::Accepted,
+        }
+    }
+
+    pub(crate) fn decided(self, decision: RemoteFileAuditDecision) -> Self {
+        Self { decision, ..self }
+    }
+
+    pub(crate) fn with_operation(self, operation: RemoteFileAuditOperation) -> Self {
+        Self { operation, ..self }
+    }
+}
+
+// Which entries a query returns
+pub enum RemoteFileAuditFilter {
+    File(RemoteFileName),
+    Client(u64),
+}
+
+impl RemoteFileAuditFilter {
+    fn matches(&self, entry: &RemoteFileAuditEntry) -> bool {
+        match self {
+            RemoteFileAuditFilter::File(file_name) => {
+                entry.file_name == *file_name
+                    || matches!(&entry.operation, RemoteFileAuditOperation::Rename { to } if to == file_name)
+            }
+            RemoteFileAuditFilter::Client(client_id) => entry.client_id == Some(*client_id),
+        }
+    }
+}
+
+// Append-only, one JSON entry per line. Entries are never kept in memory, queries read the file.
+#[derive(Resource)]
+pub struct RemoteFileAuditLog {
+    path: PathBuf,
+}
+
+impl RemoteFileAuditLog {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Failing to log never stops an upload, it's only reported
+    pub(crate) fn record(&self, entry: &RemoteFileAuditEntry) {
+        let written = serde_json::to_string(entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| {
+                let mut log = OpenOptions::new().create(true).append(true).open(&self.path)?;
+                writeln!(log, "{}", line)
+            });
+        if let Err(e) = written {
+            error!("Failed to write remotefile audit log {:?}: {:?}", self.path, e);
+        }
+    }
+
+    // Matching entries, oldest first
+    pub fn query(&self, filter: &RemoteFileAuditFilter) -> std::io::Result<Vec<RemoteFileAuditEntry>> {
+        let log = match std::fs::File::open(&self.path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(log).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<RemoteFileAuditEntry>(&line) {
+                Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => error!("Skipping unreadable remotefile audit entry {:?}: {:?}", line, e),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn upload(client_id: u64, file_name: &str) -> RemoteFileAuditEntry {
+        RemoteFileAuditEntry::new(Some(client_id), RemoteFileName(file_name.to_string()), "new".to_string(), 3)
+    }
+
+    #[test]
+    fn entries_are_queried_by_file_and_client() {
+        let dir = TestDir::new();
+        let audit_log = RemoteFileAuditLog::new(dir.join("remote_file_audit.jsonl"));
+        assert!(audit_log.query(&RemoteFileAuditFilter::Client(1)).unwrap().is_empty());
+        let accepted = RemoteFileAuditEntry { old_hash: "old".to_string(), rooms: vec![2], ..upload(1, "map_2.tmx") };
+        let rejected = upload(2, "map_2.tmx").decided(RemoteFileAuditDecision::Rejected(RemoteFileRejection::PermissionDenied));
+        let conflict = upload(1, "map_3.tmx").decided(RemoteFileAuditDecision::Conflict { conflict_copy: None });
+        let rollback = RemoteFileAuditEntry::new(None, RemoteFileName("map_2.tmx".to_string()), "old".to_string(), 3);
+        let renamed = upload(3, "map_4.tmx").with_operation(RemoteFileAuditOperation::Rename { to: RemoteFileName("map_2.tmx".to_string()) });
+        for entry in [&accepted, &rejected, &conflict, &rollback, &renamed] {
+            audit_log.record(entry);
+        }
+
+        let map_2 = audit_log.query(&RemoteFileAuditFilter::File(RemoteFileName("map_2.tmx".to_string()))).unwrap();
+        assert_eq!(map_2, vec![accepted.clone(), rejected, rollback, renamed]);
+        assert_eq!(audit_log.query(&RemoteFileAuditFilter::Client(1)).unwrap(), vec![accepted, conflict]);
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let dir = TestDir::new();
+        let audit_log = RemoteFileAuditLog::new(dir.join("remote_file_audit.jsonl"));
+        audit_log.record(&upload(1, "map_2.tmx"));
+        let mut log = OpenOptions::new().append(true).open(&audit_log.path).unwrap();
+        writeln!(log, "\n{{\"timestamp\":").unwrap();
+        audit_log.record(&upload(1, "map_3.tmx"));
+        assert_eq!(audit_log.query(&RemoteFileAuditFilter::Client(1)).unwrap().len(), 2);
+    }
+}
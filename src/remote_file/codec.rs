@@ -0,0 +1,138 @@
+// Compression of RemoteFile payloads. Only file data is compressed, so the transport's global
+// compression setting can stay off for the per-tick gameplay messages.
+
+use std::io::Read;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Payloads smaller than this are sent as they are, compressing them saves next to nothing
+pub(crate) const COMPRESSION_THRESHOLD: usize = 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RemoteFileCodec {
+    #[default]
+    None,
+    Zstd,
+}
+
+// The codecs this build can decode, best first
+const SUPPORTED_CODECS: &[RemoteFileCodec] = &[RemoteFileCodec::Zstd, RemoteFileCodec::None];
+
+// The codecs the sender can decode, sent by each side after connecting. Until it arrives
+// nothing is compressed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileCodecs(pub Vec<RemoteFileCodec>);
+
+impl RemoteFileCodecs {
+    pub(crate) fn supported() -> Self {
+        Self(SUPPORTED_CODECS.to_vec())
+    }
+
+    // Our best codec the other side can decode
+    pub(crate) fn negotiate(&self) -> RemoteFileCodec {
+        SUPPORTED_CODECS.iter()
+            .copied()
+            .find(|codec| self.0.contains(codec))
+            .unwrap_or_default()
+    }
+}
+
+// Compress a payload if it's big enough and actually gets smaller. Returns the codec that was used.
+pub(crate) fn compress(codec: RemoteFileCodec, data: &[u8]) -> (RemoteFileCodec, Vec<u8>) {
+    if codec == RemoteFileCodec::None || data.len() < COMPRESSION_THRESHOLD {
+        return (RemoteFileCodec::None, data.to_vec());
+    }
+    match compress_with(codec, data) {
+        Ok(compressed) if compressed.len() < data.len() => (codec, compressed),
+        Ok(_) => (RemoteFileCodec::None, data.to_vec()),
+        Err(e) => {
+            error!("Failed to compress remotefile payload: {:?}", e);
+            (RemoteFileCodec::None, data.to_vec())
+        }
+    }
+}
+
+// Compress with the codec regardless of size
+pub(crate) fn compress_with(codec: RemoteFileCodec, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match codec {
+        RemoteFileCodec::None => Ok(data.to_vec()),
+        RemoteFileCodec::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+    }
+}
+
+// Decompress a payload that should be at most max_len bytes. Decoding stops as soon as it's
+// bigger, so a small payload can't make the receiver allocate more than it expects.
+pub(crate) fn decompress(codec: RemoteFileCodec, data: &[u8], max_len: usize) -> std::io::Result<Vec<u8>> {
+    let data = match codec {
+        RemoteFileCodec::None => data.to_vec(),
+        RemoteFileCodec::Zstd => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            decompressed
+        }
+    };
+    if data.len() > max_len {
+        let message = format!("payload is bigger than the expected {} bytes", max_len);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(len: usize) -> Vec<u8> {
+        b"<tile id=\"1\"/>\n".iter().copied().cycle().take(len).collect()
+    }
+
+    #[test]
+    fn negotiates_the_best_shared_codec() {
+        assert_eq!(RemoteFileCodecs::supported().negotiate(), RemoteFileCodec::Zstd);
+        assert_eq!(RemoteFileCodecs(vec![RemoteFileCodec::None]).negotiate(), RemoteFileCodec::None);
+        // Older peers that don't send their codecs get nothing compressed
+        assert_eq!(RemoteFileCodecs(Vec::new()).negotiate(), RemoteFileCodec::None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = text(10_000);
+        let (codec, compressed) = compress(RemoteFileCodec::Zstd, &data);
+        assert_eq!(codec, RemoteFileCodec::Zstd);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(codec, &compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn small_or_incompressible_payloads_are_sent_as_they_are() {
+        let small = text(COMPRESSION_THRESHOLD - 1);
+        assert_eq!(compress(RemoteFileCodec::Zstd, &small), (RemoteFileCodec::None, small));
+        // Already compressed data doesn't get any smaller
+        let compressed = compress_with(RemoteFileCodec::Zstd, &text(100_000)).unwrap();
+        assert_eq!(compress(RemoteFileCodec::Zstd, &compressed), (RemoteFileCodec::None, compressed));
+    }
+
+    #[test]
+    fn refuses_payloads_bigger_than_expected() {
+        // A few hundred bytes that decompress to megabytes
+        let data = vec![0; 8 * 1024 * 1024];
+        let bomb = compress_with(RemoteFileCodec::Zstd, &data).unwrap();
+        assert!(bomb.len() < 1024);
+        assert!(decompress(RemoteFileCodec::Zstd, &bomb, 1024).is_err());
+        assert!(decompress(RemoteFileCodec::Zstd, &bomb, data.len() - 1).is_err());
+        assert_eq!(decompress(RemoteFileCodec::Zstd, &bomb, data.len()).unwrap().len(), data.len());
+        assert!(decompress(RemoteFileCodec::None, &data, 1024).is_err());
+    }
+
+    #[test]
+    fn refuses_corrupt_payloads() {
+        let mut compressed = compress_with(RemoteFileCodec::Zstd, &text(10_000)).unwrap();
+        compressed.truncate(compressed.len() / 2);
+        assert!(decompress(RemoteFileCodec::Zstd, &compressed, 10_000).is_err());
+    }
+}
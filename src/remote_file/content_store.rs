@@ -0,0 +1,336 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{asset::ron, prelude::*, utils::{HashMap, HashSet}};
+use serde::{Deserialize, Serialize};
+
+use super::{hash_bytes, store::{not_found, sanitize}, RemoteFileMetadata, RemoteFileName, RemoteFileStore};
+
+// A store that keeps every version by its SHA-256 in `objects/`, with `index.ron` mapping file
+// names to hashes. Hashes come from the index, so nothing is read back to check a file is up to
+// date, and a version seen before can be restored without downloading it. Once the objects take
+// more than the size limit, the least recently used are removed, old versions no file has first.
+// The files of the rooms in view are never removed.
+#[derive(Resource, Clone)]
+pub struct RemoteFileContentStore {
+    path: PathBuf,
+    state: Arc<Mutex<ContentStoreState>>,
+}
+
+struct ContentStoreState {
+    index: ContentIndex,
+    max_size: u64,
+    // The files of each room in view, see RemoteFileStore::keep
+    kept: HashMap<u64, HashSet<RemoteFileName>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ContentIndex {
+    files: HashMap<RemoteFileName, String>,
+    objects: HashMap<String, ContentObject>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContentObject {
+    size: u64,
+    // Seconds since the unix epoch
+    last_used: u64,
+}
+
+impl RemoteFileContentStore {
+    pub fn open(path: impl Into<PathBuf>, max_size: u64) -> Self {
+        let path = path.into();
+        let index = match std::fs::read_to_string(path.join("index.ron")) {
+            Ok(index) => ron::de::from_str(&index).unwrap_or_else(|e| {
+                error!("Unreadable remotefile cache index, starting empty: {:?}", e);
+                ContentIndex::default()
+            }),
+            Err(_) => ContentIndex::default(),
+        };
+        info!("Opened remotefile cache {:?} with {} files", path, index.files.len());
+        Self {
+            path,
+            state: Arc::new(Mutex::new(ContentStoreState { index, max_size, kept: HashMap::default() })),
+        }
+    }
+
+    pub fn set_max_size(&self, max_size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.max_size = max_size;
+        if let Err(e) = self.evict(&mut state, None) {
+            error!("Failed to evict from the remotefile cache: {:?}", e);
+        }
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.path.join("objects").join(hash)
+    }
+
+    // Remove objects until the cache fits, except the one just written and the ones of the rooms
+    // in view. Versions no file has go first, then the least recently used.
+    fn evict(&self, state: &mut ContentStoreState, keep: Option<&str>) -> std::io::Result<()> {
+        let mut total: u64 = state.index.objects.values().map(|object| object.size).sum();
+        if total <= state.max_size {
+            return self.save(state);
+        }
+        let mut candidates: Vec<(bool, u64, String, u64)> = {
+            let kept: HashSet<&String> = state.kept.values().flatten()
+                .filter_map(|file_name| state.index.files.get(file_name))
+                .collect();
+            let current: HashSet<&String> = state.index.files.values().collect();
+            state.index.objects.iter()
+                .filter(|(hash, _)| Some(hash.as_str()) != keep && !kept.contains(hash))
+                .map(|(hash, object)| (current.contains(hash), object.last_used, hash.clone(), object.size))
+                .collect()
+        };
+        candidates.sort();
+        for (_, _, hash, size) in candidates {
+            if total <= state.max_size {
+                break;
+            }
+            self.forget(state, &hash)?;
+            total -= size;
+        }
+        self.save(state)
+    }
+
+    // Remove an object and every file that has it
+    fn forget(&self, state: &mut ContentStoreState, hash: &str) -> std::io::Result<()> {
+        match std::fs::remove_file(self.object_path(hash)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        state.index.objects.remove(hash);
+        state.index.files.retain(|_, file_hash| file_hash != hash);
+        Ok(())
+    }
+
+    // The object with the hash, or None if it's missing or was damaged, e.g. by a crash
+    fn read_object(&self, state: &mut ContentStoreState, hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let data = match std::fs::read(self.object_path(hash)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        if hash_bytes(&data) == hash {
+            return Ok(Some(data));
+        }
+        warn!("Remotefile cache object {} is damaged, removing it", hash);
+        self.forget(state, hash)?;
+        self.save(state)?;
+        Ok(None)
+    }
+
+    fn save(&self, state: &ContentStoreState) -> std::io::Result<()> {
+        let index = ron::ser::to_string(&state.index).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        std::fs::create_dir_all(&self.path)?;
+        write_and_rename(&self.path.join("index.ron"), index.as_bytes())
+    }
+}
+
+impl RemoteFileStore for RemoteFileContentStore {
+    // Files aren't stored under their names
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn metadata(&self, file_name: &RemoteFileName) -> std::io::Result<RemoteFileMetadata> {
+        let file_name = sanitize(file_name)?;
+        let state = self.state.lock().unwrap();
+        let hash = state.index.files.get(&file_name).ok_or_else(|| not_found(&file_name))?;
+        let object = state.index.objects.get(hash).ok_or_else(|| not_found(&file_name))?;
+        Ok(RemoteFileMetadata {
+            len: object.size,
+            modified: None,
+            hash: Some(hash.clone()),
+        })
+    }
+
+    // A damaged file is removed, so the next manifest downloads it again. The index is only saved
+    // when the last used time changes, which is at most once a second per file.
+    fn read(&self, file_name: &RemoteFileName) -> std::io::Result<Vec<u8>> {
+        let file_name = sanitize(file_name)?;
+        let mut state = self.state.lock().unwrap();
+        let hash = state.index.files.get(&file_name).cloned().ok_or_else(|| not_found(&file_name))?;
+        let data = self.read_object(&mut state, &hash)?.ok_or_else(|| not_found(&file_name))?;
+        let now = now();
+        if let Some(object) = state.index.objects.get_mut(&hash).filter(|object| object.last_used != now) {
+            object.last_used = now;
+            self.save(&state)?;
+        }
+        Ok(data)
+    }
+
+    // Objects are written next to their final path and then moved there, so a crash never leaves
+    // half an object behind. They're written again even if they exist, which repairs them.
+    fn write(&self, file_name: &RemoteFileName, data: &[u8]) -> std::io::Result<()> {
+        let file_name = sanitize(file_name)?;
+        let hash = hash_bytes(data);
+        std::fs::create_dir_all(self.path.join("objects"))?;
+        write_and_rename(&self.object_path(&hash), data)?;
+        let mut state = self.state.lock().unwrap();
+        state.index.objects.insert(hash.clone(), ContentObject { size: data.len() as u64, last_used: now() });
+        state.index.files.insert(file_name, hash.clone());
+        self.evict(&mut state, Some(&hash))
+    }
+
+    // The contents stay cached until they're evicted, in case the file comes back
+    fn remove(&self, file_name: &RemoteFileName) -> std::io::Result<()> {
+        let file_name = sanitize(file_name)?;
+        let mut state = self.state.lock().unwrap();
+        if state.index.files.remove(&file_name).is_none() {
+            return Err(not_found(&file_name));
+        }
+        self.save(&state)
+    }
+
+    fn rename(&self, from: &RemoteFileName, to: &RemoteFileName) -> std::io::Result<()> {
+        let from = sanitize(from)?;
+        let to = sanitize(to)?;
+        let mut state = self.state.lock().unwrap();
+        let hash = state.index.files.remove(&from).ok_or_else(|| not_found(&from))?;
+        state.index.files.insert(to, hash);
+        self.save(&state)
+    }
+
+    fn restore(&self, file_name: &RemoteFileName, hash: &str) -> std::io::Result<bool> {
+        let file_name = sanitize(file_name)?;
+        let mut state = self.state.lock().unwrap();
+        if !state.index.objects.contains_key(hash) || self.read_object(&mut state, hash)?.is_none() {
+            return Ok(false);
+        }
+        if let Some(object) = state.index.objects.get_mut(hash) {
+            object.last_used = now();
+        }
+        state.index.files.insert(file_name, hash.to_string());
+        self.save(&state)?;
+        Ok(true)
+    }
+
+    fn keep(&self, room: u64, file_names: Vec<RemoteFileName>) {
+        let mut state = self.state.lock().unwrap();
+        if file_names.is_empty() {
+            state.kept.remove(&room);
+        } else {
+            state.kept.insert(room, file_names.into_iter().collect());
+        }
+    }
+}
+
+fn write_and_rename(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, data)?;
+    std::fs::rename(&temp_path, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn set_last_used(store: &RemoteFileContentStore, data: &[u8], last_used: u64) {
+        let mut state = store.state.lock().unwrap();
+        state.index.objects.get_mut(&hash_bytes(data)).unwrap().last_used = last_used;
+    }
+
+    fn name(file_name: &str) -> RemoteFileName {
+        RemoteFileName(file_name.to_string())
+    }
+
+    #[test]
+    fn hashes_come_from_the_index() {
+        let dir = TestDir::new();
+        let store = RemoteFileContentStore::open(dir.path(), u64::MAX);
+        store.write(&name("map.tmx"), b"first").unwrap();
+        let metadata = store.metadata(&name("map.tmx")).unwrap();
+        assert_eq!(metadata.hash, Some(hash_bytes(b"first")));
+        assert_eq!(metadata.len, 5);
+        assert_eq!(store.read(&name("map.tmx")).unwrap(), b"first");
+        // Nothing is left next to the objects
+        let objects: Vec<_> = std::fs::read_dir(store.path.join("objects")).unwrap().collect();
+        assert_eq!(objects.len(), 1);
+    }
+
+    #[test]
+    fn old_versions_are_restored_and_evicted_first() {
+        let dir = TestDir::new();
+        let store = RemoteFileContentStore::open(dir.path(), u64::MAX);
+        store.write(&name("map.tmx"), b"first").unwrap();
+        store.write(&name("map.tmx"), b"second").unwrap();
+        store.write(&name("other.tmx"), b"other").unwrap();
+        // The old version was used last, but no file has it anymore
+        set_last_used(&store, b"other", 1);
+        set_last_used(&store, b"second", 2);
+        set_last_used(&store, b"first", 3);
+        store.set_max_size(11);
+        assert!(!store.restore(&name("map.tmx"), &hash_bytes(b"first")).unwrap());
+        assert_eq!(store.read(&name("map.tmx")).unwrap(), b"second");
+        assert_eq!(store.read(&name("other.tmx")).unwrap(), b"other");
+
+        store.set_max_size(u64::MAX);
+        store.write(&name("map.tmx"), b"first").unwrap();
+        assert!(store.restore(&name("map.tmx"), &hash_bytes(b"second")).unwrap());
+        assert_eq!(store.read(&name("map.tmx")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn files_of_rooms_in_view_are_kept() {
+        let dir = TestDir::new();
+        let store = RemoteFileContentStore::open(dir.path(), u64::MAX);
+        store.write(&name("map_1.tmx"), b"room 1").unwrap();
+        store.write(&name("map_2.tmx"), b"room 2").unwrap();
+        set_last_used(&store, b"room 1", 1);
+        set_last_used(&store, b"room 2", 2);
+        store.keep(1, vec![name("map_1.tmx")]);
+        store.set_max_size(6);
+        assert_eq!(store.read(&name("map_1.tmx")).unwrap(), b"room 1");
+        assert!(store.read(&name("map_2.tmx")).is_err());
+
+        // Once the room is out of view, its files can go
+        store.keep(1, Vec::new());
+        store.write(&name("map_2.tmx"), b"room 2").unwrap();
+        assert!(store.read(&name("map_1.tmx")).is_err());
+    }
+
+    #[test]
+    fn least_recently_used_survives_a_restart() {
+        let dir = TestDir::new();
+        let store = RemoteFileContentStore::open(dir.path(), u64::MAX);
+        store.write(&name("map_1.tmx"), b"room 1").unwrap();
+        store.write(&name("map_2.tmx"), b"room 2").unwrap();
+        set_last_used(&store, b"room 1", 1);
+        set_last_used(&store, b"room 2", 2);
+        // Reading map_1 makes map_2 the least recently used
+        store.read(&name("map_1.tmx")).unwrap();
+        let store = RemoteFileContentStore::open(store.path.clone(), u64::MAX);
+        store.set_max_size(6);
+        assert_eq!(store.read(&name("map_1.tmx")).unwrap(), b"room 1");
+        assert!(store.read(&name("map_2.tmx")).is_err());
+    }
+
+    #[test]
+    fn damaged_objects_are_dropped_and_repaired() {
+        let dir = TestDir::new();
+        let store = RemoteFileContentStore::open(dir.path(), u64::MAX);
+        store.write(&name("map.tmx"), b"first").unwrap();
+        let object_path = store.object_path(&hash_bytes(b"first"));
+        std::fs::write(&object_path, b"fir").unwrap();
+        assert!(!store.restore(&name("map.tmx"), &hash_bytes(b"first")).unwrap());
+        assert_eq!(store.read(&name("map.tmx")).unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(store.metadata(&name("map.tmx")).is_err());
+
+        // Downloading it again writes the object again
+        std::fs::write(&object_path, b"fir").unwrap();
+        store.write(&name("map.tmx"), b"first").unwrap();
+        assert_eq!(store.read(&name("map.tmx")).unwrap(), b"first");
+    }
+}
@@ -0,0 +1,397 @@
+// rsync-style delta encoding. The receiver describes the file it has as a list of block
+// signatures, the sender then finds those blocks in the new file with a rolling checksum and
+// only sends the bytes in between.
+
+use bevy::{log::info, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{codec::{compress_with, decompress, RemoteFileCodec, COMPRESSION_THRESHOLD}, RemoteFileName};
+
+const MIN_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+// Files bigger than this are always sent whole, their signature would be too large to be worth it
+pub(crate) const MAX_SIGNATURE_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+// Deltas whose new bytes add up to more than this are sent as a chunked transfer instead
+pub(crate) const MAX_DELTA_DATA_SIZE: usize = 256 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockSignature {
+    weak: u32,
+    strong: u64,
+}
+
+// The block signatures of one version of a file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileSignature {
+    pub(crate) block_size: u32,
+    file_len: u64,
+    blocks: Vec<BlockSignature>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DeltaOp {
+    // Reuse `count` blocks of the receiver's file, starting at `block`
+    Copy { block: u32, count: u32 },
+    // Bytes the receiver doesn't have
+    Data(Vec<u8>),
+}
+
+// A file expressed as changes to the version with base_hash
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileDelta {
+    // Deltas are queued like chunked transfers and share their ids, see OutgoingTransfer::delta
+    pub(crate) transfer_id: u64,
+    pub(crate) file_name: RemoteFileName,
+    pub(crate) base_hash: String,
+    pub(crate) hash: String,
+    // The server's signature of the file name and hash, see origin.rs. Empty on uploads.
+    pub(crate) server_signature: Vec<u8>,
+    pub(crate) block_size: u32,
+    // How the bytes of the Data ops are compressed
+    pub(crate) codec: RemoteFileCodec,
+    pub(crate) ops: Vec<DeltaOp>,
+}
+
+impl RemoteFileDelta {
+    // Number of literal bytes the delta carries
+    pub(crate) fn data_len(&self) -> usize {
+        self.ops.iter()
+            .map(|op| match op {
+                DeltaOp::Data(data) => data.len(),
+                DeltaOp::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    // Compress the bytes of every Data op, if that makes the delta smaller
+    pub(crate) fn compressed(self, codec: RemoteFileCodec) -> Self {
+        let data_len = self.data_len();
+        if codec == RemoteFileCodec::None || self.codec != RemoteFileCodec::None || data_len < COMPRESSION_THRESHOLD {
+            return self;
+        }
+        let ops: std::io::Result<Vec<DeltaOp>> = self.ops.iter()
+            .map(|op| match op {
+                DeltaOp::Data(data) => compress_with(codec, data).map(DeltaOp::Data),
+                DeltaOp::Copy { block, count } => Ok(DeltaOp::Copy { block: *block, count: *count }),
+            })
+            .collect();
+        let Ok(ops) = ops else {
+            return self;
+        };
+        let compressed = RemoteFileDelta { ops, codec, ..self.clone() };
+        let compressed_len = compressed.data_len();
+        if compressed_len >= data_len {
+            return self;
+        }
+        info!("Compressed remotefile delta {:?} with {:?}: {} -> {} bytes", self.file_name.0, codec, data_len, compressed_len);
+        compressed
+    }
+
+    // Deltas never carry more than MAX_DELTA_DATA_SIZE new bytes, so that's all that's decoded
+    pub(crate) fn decompressed(mut self) -> std::io::Result<Self> {
+        let mut remaining = MAX_DELTA_DATA_SIZE;
+        for op in self.ops.iter_mut() {
+            if let DeltaOp::Data(data) = op {
+                *data = decompress(self.codec, data, remaining)?;
+                remaining -= data.len();
+            }
+        }
+        self.codec = RemoteFileCodec::None;
+        Ok(self)
+    }
+}
+
+// Adler-32 style checksum that can be rolled forward one byte at a time
+#[derive(Clone, Copy)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        Self { a: a & 0xffff, b: b & 0xffff, len }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    fn value(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> u64 {
+    let digest = Sha256::digest(block);
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+fn block_size_for(file_len: usize) -> usize {
+    // Roughly sqrt(len), like rsync, so the signature and the delta grow together
+    let size = ((file_len as f64).sqrt() as usize).next_multiple_of(64);
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+pub(crate) fn signature(data: &[u8]) -> FileSignature {
+    let block_size = block_size_for(data.len());
+    FileSignature {
+        block_size: block_size as u32,
+        file_len: data.len() as u64,
+        blocks: data.chunks(block_size)
+            .map(|block| BlockSignature {
+                weak: RollingChecksum::new(block).value(),
+                strong: strong_hash(block),
+            })
+            .collect(),
+    }
+}
+
+// Describe `data` in terms of the blocks of the file the signature was made from
+pub(crate) fn delta(signature: &FileSignature, data: &[u8]) -> Vec<DeltaOp> {
+    let block_size = signature.block_size as usize;
+    let mut ops = Vec::new();
+    let mut literal = Vec::new();
+    let push_copy = |ops: &mut Vec<DeltaOp>, literal: &mut Vec<u8>, block: u32| {
+        if !literal.is_empty() {
+            ops.push(DeltaOp::Data(std::mem::take(literal)));
+        }
+        match ops.last_mut() {
+            Some(DeltaOp::Copy { block: start, count }) if *start + *count == block => *count += 1,
+            _ => ops.push(DeltaOp::Copy { block, count: 1 }),
+        }
+    };
+
+    if block_size == 0 || signature.blocks.is_empty() {
+        return vec![DeltaOp::Data(data.to_vec())];
+    }
+
+    // Only full blocks can be found while rolling, the last block of the base file may be shorter
+    let mut full_blocks: HashMap<u32, Vec<u32>> = HashMap::default();
+    let last_block = signature.blocks.len() - 1;
+    let last_block_len = signature.file_len as usize - last_block * block_size;
+    for (index, block) in signature.blocks.iter().enumerate() {
+        if index != last_block || last_block_len == block_size {
+            full_blocks.entry(block.weak).or_default().push(index as u32);
+        }
+    }
+    let find_block = |window: &[u8], weak: u32| -> Option<u32> {
+        let candidates = full_blocks.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates.iter().copied().find(|index| signature.blocks[*index as usize].strong == strong)
+    };
+
+    let mut i = 0;
+    let mut checksum = None;
+    while i + block_size <= data.len() {
+        let window = &data[i..i + block_size];
+        let weak = checksum.get_or_insert_with(|| RollingChecksum::new(window)).value();
+        if let Some(block) = find_block(window, weak) {
+            push_copy(&mut ops, &mut literal, block);
+            i += block_size;
+            checksum = None;
+            continue;
+        }
+        literal.push(data[i]);
+        if let (Some(checksum), Some(next)) = (checksum.as_mut(), data.get(i + block_size)) {
+            checksum.roll(data[i], *next);
+        }
+        i += 1;
+    }
+
+    // The tail can still be the short last block of the base file
+    let tail = &data[i..];
+    let tail_is_last_block = !tail.is_empty()
+        && tail.len() == last_block_len
+        && signature.blocks[last_block].weak == RollingChecksum::new(tail).value()
+        && signature.blocks[last_block].strong == strong_hash(tail);
+    if tail_is_last_block {
+        push_copy(&mut ops, &mut literal, last_block as u32);
+    } else {
+        literal.extend_from_slice(tail);
+    }
+    if !literal.is_empty() {
+        ops.push(DeltaOp::Data(literal));
+    }
+    ops
+}
+
+// Rebuild the new file from the base file and a delta. Returns None if the delta refers to
+// blocks the base doesn't have, or if the file would grow past max_len.
+pub(crate) fn apply_delta(base: &[u8], block_size: u32, ops: &[DeltaOp], max_len: usize) -> Option<Vec<u8>> {
+    let block_size = block_size as usize;
+    let mut data = Vec::with_capacity(base.len().min(max_len));
+    for op in ops {
+        let bytes = match op {
+            DeltaOp::Copy { block, count } => {
+                let start = (*block as usize).checked_mul(block_size)?;
+                let end = start.checked_add((*count as usize).checked_mul(block_size)?)?.min(base.len());
+                base.get(start..end)?
+            }
+            DeltaOp::Data(bytes) => bytes.as_slice(),
+        };
+        if data.len() + bytes.len() > max_len {
+            return None;
+        }
+        data.extend_from_slice(bytes);
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pseudo-random bytes, so blocks don't repeat by accident
+    fn file(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(base: &[u8], new: &[u8]) -> Vec<DeltaOp> {
+        let signature = signature(base);
+        let ops = delta(&signature, new);
+        assert_eq!(apply_delta(base, signature.block_size, &ops, usize::MAX).as_deref(), Some(new));
+        ops
+    }
+
+    fn data_len(ops: &[DeltaOp]) -> usize {
+        ops.iter().map(|op| match op {
+            DeltaOp::Data(data) => data.len(),
+            DeltaOp::Copy { .. } => 0,
+        }).sum()
+    }
+
+    #[test]
+    fn rolling_checksum_matches_fresh_checksum() {
+        let data = file(4096, 1);
+        let window = 700;
+        let mut checksum = RollingChecksum::new(&data[..window]);
+        for i in 1..data.len() - window {
+            checksum.roll(data[i - 1], data[i + window - 1]);
+            assert_eq!(checksum.value(), RollingChecksum::new(&data[i..i + window]).value(), "at offset {}", i);
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_all_copies() {
+        let base = file(100_000, 2);
+        let ops = round_trip(&base, &base);
+        assert_eq!(data_len(&ops), 0);
+        assert_eq!(ops.len(), 1);
+    }
+
+    #[test]
+    fn edits_only_send_changed_bytes() {
+        let base = file(100_000, 3);
+        let block_size = signature(&base).block_size as usize;
+
+        let mut inserted = base.clone();
+        inserted.splice(50_000..50_000, b"inserted".iter().copied());
+        assert!(data_len(&round_trip(&base, &inserted)) < 2 * block_size);
+
+        let mut removed = base.clone();
+        removed.drain(20_000..20_100);
+        assert!(data_len(&round_trip(&base, &removed)) < 2 * block_size);
+
+        let mut appended = base.clone();
+        appended.extend_from_slice(b"appended");
+        assert!(data_len(&round_trip(&base, &appended)) < 2 * block_size);
+
+        let mut changed = base.clone();
+        changed[0] ^= 0xff;
+        changed[99_999] ^= 0xff;
+        assert!(data_len(&round_trip(&base, &changed)) <= 2 * block_size);
+    }
+
+    #[test]
+    fn short_last_block_is_reused() {
+        let base = file(MIN_BLOCK_SIZE * 3 + 10, 4);
+        let mut new = file(MIN_BLOCK_SIZE, 5);
+        new.extend_from_slice(&base);
+        let ops = round_trip(&base, &new);
+        assert_eq!(data_len(&ops), MIN_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn empty_files() {
+        let base = file(10_000, 6);
+        round_trip(&[], &base);
+        round_trip(&base, &[]);
+        round_trip(&[], &[]);
+    }
+
+    #[test]
+    fn refuses_copies_past_the_base() {
+        let base = file(MIN_BLOCK_SIZE * 2, 7);
+        let ops = [DeltaOp::Copy { block: 3, count: 1 }];
+        assert_eq!(apply_delta(&base, MIN_BLOCK_SIZE as u32, &ops, usize::MAX), None);
+    }
+
+    #[test]
+    fn refuses_deltas_that_grow_past_the_limit() {
+        let base = file(MIN_BLOCK_SIZE * 2, 9);
+        let ops = vec![DeltaOp::Copy { block: 0, count: 2 }; 100];
+        assert_eq!(apply_delta(&base, MIN_BLOCK_SIZE as u32, &ops, MIN_BLOCK_SIZE * 100).map(|data| data.len()), None);
+        assert_eq!(apply_delta(&base, MIN_BLOCK_SIZE as u32, &ops, MIN_BLOCK_SIZE * 200).map(|data| data.len()), Some(MIN_BLOCK_SIZE * 200));
+
+        let ops = [DeltaOp::Copy { block: u32::MAX, count: u32::MAX }];
+        assert_eq!(apply_delta(&base, u32::MAX, &ops, usize::MAX), None);
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let base = file(100_000, 8);
+        let mut new = base.clone();
+        new.splice(1000..1000, [b'a'; 10_000]);
+        let signature = signature(&base);
+        let delta = RemoteFileDelta {
+            transfer_id: 1,
+            file_name: RemoteFileName("map.tmx".to_string()),
+            base_hash: String::new(),
+            hash: String::new(),
+            server_signature: Vec::new(),
+            block_size: signature.block_size,
+            codec: RemoteFileCodec::None,
+            ops: delta(&signature, &new),
+        };
+        let compressed = delta.clone().compressed(RemoteFileCodec::Zstd);
+        assert_eq!(compressed.codec, RemoteFileCodec::Zstd);
+        assert!(compressed.data_len() < delta.data_len());
+        assert_eq!(compressed.decompressed().unwrap(), delta);
+    }
+
+    #[test]
+    fn refuses_more_new_bytes_than_a_delta_carries() {
+        // Each op fits on its own, together they're past the limit
+        let half = compress_with(RemoteFileCodec::Zstd, &vec![0; MAX_DELTA_DATA_SIZE / 2 + 1]).unwrap();
+        let delta = RemoteFileDelta {
+            transfer_id: 1,
+            file_name: RemoteFileName("map.tmx".to_string()),
+            base_hash: String::new(),
+            hash: String::new(),
+            server_signature: Vec::new(),
+            block_size: MIN_BLOCK_SIZE as u32,
+            codec: RemoteFileCodec::Zstd,
+            ops: vec![DeltaOp::Data(half.clone()), DeltaOp::Data(half)],
+        };
+        assert!(delta.decompressed().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use bevy::utils::HashSet;
+
+use super::{RemoteFileName, RemoteFileStore, SandboxedAssetRoot};
+
+// The only kinds of files a map, tileset or script can load, so a reference can't make the server
+// send anything else from the asset root
+const DEPENDENCY_EXTENSIONS: [&str; 7] = ["tsx", "png", "jpg", "jpeg", "gif", "bmp", "lua"];
+
+// The files a remotefile refers to directly, relative to the asset root
+fn file_dependencies(asset_root: &impl RemoteFileStore, file_name: &RemoteFileName) -> Vec<RemoteFileName> {
+    let Ok(file_data) = asset_root.read(file_name) else {
+        return Vec::new();
+    };
+    let mut dependencies = Vec::new();
+    for dependency in direct_dependencies(file_name, &String::from_utf8_lossy(&file_data)) {
+        if dependency != *file_name && !dependencies.contains(&dependency) {
+            dependencies.push(dependency);
+        }
+    }
+    dependencies
+}
+
+// The files and everything they refer to, directly or through other dependencies, leaving out the
+// dependencies that can't be read
+pub(crate) fn with_dependencies(
+    asset_root: &impl RemoteFileStore,
+    files: impl IntoIterator<Item = RemoteFileName>,
+    can_read: impl Fn(&RemoteFileName) -> bool,
+) -> HashSet<RemoteFileName> {
+    let mut found = HashSet::new();
+    let mut pending: Vec<RemoteFileName> = files.into_iter().collect();
+    while let Some(file_name) = pending.pop() {
+        if found.insert(file_name.clone()) {
+            pending.extend(file_dependencies(asset_root, &file_name).into_iter().filter(|dependency| can_read(dependency)));
+        }
+    }
+    found
+}
+
+// The files a single file refers to, relative to the asset root
+fn direct_dependencies(file_name: &RemoteFileName, contents: &str) -> Vec<RemoteFileName> {
+    let sources = match file_name.0.rsplit('.').next() {
+        // Maps refer to external tilesets and to images, tilesets only to images
+        Some("tmx") => [xml_sources(contents, "tileset"), xml_sources(contents, "image")].concat(),
+        Some("tsx") => xml_sources(contents, "image"),
+        Some("lua") => lua_requires(contents),
+        _ => Vec::new(),
+    };
+    sources.iter()
+        .filter_map(|source| relative_to(file_name, source))
+        .filter(|dependency| {
+            let extension = dependency.0.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+            extension.is_some_and(|extension| DEPENDENCY_EXTENSIONS.contains(&extension.as_str()))
+        })
+        .collect()
+}
+
+// The `source` attribute of every `<tag ...>` element
+fn xml_sources(contents: &str, tag: &str) -> Vec<String> {
+    let open_tag = format!("<{}", tag);
+    contents.match_indices(&open_tag)
+        .filter_map(|(start, _)| {
+            let element = &contents[start + open_tag.len()..];
+            // Skip longer tag names that start the same, e.g. <imagelayer> for <image>
+            if !element.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let element = &element[..element.find('>')?];
+            let value = element.split_once(" source=\"")?.1;
+            let value = &value[..value.find('"')?];
+            Some(value.replace("&quot;", "\"").replace("&apos;", "'").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+        })
+        .collect()
+}
+
+// The modules loaded with `require "name"` or `require("name")`, as paths. `a.b` is `a/b.lua`.
+fn lua_requires(contents: &str) -> Vec<String> {
+    contents.match_indices("require")
+        .filter_map(|(start, keyword)| {
+            // Skip identifiers that only contain the keyword, e.g. `my_require`
+            let before = contents[..start].chars().next_back();
+            if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                return None;
+            }
+            let rest = contents[start + keyword.len()..].trim_start();
+            let rest = rest.strip_prefix('(').unwrap_or(rest).trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let module = &rest[1..];
+            let module = &module[..module.find(quote)?];
+            Some(format!("{}.lua", module.replace('.', "/")))
+        })
+        .collect()
+}
+
+// Resolve a path found inside a file against that file's directory, None if it leaves the asset root
+pub(crate) fn relative_to(file_name: &RemoteFileName, source: &str) -> Option<RemoteFileName> {
+    let mut parts: Vec<&str> = file_name.0.split('/').collect();
+    parts.pop();
+    for part in source.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    SandboxedAssetRoot::sanitize(&RemoteFileName(parts.join("/"))).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_file::MemoryRemoteFileStore;
+
+    fn dependencies(file_name: &str, contents: &str) -> Vec<String> {
+        direct_dependencies(&RemoteFileName(file_name.to_string()), contents).into_iter()
+            .map(|dependency| dependency.0)
+            .collect()
+    }
+
+    #[test]
+    fn maps_refer_to_tilesets_and_images() {
+        let map = r#"<map><tileset firstgid="1" source="../tiled/atlas.tsx"/><imagelayer><image source="sky.png"/></imagelayer></map>"#;
+        assert_eq!(dependencies("maps/map.tmx", map), ["tiled/atlas.tsx", "maps/sky.png"]);
+    }
+
+    #[test]
+    fn scripts_refer_to_required_modules() {
+        let script = "local a = require \"lib.a\"\nlocal b = require('b')\nmy_require(\"c\")";
+        assert_eq!(dependencies("scripts/main.lua", script), ["scripts/lib/a.lua", "scripts/b.lua"]);
+    }
+
+    #[test]
+    fn only_loadable_files_are_dependencies() {
+        let map = r#"<map><image source="../settings.ron"/><image source="notes"/><image source="A.PNG"/></map>"#;
+        assert_eq!(dependencies("maps/map.tmx", map), ["maps/A.PNG"]);
+    }
+
+    #[test]
+    fn references_outside_the_root_are_ignored() {
+        let tileset = r#"<tileset><image source="../../../etc/passwd.png"/></tileset>"#;
+        assert!(dependencies("tiled/atlas.tsx", tileset).is_empty());
+    }
+
+    #[test]
+    fn dependencies_are_followed_until_private() {
+        let asset_root = MemoryRemoteFileStore::default();
+        asset_root.write(&RemoteFileName("maps/map.tmx".to_string()), br#"<map><tileset source="../tiled/atlas.tsx"/></map>"#).unwrap();
+        asset_root.write(&RemoteFileName("tiled/atlas.tsx".to_string()), br#"<tileset><image source="atlas.png"/><image source="secret.png"/></tileset>"#).unwrap();
+        let files = with_dependencies(&asset_root, [RemoteFileName("maps/map.tmx".to_string())], |file_name| file_name.0 != "tiled/secret.png");
+        let mut files: Vec<String> = files.into_iter().map(|file_name| file_name.0).collect();
+        files.sort();
+        assert_eq!(files, ["maps/map.tmx", "tiled/atlas.png", "tiled/atlas.tsx"]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{asset::ron, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{hash_bytes, RemoteFileName};
+
+// Server-side directory with every accepted version of every remotefile, kept out of assets/
+pub(crate) const HISTORY_PATH: &str = "remote_file_history";
+
+// Shortest hash prefix accepted when picking a revision by hand
+const MIN_HASH_PREFIX: usize = 6;
+
+// One accepted version of a file. The contents are stored by hash, so a revision that restores
+// an older version doesn't store the data twice.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileRevision {
+    pub file_name: RemoteFileName,
+    pub hash: String,
+    // The client that uploaded it, None for a rollback on the server or for the version the
+    // server had before a client changed the file
+    pub client_id: Option<u64>,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    // The rooms the file was in
+    pub rooms: Vec<u64>,
+}
+
+// The revision log, one RON revision per line in `revisions.ron`, and the contents in `objects/`
+#[derive(Resource)]
+pub struct RemoteFileHistory {
+    path: PathBuf,
+    revisions: Vec<RemoteFileRevision>,
+}
+
+impl RemoteFileHistory {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let revisions = match std::fs::read_to_string(path.join("revisions.ron")) {
+            Ok(log) => log.lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| match ron::de::from_str::<RemoteFileRevision>(line) {
+                    Ok(revision) => Some(revision),
+                    Err(e) => {
+                        error!("Skipping unreadable remotefile revision {:?}: {:?}", line, e);
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        info!("Loaded {} remotefile revisions from {:?}", revisions.len(), path);
+        Self { path, revisions }
+    }
+
+    // Store a version of a file and append it to the log
+    pub(crate) fn record(
+        &mut self,
+        file_name: &RemoteFileName,
+        file_data: &[u8],
+        client_id: Option<u64>,
+        rooms: Vec<u64>,
+    ) -> std::io::Result<RemoteFileRevision> {
+        let revision = RemoteFileRevision {
+            file_name: file_name.clone(),
+            hash: hash_bytes(file_data),
+            client_id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default(),
+            rooms,
+        };
+        let object = self.object_path(&revision.hash);
+        if !object.exists() {
+            std::fs::create_dir_all(self.path.join("objects"))?;
+            std::fs::write(object, file_data)?;
+        }
+        let line = ron::ser::to_string(&revision).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut log = OpenOptions::new().create(true).append(true).open(self.path.join("revisions.ron"))?;
+        writeln!(log, "{}", line)?;
+        self.revisions.push(revision.clone());
+        Ok(revision)
+    }
+
+    // Whether the latest revision of a file has this hash
+    pub(crate) fn is_latest(&self, file_name: &RemoteFileName, hash: &str) -> bool {
+        self.revisions(file_name).last().is_some_and(|revision| revision.hash == hash)
+    }
+
+    // Revisions of a file, oldest first
+    pub fn revisions(&self, file_name: &RemoteFileName) -> impl Iterator<Item = &RemoteFileRevision> {
+        let file_name = file_name.clone();
+        self.revisions.iter().filter(move |revision| revision.file_name == file_name)
+    }
+
+    // The revision of a file whose hash starts with the prefix, if exactly one does
+    pub fn find(&self, file_name: &RemoteFileName, hash_prefix: &str) -> Result<&RemoteFileRevision, String> {
+        if hash_prefix.len() < MIN_HASH_PREFIX {
+            return Err(format!("revision hash must be at least {} characters", MIN_HASH_PREFIX));
+        }
+        let mut matches = self.revisions(file_name).filter(|revision| revision.hash.starts_with(hash_prefix));
+        let Some(revision) = matches.next() else {
+            return Err(format!("no revision {} of {}", hash_prefix, file_name.0));
+        };
+        if matches.any(|other| other.hash != revision.hash) {
+            return Err(format!("revision {} of {} is ambiguous", hash_prefix, file_name.0));
+        }
+        Ok(revision)
+    }
+
+    pub(crate) fn read(&self, revision: &RemoteFileRevision) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.object_path(&revision.hash))
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.path.join("objects").join(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn revisions_survive_a_restart() {
+        let dir = TestDir::new();
+        let mut history = RemoteFileHistory::new(dir.path());
+        let file_name = RemoteFileName("map.tmx".to_string());
+        history.record(&file_name, b"first", None, vec![1]).unwrap();
+        let second = history.record(&file_name, b"second", Some(2), vec![1]).unwrap();
+        let history = RemoteFileHistory::new(history.path.clone());
+        assert_eq!(history.revisions(&file_name).count(), 2);
+        assert_eq!(history.read(history.find(&file_name, &second.hash[..6]).unwrap()).unwrap(), b"second");
+    }
+
+    #[test]
+    fn only_the_last_revision_is_latest() {
+        let dir = TestDir::new();
+        let mut history = RemoteFileHistory::new(dir.path());
+        let file_name = RemoteFileName("map.tmx".to_string());
+        assert!(!history.is_latest(&file_name, &hash_bytes(b"first")));
+        history.record(&file_name, b"first", None, Vec::new()).unwrap();
+        assert!(history.is_latest(&file_name, &hash_bytes(b"first")));
+        history.record(&file_name, b"second", Some(2), Vec::new()).unwrap();
+        assert!(!history.is_latest(&file_name, &hash_bytes(b"first")));
+        assert!(!history.is_latest(&RemoteFileName("other.tmx".to_string()), &hash_bytes(b"second")));
+    }
+
+    #[test]
+    fn short_or_unknown_prefixes_are_refused() {
+        let dir = TestDir::new();
+        let mut history = RemoteFileHistory::new(dir.path());
+        let file_name = RemoteFileName("map.tmx".to_string());
+        let revision = history.record(&file_name, b"first", None, Vec::new()).unwrap();
+        assert!(history.find(&file_name, &revision.hash[..5]).is_err());
+        assert!(history.find(&file_name, "").is_err());
+        assert!(history.find(&RemoteFileName("other.tmx".to_string()), &revision.hash).is_err());
+    }
+}
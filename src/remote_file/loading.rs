@@ -0,0 +1,130 @@
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use lightyear::prelude::server::RoomId;
+
+use super::{RemoteFileName, RemoteFileProgress};
+
+// Which rooms have every file of their manifest up to date on the client. Levels and scripts
+// wait for their room to be ready before loading, so they don't load a file that is about to
+// be replaced.
+#[derive(Resource, Default)]
+pub struct RemoteFileLoading {
+    // Files each room is still waiting for
+    pending: HashMap<u64, HashSet<RemoteFileName>>,
+    // Rooms whose manifest arrived and whose files are all downloaded
+    ready: HashSet<u64>,
+    // Bytes received and total of the downloads in progress
+    progress: HashMap<RemoteFileName, (u64, u64)>,
+}
+
+impl RemoteFileLoading {
+    pub fn is_room_ready(&self, room_id: RoomId) -> bool {
+        self.ready.contains(&room_id.0)
+    }
+
+    // A manifest arrived, the room is ready once the stale files are downloaded
+    pub(crate) fn expect(&mut self, room: u64, stale: impl IntoIterator<Item = RemoteFileName>) {
+        let stale: HashSet<RemoteFileName> = stale.into_iter().collect();
+        if stale.is_empty() {
+            self.pending.remove(&room);
+            self.ready.insert(room);
+        } else {
+            self.ready.remove(&room);
+            self.pending.insert(room, stale);
+        }
+    }
+
+    // The room is out of view, it's loaded again once its next manifest is up to date
+    pub(crate) fn left(&mut self, room: u64) {
+        self.pending.remove(&room);
+        self.ready.remove(&room);
+        let RemoteFileLoading { pending, progress, .. } = self;
+        progress.retain(|file_name, _| pending.values().any(|files| files.contains(file_name)));
+    }
+
+    // The rooms in view, whether they're loaded yet or not
+    pub(crate) fn rooms(&self) -> Vec<u64> {
+        self.pending.keys().chain(self.ready.iter()).copied().collect()
+    }
+
+    // Every room gets a new manifest after a reconnect
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+        self.ready.clear();
+        self.progress.clear();
+    }
+
+    // A file was downloaded and written, or the server told us there's nothing to download
+    pub(crate) fn downloaded(&mut self, file_name: &RemoteFileName) {
+        self.progress.remove(file_name);
+        for (room, files) in self.pending.iter_mut() {
+            if files.remove(file_name) && files.is_empty() {
+                info!("All files of room {:?} are downloaded", room);
+                self.ready.insert(*room);
+            }
+        }
+        self.pending.retain(|_, files| !files.is_empty());
+    }
+
+    fn is_pending(&self, file_name: &RemoteFileName) -> bool {
+        self.pending.values().any(|files| files.contains(file_name))
+    }
+}
+
+pub(crate) fn remotefile_loading_progress(
+    mut progress: EventReader<RemoteFileProgress>,
+    mut loading: ResMut<RemoteFileLoading>,
+) {
+    for event in progress.read() {
+        if loading.is_pending(&event.file_name) && event.received < event.total {
+            loading.progress.insert(event.file_name.clone(), (event.received, event.total));
+        }
+    }
+}
+
+// Marks the text showing how much of the current rooms is still downloading
+#[derive(Component)]
+pub(crate) struct RemoteFileLoadingText;
+
+pub(crate) fn remotefile_loading_overlay_spawn(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        RemoteFileLoadingText,
+    ));
+}
+
+pub(crate) fn remotefile_loading_overlay(
+    loading: Res<RemoteFileLoading>,
+    mut text_query: Query<&mut Text, With<RemoteFileLoadingText>>,
+) {
+    if !loading.is_changed() {
+        return;
+    }
+    let files: HashSet<&RemoteFileName> = loading.pending.values().flatten().collect();
+    let section = if files.is_empty() {
+        String::new()
+    } else {
+        let (received, total) = loading.progress.values()
+            .fold((0, 0), |(received, total), (file_received, file_total)| (received + file_received, total + file_total));
+        let mut section = format!("Downloading {} files", files.len());
+        if total > 0 {
+            section += &format!(": {} / {} KB ({:.0}%)", received / 1024, total / 1024, received as f32 / total as f32 * 100.0);
+        }
+        section
+    };
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value.clone_from(&section);
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::SystemTime;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use super::{hash_bytes, RemoteFileHash, RemoteFileName, RemoteFileStore};
+
+// Everything a room needs, sent to a client when they enter the room, or as a prefetch when
+// they're heading towards it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetManifest {
+    pub room: u64,
+    pub prefetch: bool,
+    pub entries: Vec<AssetManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetManifestEntry {
+    pub file_name: RemoteFileName,
+    pub size: u64,
+    pub hash: String,
+}
+
+// Sent to a client when a room is no longer in their view, its files may change without them
+// being told until they get its manifest again
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetManifestLeft {
+    pub room: u64,
+}
+
+// The client's answer to an AssetManifest, listing the files it doesn't have the latest version of
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetManifestDiff {
+    pub room: u64,
+    pub prefetch: bool,
+    pub stale: Vec<RemoteFileHash>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedHash {
+    pub size: u64,
+    pub hash: String,
+    modified: Option<SystemTime>,
+}
+
+// Hashes of the files in the store. A file is only hashed again once its size or
+// modification time changes.
+#[derive(Resource, Default)]
+pub struct RemoteFileHashCache {
+    files: HashMap<RemoteFileName, CachedHash>,
+}
+
+impl RemoteFileHashCache {
+    pub fn get(&mut self, asset_root: &impl RemoteFileStore, file_name: &RemoteFileName) -> Option<CachedHash> {
+        let Ok(metadata) = asset_root.metadata(file_name) else {
+            self.files.remove(file_name);
+            return None;
+        };
+        if let Some(hash) = metadata.hash {
+            return Some(CachedHash { size: metadata.len, hash, modified: None });
+        }
+        let modified = metadata.modified;
+        if let Some(cached) = self.files.get(file_name) {
+            if cached.size == metadata.len && cached.modified == modified && modified.is_some() {
+                return Some(cached.clone());
+            }
+        }
+        let file_data = asset_root.read(file_name).ok()?;
+        Some(self.insert(file_name.clone(), &file_data, modified))
+    }
+
+    // Record the contents of a file that was just written, so it doesn't need to be read back
+    pub fn update(&mut self, asset_root: &impl RemoteFileStore, file_name: &RemoteFileName, file_data: &[u8]) {
+        let modified = asset_root.metadata(file_name).ok().and_then(|metadata| metadata.modified);
+        self.insert(file_name.clone(), file_data, modified);
+    }
+
+    fn insert(&mut self, file_name: RemoteFileName, file_data: &[u8], modified: Option<SystemTime>) -> CachedHash {
+        let cached = CachedHash {
+            size: file_data.len() as u64,
+            hash: hash_bytes(file_data),
+            modified,
+        };
+        self.files.insert(file_name, cached.clone());
+        cached
+    }
+}
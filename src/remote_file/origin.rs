@@ -0,0 +1,111 @@
+// Files the server sends are signed with its Ed25519 key, over the file name and the hash of the
+// contents. Clients only write files whose signature checks out against the public key in their
+// settings, so nothing but the server can put a file, e.g. a Lua script, in their cache. The key
+// is only read by the server at startup, from the environment or a file that isn't in `assets/`,
+// so it's never part of what clients get.
+
+use std::path::Path;
+
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+use super::RemoteFileName;
+
+pub(crate) const SIGNING_KEY_PATH: &str = "remote_file_signing_key";
+pub(crate) const SIGNING_KEY_VAR: &str = "REMOTE_FILE_SIGNING_KEY";
+
+pub(crate) struct RemoteFileSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl RemoteFileSigner {
+    // Fails if the seed doesn't belong to the public key the clients have. The error tells the
+    // public key that does, to paste into the shared settings.
+    pub(crate) fn new(seed: &[u8; 32], public_key: &[u8; 32]) -> Result<Self, String> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed).map_err(|e| format!("invalid signing key: {}", e))?;
+        if key_pair.public_key().as_ref() != public_key {
+            let numbers: Vec<String> = key_pair.public_key().as_ref().iter().map(|byte| byte.to_string()).collect();
+            return Err(format!("the signing key doesn't match remote_file_public_key, its public key is ({})", numbers.join(", ")));
+        }
+        Ok(Self { key_pair })
+    }
+
+    pub(crate) fn sign(&self, file_name: &RemoteFileName, hash: &str) -> Vec<u8> {
+        self.key_pair.sign(&signed_message(file_name, hash)).as_ref().to_vec()
+    }
+}
+
+// The 32 byte seed as 64 hex digits, from REMOTE_FILE_SIGNING_KEY if it's set and from the file
+// otherwise
+pub(crate) fn load_signing_key(path: &Path) -> Result<[u8; 32], String> {
+    match std::env::var(SIGNING_KEY_VAR) {
+        Ok(seed) => parse_seed(&seed).map_err(|e| format!("{}: {}", SIGNING_KEY_VAR, e)),
+        Err(_) => {
+            let seed = std::fs::read_to_string(path)
+                .map_err(|e| format!("neither {} nor {:?} is readable: {}", SIGNING_KEY_VAR, path, e))?;
+            parse_seed(&seed).map_err(|e| format!("{:?}: {}", path, e))
+        }
+    }
+}
+
+fn parse_seed(seed: &str) -> Result<[u8; 32], String> {
+    let seed = seed.trim();
+    if seed.len() != 64 || !seed.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return Err("expected 64 hex digits".to_string());
+    }
+    let mut bytes = [0; 32];
+    for (byte, digits) in bytes.iter_mut().zip(seed.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn verify(public_key: &[u8; 32], file_name: &RemoteFileName, hash: &str, signature: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&signed_message(file_name, hash), signature)
+        .is_ok()
+}
+
+// The file name is part of the message, so a signed file can't be replayed under another name
+fn signed_message(file_name: &RemoteFileName, hash: &str) -> Vec<u8> {
+    [b"remotefile\0", file_name.0.as_bytes(), b"\0", hash.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed() -> [u8; 32] {
+        [7; 32]
+    }
+
+    fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+        Ed25519KeyPair::from_seed_unchecked(seed).unwrap().public_key().as_ref().try_into().unwrap()
+    }
+
+    #[test]
+    fn seeds_are_hex() {
+        assert_eq!(parse_seed(&format!("{}\n", "07".repeat(32))), Ok(seed()));
+        assert_eq!(parse_seed(&"aB".repeat(32)), Ok([0xab; 32]));
+        assert!(parse_seed("0707").is_err());
+        assert!(parse_seed(&"zz".repeat(32)).is_err());
+        assert!(parse_seed(&"+7".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn signatures_only_verify_for_their_file() {
+        let seed = seed();
+        let public_key = public_key(&seed);
+        let signer = RemoteFileSigner::new(&seed, &public_key).unwrap();
+        let file_name = RemoteFileName("scripts/door.lua".to_string());
+        let signature = signer.sign(&file_name, "hash");
+        assert!(verify(&public_key, &file_name, "hash", &signature));
+        assert!(!verify(&public_key, &file_name, "other hash", &signature));
+        assert!(!verify(&public_key, &RemoteFileName("scripts/other.lua".to_string()), "hash", &signature));
+    }
+
+    #[test]
+    fn keys_of_other_servers_are_refused() {
+        let error = RemoteFileSigner::new(&seed(), &[0; 32]).err().unwrap();
+        assert!(error.contains(&format!("({}", public_key(&seed())[0])));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{path::PathBuf, time::SystemTime};
+
+use bevy::{asset::ron, prelude::*, utils::HashSet};
+use glob::{MatchOptions, Pattern};
+use lightyear::{connection::id::ClientId, prelude::server::RoomId};
+use serde::Deserialize;
+
+use super::RemoteFileName;
+
+// Server-side file with the upload rules, kept out of assets/ so it can't be uploaded itself
+pub(crate) const PERMISSIONS_PATH: &str = "remote_file_permissions.ron";
+
+// How often the rules file is checked for changes
+const RELOAD_INTERVAL_SECS: f32 = 2.0;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum ClientMatch {
+    Any,
+    Only(Vec<u64>),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+// One line of the rules file, e.g. `(clients: Only([3]), path: "map_*.tmx", access: Allow, in_room: true)`
+#[derive(Deserialize, Clone, Debug)]
+pub struct PermissionRule {
+    pub clients: ClientMatch,
+    // Glob relative to assets/, `*` stays within a directory and `**` crosses directories
+    pub path: String,
+    pub access: Access,
+    // Only match if the file belongs to a room the client is in. A file in no room, e.g. a new
+    // one, belongs to the rooms of the client writing it.
+    #[serde(default)]
+    pub in_room: bool,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct PermissionRules {
+    pub rules: Vec<PermissionRule>,
+    // Clients that may not write anything, whatever the rules say
+    #[serde(default)]
+    pub read_only: Vec<u64>,
+    // Globs of files no client may read, they're never sent even if another file refers to them
+    #[serde(default)]
+    pub private: Vec<String>,
+}
+
+// The upload rules, checked top to bottom. The first matching rule decides, and uploads that
+// match no rule are denied.
+#[derive(Resource)]
+pub struct RemoteFilePermissions {
+    path: PathBuf,
+    loaded: bool,
+    modified: Option<SystemTime>,
+    rules: Vec<(PermissionRule, Pattern)>,
+    read_only: HashSet<u64>,
+    private: Vec<Pattern>,
+    // Clients that asked to be read-only themselves, for as long as they're connected
+    requested_read_only: HashSet<ClientId>,
+    reload_timer: Timer,
+}
+
+impl RemoteFilePermissions {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        let mut permissions = Self {
+            path: path.into(),
+            loaded: false,
+            modified: None,
+            rules: Vec::new(),
+            read_only: HashSet::new(),
+            private: Vec::new(),
+            requested_read_only: HashSet::new(),
+            reload_timer: Timer::from_seconds(RELOAD_INTERVAL_SECS, TimerMode::Repeating),
+        };
+        permissions.reload();
+        permissions
+    }
+
+    // Re-read the rules file if it changed on disk. On a parse error the previous rules are kept.
+    fn reload(&mut self) {
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if self.loaded && modified == self.modified {
+            return;
+        }
+        self.loaded = true;
+        self.modified = modified;
+
+        let rules = match std::fs::read_to_string(&self.path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!("Failed to read {:?}, all uploads will be denied: {:?}", self.path, e);
+                self.rules.clear();
+                self.read_only.clear();
+                self.private.clear();
+                return;
+            }
+        };
+        match ron::de::from_str::<PermissionRules>(&rules) {
+            Ok(rules) => {
+                self.rules = rules.rules.into_iter()
+                    .filter_map(|rule| match Pattern::new(&rule.path) {
+                        Ok(pattern) => Some((rule, pattern)),
+                        Err(e) => {
+                            error!("Invalid path pattern {:?} in {:?}: {:?}", rule.path, self.path, e);
+                            None
+                        }
+                    })
+                    .collect();
+                self.read_only = rules.read_only.into_iter().collect();
+                self.private = rules.private.iter()
+                    .filter_map(|path| match Pattern::new(path) {
+                        Ok(pattern) => Some(pattern),
+                        Err(e) => {
+                            error!("Invalid private pattern {:?} in {:?}: {:?}", path, self.path, e);
+                            None
+                        }
+                    })
+                    .collect();
+                info!("Loaded {} remotefile permission rules from {:?}", self.rules.len(), self.path);
+            }
+            Err(e) => {
+                error!("Failed to parse {:?}, keeping the previous rules: {:?}", self.path, e);
+            }
+        }
+    }
+
+    pub(crate) fn set_requested_read_only(&mut self, client_id: ClientId, read_only: bool) {
+        if read_only {
+            self.requested_read_only.insert(client_id);
+        } else {
+            self.requested_read_only.remove(&client_id);
+        }
+    }
+
+    // Read-only clients only ever receive files
+    pub(crate) fn is_read_only(&self, client_id: ClientId) -> bool {
+        self.read_only.contains(&client_id.to_bits()) || self.requested_read_only.contains(&client_id)
+    }
+
+    // Whether any client may be sent the file
+    pub(crate) fn can_read(&self, file_name: &RemoteFileName) -> bool {
+        !self.private.iter().any(|pattern| pattern.matches_with(&file_name.0, MATCH_OPTIONS))
+    }
+
+    // Whether the client may write the file. `client_rooms` are the rooms the client is in, and
+    // `file_rooms` the rooms the file is replicated to, empty for a file that isn't in a room yet.
+    pub(crate) fn can_write(
+        &self,
+        client_id: ClientId,
+        file_name: &RemoteFileName,
+        client_rooms: &[RoomId],
+        file_rooms: &[RoomId],
+    ) -> bool {
+        if self.is_read_only(client_id) {
+            return false;
+        }
+        let in_room = if file_rooms.is_empty() {
+            !client_rooms.is_empty()
+        } else {
+            file_rooms.iter().any(|room_id| client_rooms.contains(room_id))
+        };
+        self.rules.iter()
+            .find(|(rule, pattern)| {
+                let client_matches = match &rule.clients {
+                    ClientMatch::Any => true,
+                    ClientMatch::Only(client_ids) => client_ids.contains(&client_id.to_bits()),
+                };
+                client_matches && (!rule.in_room || in_room) && pattern.matches_with(&file_name.0, MATCH_OPTIONS)
+            })
+            .is_some_and(|(rule, _)| rule.access == Access::Allow)
+    }
+}
+
+pub(crate) fn remotefile_permissions_reload(
+    time: Res<Time>,
+    mut permissions: ResMut<RemoteFilePermissions>,
+) {
+    if permissions.reload_timer.tick(time.delta()).just_finished() {
+        permissions.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn permissions(rules: &str) -> RemoteFilePermissions {
+        let dir = TestDir::new();
+        let path = dir.join(PERMISSIONS_PATH);
+        std::fs::write(&path, rules).unwrap();
+        RemoteFilePermissions::new(path)
+    }
+
+    fn can_write(permissions: &RemoteFilePermissions, client: u64, file_name: &str) -> bool {
+        let room = [RoomId(1)];
+        permissions.can_write(ClientId::Netcode(client), &RemoteFileName(file_name.to_string()), &room, &room)
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let permissions = permissions(r#"PermissionRules(rules: [
+            (clients: Any, path: "settings.ron", access: Deny),
+            (clients: Any, path: "**", access: Allow),
+        ])"#);
+        assert!(!can_write(&permissions, 1, "settings.ron"));
+        assert!(can_write(&permissions, 1, "map.tmx"));
+    }
+
+    #[test]
+    fn unmatched_files_are_denied() {
+        let permissions = permissions(r#"PermissionRules(rules: [
+            (clients: Any, path: "*.tmx", access: Allow),
+        ])"#);
+        assert!(can_write(&permissions, 1, "map.tmx"));
+        assert!(!can_write(&permissions, 1, "script.lua"));
+        // `*` stays within a directory
+        assert!(!can_write(&permissions, 1, "maps/map.tmx"));
+    }
+
+    #[test]
+    fn missing_rules_deny_everything() {
+        let permissions = RemoteFilePermissions::new(TestDir::new().join(PERMISSIONS_PATH));
+        assert!(!can_write(&permissions, 1, "map.tmx"));
+    }
+
+    #[test]
+    fn rules_can_name_clients() {
+        let permissions = permissions(r#"PermissionRules(rules: [
+            (clients: Only([3]), path: "**", access: Allow),
+        ])"#);
+        assert!(can_write(&permissions, 3, "maps/map.tmx"));
+        assert!(!can_write(&permissions, 4, "maps/map.tmx"));
+    }
+
+    #[test]
+    fn in_room_rules_need_a_shared_room() {
+        let permissions = permissions(r#"PermissionRules(rules: [
+            (clients: Any, path: "**", access: Allow, in_room: true),
+        ])"#);
+        let file_name = RemoteFileName("map.tmx".to_string());
+        assert!(permissions.can_write(ClientId::Netcode(1), &file_name, &[RoomId(1), RoomId(2)], &[RoomId(2)]));
+        assert!(!permissions.can_write(ClientId::Netcode(1), &file_name, &[RoomId(1)], &[RoomId(2)]));
+        // A file in no room yet belongs to the rooms of the client
+        assert!(permissions.can_write(ClientId::Netcode(1), &file_name, &[RoomId(1)], &[]));
+        assert!(!permissions.can_write(ClientId::Netcode(1), &file_name, &[], &[]));
+    }
+
+    #[test]
+    fn default_rules_allow_new_files() {
+        let permissions = RemoteFilePermissions::new(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(PERMISSIONS_PATH));
+        let file_name = RemoteFileName("maps/new_map.tmx".to_string());
+        assert!(permissions.can_write(ClientId::Netcode(1), &file_name, &[RoomId(1)], &[]));
+        assert!(!permissions.can_write(ClientId::Netcode(1), &RemoteFileName("settings.ron".to_string()), &[RoomId(1)], &[]));
+    }
+
+    #[test]
+    fn read_only_clients_never_write() {
+        let mut permissions = permissions(r#"PermissionRules(rules: [
+            (clients: Any, path: "**", access: Allow),
+        ], read_only: [2])"#);
+        assert!(!can_write(&permissions, 2, "map.tmx"));
+        assert!(can_write(&permissions, 3, "map.tmx"));
+        permissions.set_requested_read_only(ClientId::Netcode(3), true);
+        assert!(!can_write(&permissions, 3, "map.tmx"));
+        permissions.set_requested_read_only(ClientId::Netcode(3), false);
+        assert!(can_write(&permissions, 3, "map.tmx"));
+    }
+
+    #[test]
+    fn private_files_are_never_read() {
+        let permissions = permissions(r#"PermissionRules(rules: [], private: ["secrets/**", "*.ron"])"#);
+        assert!(!permissions.can_read(&RemoteFileName("secrets/keys/a.png".to_string())));
+        assert!(!permissions.can_read(&RemoteFileName("settings.ron".to_string())));
+        assert!(permissions.can_read(&RemoteFileName("maps/settings.ron.png".to_string())));
+        assert!(permissions.can_read(&RemoteFileName("map.tmx".to_string())));
+    }
+}
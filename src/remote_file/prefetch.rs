@@ -0,0 +1,119 @@
+use bevy::{prelude::*, utils::HashMap};
+use interest_management::{server::{get_grid_position, get_grid_position_from_room_id, get_room_id_from_grid_position, Global}, shared::{PlayerId, Position}};
+use lightyear::{connection::id::ClientId, prelude::server::RoomId, shared::replication::network_target::NetworkTarget};
+
+use super::{dependencies::with_dependencies, AssetManifest, AssetManifestEntry, RemoteFileHashCache, RemoteFileName, RemoteFilePermissions, RemoteFileStore};
+use crate::{level::WorldManifest, player::Channel1};
+
+// Only the room a player is in is relevant to them, so the rooms they're walking towards are
+// sent ahead of time as prefetch manifests. Their levels aren't spawned yet, so the manifests
+// list what the world manifest puts in the room.
+#[derive(Resource, Default)]
+pub(crate) struct RemoteFilePrefetch {
+    last_positions: HashMap<ClientId, Vec2>,
+    // The files of the prefetch manifests each client was sent, by room. Rooms are forgotten once
+    // they're no longer next to the client, so they're sent again if the client comes back.
+    rooms: HashMap<ClientId, HashMap<RoomId, Vec<RemoteFileName>>>,
+}
+
+impl RemoteFilePrefetch {
+    pub(crate) fn forget(&mut self, client_id: ClientId) {
+        self.last_positions.remove(&client_id);
+        self.rooms.remove(&client_id);
+    }
+
+    // Whether a file was in a prefetch manifest the client was sent
+    pub(crate) fn was_sent(&self, client_id: ClientId, file_name: &RemoteFileName) -> bool {
+        self.rooms.get(&client_id).into_iter().flat_map(|rooms| rooms.values()).any(|files| files.contains(file_name))
+    }
+}
+
+// The level files of a room's cell, with everything they refer to
+fn prefetch_manifest(
+    room_id: RoomId,
+    world: &WorldManifest,
+    permissions: &RemoteFilePermissions,
+    hash_cache: &mut RemoteFileHashCache,
+    asset_root: &impl RemoteFileStore,
+) -> AssetManifest {
+    let cell = get_grid_position_from_room_id(room_id).as_ivec2();
+    let level_files = world.level(cell).into_iter().flat_map(|level| [Some(level.map), level.script]).flatten().map(RemoteFileName);
+    let files = with_dependencies(asset_root, level_files, |file_name| permissions.can_read(file_name));
+    AssetManifest {
+        room: room_id.0,
+        prefetch: true,
+        entries: files.into_iter()
+            .filter_map(|file_name| {
+                let cached = hash_cache.get(asset_root, &file_name)?;
+                Some(AssetManifestEntry { file_name, size: cached.size, hash: cached.hash })
+            })
+            .collect(),
+    }
+}
+
+// The rooms next to the grid cell in the direction of the movement, e.g. three rooms when
+// moving diagonally
+fn rooms_ahead(grid_position: Vec2, movement: Vec2) -> Vec<RoomId> {
+    // f32::signum is 1 for 0, which would count standing still on an axis as moving
+    let sign = |value: f32| if value > 0.0 { 1.0 } else if value < 0.0 { -1.0 } else { 0.0 };
+    let step = Vec2::new(sign(movement.x), sign(movement.y));
+    [Vec2::new(step.x, 0.0), Vec2::new(0.0, step.y), step]
+        .into_iter()
+        .filter(|offset| *offset != Vec2::ZERO)
+        .map(|offset| get_room_id_from_grid_position(grid_position + offset))
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn remotefile_prefetch<S: RemoteFileStore>(
+    mut prefetch: ResMut<RemoteFilePrefetch>,
+    mut connection: ResMut<lightyear::server::connection::ConnectionManager>,
+    mut hash_cache: ResMut<RemoteFileHashCache>,
+    permissions: Res<RemoteFilePermissions>,
+    asset_root: Res<S>,
+    world: Option<Res<WorldManifest>>,
+    player_query: Query<(&PlayerId, &Position)>,
+    global: Res<Global>,
+) {
+    let Some(world) = world else {
+        return;
+    };
+    for (player_id, position) in player_query.iter() {
+        let client_id = player_id.0;
+        let Some(last_position) = prefetch.last_positions.insert(client_id, position.0) else {
+            continue;
+        };
+        let movement = position.0 - last_position;
+        if movement == Vec2::ZERO {
+            continue;
+        }
+        let grid_position = get_grid_position(position.0);
+        let client_rooms = global.client_id_to_room_ids.get(&client_id);
+        let ahead: Vec<RoomId> = rooms_ahead(grid_position, movement).into_iter()
+            .filter(|room_id| !client_rooms.is_some_and(|client_rooms| client_rooms.contains(room_id)))
+            .collect();
+
+        // Only keep the rooms that are still next to the player
+        let prefetched = prefetch.rooms.entry(client_id).or_default();
+        prefetched.retain(|room_id, _| {
+            let offset = (get_grid_position_from_room_id(*room_id) - grid_position).abs();
+            offset.x.max(offset.y) <= 1.0
+        });
+        // Rooms with nothing to send yet are tried again on the next move
+        for room_id in ahead {
+            if prefetched.contains_key(&room_id) {
+                continue;
+            }
+            let mut manifest = prefetch_manifest(room_id, &world, &permissions, &mut hash_cache, &*asset_root);
+            if manifest.entries.is_empty() {
+                continue;
+            }
+            info!("Sending prefetch manifest of room {:?} ({} files) to client {:?}", room_id.0, manifest.entries.len(), client_id);
+            if let Err(e) = connection.send_message_to_target::<Channel1, _>(&mut manifest, NetworkTarget::Single(client_id)) {
+                error!("Failed to send message: {:?}", e);
+                continue;
+            }
+            prefetched.insert(room_id, manifest.entries.into_iter().map(|entry| entry.file_name).collect());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{RemoteFileMetadata, RemoteFileName, RemoteFileStore};
+
+// Windows device names, reserved with any extension (`nul.tmx` is still `nul`)
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+// Why a file name that came from the network was refused
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RemoteFilePathError {
+    Empty,
+    Absolute,
+    ParentDir,
+    ReservedName(String),
+    InvalidCharacter(char),
+    // The path leaves the asset root through a symlink
+    Escapes,
+}
+
+// The directory RemoteFiles are read from and written to on disk. Every file name that came from the
+// network is resolved through here, so it can't point outside of the root.
+#[derive(Resource, Clone, Debug)]
+pub struct SandboxedAssetRoot {
+    root: PathBuf,
+}
+
+impl SandboxedAssetRoot {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Normalise a file name to forward slashes without `.` components, rejecting anything that
+    // could leave the root or that some platform can't store
+    pub fn sanitize(file_name: &RemoteFileName) -> Result<RemoteFileName, RemoteFilePathError> {
+        let name = file_name.0.replace('\\', "/");
+        if name.starts_with('/') || Path::new(&name).is_absolute() {
+            return Err(RemoteFilePathError::Absolute);
+        }
+        let mut parts = Vec::new();
+        for component in Path::new(&name).components() {
+            match component {
+                Component::Normal(part) => {
+                    let part = part.to_str().ok_or(RemoteFilePathError::InvalidCharacter(char::REPLACEMENT_CHARACTER))?;
+                    if let Some(c) = part.chars().find(|c| c.is_control() || "<>:\"|?*".contains(*c)) {
+                        return Err(RemoteFilePathError::InvalidCharacter(c));
+                    }
+                    let stem = part.split('.').next().unwrap_or_default().trim_end().to_lowercase();
+                    if RESERVED_NAMES.contains(&stem.as_str()) || part.ends_with('.') || part.ends_with(' ') {
+                        return Err(RemoteFilePathError::ReservedName(part.to_string()));
+                    }
+                    parts.push(part);
+                }
+                Component::CurDir => {}
+                Component::ParentDir => return Err(RemoteFilePathError::ParentDir),
+                Component::RootDir | Component::Prefix(_) => return Err(RemoteFilePathError::Absolute),
+            }
+        }
+        if parts.is_empty() {
+            return Err(RemoteFilePathError::Empty);
+        }
+        Ok(RemoteFileName(parts.join("/")))
+    }
+
+    // The path of a file inside the root
+    pub fn resolve(&self, file_name: &RemoteFileName) -> Result<PathBuf, RemoteFilePathError> {
+        let path = self.root.join(Self::sanitize(file_name)?.0);
+
+        // The nearest part of the path that exists must still be inside the root once symlinks
+        // are followed. A dangling symlink fails to canonicalize and is refused as well.
+        let root = self.root.canonicalize().map_err(|_| RemoteFilePathError::Escapes)?;
+        let existing = path.ancestors()
+            .find(|ancestor| ancestor.symlink_metadata().is_ok())
+            .unwrap_or(&self.root);
+        match existing.canonicalize() {
+            Ok(existing) if existing.starts_with(&root) => Ok(path),
+            _ => Err(RemoteFilePathError::Escapes),
+        }
+    }
+}
+
+impl RemoteFileStore for SandboxedAssetRoot {
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    fn metadata(&self, file_name: &RemoteFileName) -> std::io::Result<RemoteFileMetadata> {
+        let metadata = std::fs::metadata(self.resolve(file_name).map_err(invalid_path)?)?;
+        Ok(RemoteFileMetadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: None,
+        })
+    }
+
+    fn read(&self, file_name: &RemoteFileName) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.resolve(file_name).map_err(invalid_path)?)
+    }
+
+    // Creates the file's directories as needed
+    fn write(&self, file_name: &RemoteFileName, data: &[u8]) -> std::io::Result<()> {
+        let path = self.resolve(file_name).map_err(invalid_path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, data)
+    }
+
+    fn remove(&self, file_name: &RemoteFileName) -> std::io::Result<()> {
+        std::fs::remove_file(self.resolve(file_name).map_err(invalid_path)?)
+    }
+
+    // Creates the directories of the new name as needed
+    fn rename(&self, from: &RemoteFileName, to: &RemoteFileName) -> std::io::Result<()> {
+        let from = self.resolve(from).map_err(invalid_path)?;
+        let to = self.resolve(to).map_err(invalid_path)?;
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(from, to)
+    }
+}
+
+pub(super) fn invalid_path(e: RemoteFilePathError) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Invalid remotefile path: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn sanitize(file_name: &str) -> Result<String, RemoteFilePathError> {
+        SandboxedAssetRoot::sanitize(&RemoteFileName(file_name.to_string())).map(|file_name| file_name.0)
+    }
+
+    #[test]
+    fn normalises_names() {
+        assert_eq!(sanitize("maps/map.tmx"), Ok("maps/map.tmx".to_string()));
+        assert_eq!(sanitize("maps\\map.tmx"), Ok("maps/map.tmx".to_string()));
+        assert_eq!(sanitize("./maps//./map.tmx"), Ok("maps/map.tmx".to_string()));
+    }
+
+    #[test]
+    fn refuses_names_outside_the_root() {
+        assert_eq!(sanitize(""), Err(RemoteFilePathError::Empty));
+        assert_eq!(sanitize("."), Err(RemoteFilePathError::Empty));
+        assert_eq!(sanitize("/etc/passwd"), Err(RemoteFilePathError::Absolute));
+        assert_eq!(sanitize("\\etc\\passwd"), Err(RemoteFilePathError::Absolute));
+        assert_eq!(sanitize("../settings.ron"), Err(RemoteFilePathError::ParentDir));
+        assert_eq!(sanitize("maps/../../settings.ron"), Err(RemoteFilePathError::ParentDir));
+    }
+
+    #[test]
+    fn refuses_names_some_platform_cant_store() {
+        assert_eq!(sanitize("c:map.tmx"), Err(RemoteFilePathError::InvalidCharacter(':')));
+        assert_eq!(sanitize("map?.tmx"), Err(RemoteFilePathError::InvalidCharacter('?')));
+        assert_eq!(sanitize("map\n.tmx"), Err(RemoteFilePathError::InvalidCharacter('\n')));
+        assert_eq!(sanitize("maps/NUL.tmx"), Err(RemoteFilePathError::ReservedName("NUL.tmx".to_string())));
+        assert_eq!(sanitize("com1"), Err(RemoteFilePathError::ReservedName("com1".to_string())));
+        assert_eq!(sanitize("map.tmx."), Err(RemoteFilePathError::ReservedName("map.tmx.".to_string())));
+        assert_eq!(sanitize("console.lua"), Ok("console.lua".to_string()));
+    }
+
+    #[test]
+    fn stores_files_inside_the_root() {
+        let dir = TestDir::new();
+        let root = SandboxedAssetRoot::new(dir.path());
+        let file_name = RemoteFileName("maps/map.tmx".to_string());
+        root.write(&file_name, b"map").unwrap();
+        assert_eq!(root.read(&file_name).unwrap(), b"map");
+        assert_eq!(root.metadata(&file_name).unwrap().len, 3);
+        assert!(root.root().join("maps/map.tmx").is_file());
+
+        let renamed = RemoteFileName("levels/map.tmx".to_string());
+        root.rename(&file_name, &renamed).unwrap();
+        assert!(root.read(&file_name).is_err());
+        root.remove(&renamed).unwrap();
+        assert_eq!(root.read(&renamed).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(root.read(&RemoteFileName("../map.tmx".to_string())).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        let (dir, outside_dir) = (TestDir::new(), TestDir::new());
+        let root = SandboxedAssetRoot::new(dir.path());
+        let outside = SandboxedAssetRoot::new(outside_dir.path());
+        std::os::unix::fs::symlink(outside.root(), root.root().join("link")).unwrap();
+        std::os::unix::fs::symlink(root.root().join("missing"), root.root().join("dangling")).unwrap();
+        assert_eq!(root.resolve(&RemoteFileName("link/map.tmx".to_string())), Err(RemoteFilePathError::Escapes));
+        assert_eq!(root.resolve(&RemoteFileName("dangling".to_string())), Err(RemoteFilePathError::Escapes));
+        assert!(root.write(&RemoteFileName("link/map.tmx".to_string()), b"map").is_err());
+        assert!(!outside.root().join("map.tmx").exists());
+    }
+}
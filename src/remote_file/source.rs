@@ -0,0 +1,115 @@
+// The `remote://` asset source. Clients keep the files the server sent in a cache separate from
+// their own `assets/`, and load levels and scripts from it, so what's shown is always the
+// server's version and local edits never mix with it.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::{
+    asset::{
+        io::{AssetReader, AssetReaderError, AssetSource, PathStream, Reader, VecReader},
+        AssetPath,
+    },
+    prelude::*,
+};
+
+use super::{RemoteFileMetadata, RemoteFileName, RemoteFileStore};
+
+pub const REMOTE_FILE_SOURCE: &str = "remote";
+
+// Where the client keeps the files the server sent, relative to the working directory
+pub const REMOTE_FILE_CACHE_PATH: &str = "remote_file_cache";
+
+// The path of a remotefile in the `remote://` source
+pub fn remote_asset_path(file_name: &str) -> AssetPath<'static> {
+    AssetPath::from(file_name.to_string()).with_source(REMOTE_FILE_SOURCE)
+}
+
+// The client's copy of the server's files. A resource of its own, so it can't be mistaken for
+// the server's store when both plugins are in the same app.
+#[derive(Resource, Clone)]
+pub struct RemoteFileCache<S: RemoteFileStore>(pub S);
+
+impl<S: RemoteFileStore> RemoteFileStore for RemoteFileCache<S> {
+    fn local_path(&self) -> Option<&Path> {
+        self.0.local_path()
+    }
+
+    fn metadata(&self, file_name: &RemoteFileName) -> std::io::Result<RemoteFileMetadata> {
+        self.0.metadata(file_name)
+    }
+
+    fn read(&self, file_name: &RemoteFileName) -> std::io::Result<Vec<u8>> {
+        self.0.read(file_name)
+    }
+
+    fn write(&self, file_name: &RemoteFileName, data: &[u8]) -> std::io::Result<()> {
+        self.0.write(file_name, data)
+    }
+
+    fn remove(&self, file_name: &RemoteFileName) -> std::io::Result<()> {
+        self.0.remove(file_name)
+    }
+
+    fn rename(&self, from: &RemoteFileName, to: &RemoteFileName) -> std::io::Result<()> {
+        self.0.rename(from, to)
+    }
+
+    fn restore(&self, file_name: &RemoteFileName, hash: &str) -> std::io::Result<bool> {
+        self.0.restore(file_name, hash)
+    }
+
+    fn keep(&self, room: u64, file_names: Vec<RemoteFileName>) {
+        self.0.keep(room, file_names)
+    }
+}
+
+// Serves `remote://` paths straight from a store
+struct RemoteFileAssetReader<S: RemoteFileStore> {
+    store: S,
+}
+
+impl<S: RemoteFileStore> AssetReader for RemoteFileAssetReader<S> {
+    async fn read<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        let Some(file_name) = path.to_str() else {
+            return Err(AssetReaderError::NotFound(path.to_path_buf()));
+        };
+        match self.store.read(&RemoteFileName(file_name.to_string())) {
+            Ok(data) => Ok(Box::new(VecReader::new(data))),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::InvalidInput) => {
+                Err(AssetReaderError::NotFound(path.to_path_buf()))
+            }
+            Err(e) => Err(AssetReaderError::Io(Arc::new(e))),
+        }
+    }
+
+    // Remotefiles never have .meta files
+    async fn read_meta<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        Err(AssetReaderError::NotFound(path.to_path_buf()))
+    }
+
+    async fn read_directory<'a>(&'a self, path: &'a Path) -> Result<Box<PathStream>, AssetReaderError> {
+        Err(AssetReaderError::NotFound(PathBuf::from(path)))
+    }
+
+    async fn is_directory<'a>(&'a self, _path: &'a Path) -> Result<bool, AssetReaderError> {
+        Ok(false)
+    }
+}
+
+// Asset sources have to exist before the AssetPlugin is built, so this is called while the
+// client app is created rather than from RemoteFileClientPlugin
+pub(crate) fn register_remote_file_source<S: RemoteFileStore>(app: &mut App, store: S) {
+    app.register_asset_source(
+        REMOTE_FILE_SOURCE,
+        AssetSource::build().with_reader(move || Box::new(RemoteFileAssetReader { store: store.clone() })),
+    );
+}
+
+// Reload everything that was loaded from a remotefile that just changed
+pub(crate) fn remotefile_reload(asset_server: &AssetServer, file_name: &RemoteFileName) {
+    asset_server.reload(remote_asset_path(&file_name.0));
+}
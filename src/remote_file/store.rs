@@ -0,0 +1,112 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{sandbox::invalid_path, RemoteFileName, SandboxedAssetRoot};
+
+// Where RemoteFiles are kept. File names may come from the network, so every implementation
+// must refuse the ones SandboxedAssetRoot::sanitize refuses. The server and client plugins insert
+// the store as a resource and only ever touch files through it.
+pub trait RemoteFileStore: Resource + Clone {
+    // The directory the files are in, if they're on disk and can be watched for local edits
+    fn local_path(&self) -> Option<&Path>;
+
+    fn metadata(&self, file_name: &RemoteFileName) -> std::io::Result<RemoteFileMetadata>;
+
+    fn read(&self, file_name: &RemoteFileName) -> std::io::Result<Vec<u8>>;
+
+    // Write a file, replacing any previous version
+    fn write(&self, file_name: &RemoteFileName, data: &[u8]) -> std::io::Result<()>;
+
+    fn remove(&self, file_name: &RemoteFileName) -> std::io::Result<()>;
+
+    // Move a file, replacing any file that has the new name
+    fn rename(&self, from: &RemoteFileName, to: &RemoteFileName) -> std::io::Result<()>;
+
+    // Give a file the contents with the hash again if the store still has them, so they don't
+    // need to be downloaded. Returns whether it did.
+    fn restore(&self, _file_name: &RemoteFileName, _hash: &str) -> std::io::Result<bool> {
+        Ok(false)
+    }
+
+    // The files of a room in view, which a store that removes files to save space must keep.
+    // An empty list means the room is out of view.
+    fn keep(&self, _room: u64, _file_names: Vec<RemoteFileName>) {}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteFileMetadata {
+    pub len: u64,
+    // None if the store can't tell, the file is then hashed again every time it's checked
+    pub modified: Option<SystemTime>,
+    // The SHA-256 of the contents, if the store knows it without reading the file
+    pub hash: Option<String>,
+}
+
+// Files kept in memory, e.g. for tests or a server that doesn't need them on disk. Clones share
+// the same files, so a handle kept outside the app sees what the plugins write.
+#[derive(Resource, Clone, Default)]
+pub struct MemoryRemoteFileStore {
+    files: Arc<RwLock<HashMap<RemoteFileName, Vec<u8>>>>,
+}
+
+impl MemoryRemoteFileStore {
+    pub fn file_names(&self) -> Vec<RemoteFileName> {
+        self.files.read().unwrap().keys().cloned().collect()
+    }
+}
+
+impl RemoteFileStore for MemoryRemoteFileStore {
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn metadata(&self, file_name: &RemoteFileName) -> std::io::Result<RemoteFileMetadata> {
+        let file_name = sanitize(file_name)?;
+        let files = self.files.read().unwrap();
+        let data = files.get(&file_name).ok_or_else(|| not_found(&file_name))?;
+        Ok(RemoteFileMetadata { len: data.len() as u64, modified: None, hash: None })
+    }
+
+    fn read(&self, file_name: &RemoteFileName) -> std::io::Result<Vec<u8>> {
+        let file_name = sanitize(file_name)?;
+        let files = self.files.read().unwrap();
+        files.get(&file_name).cloned().ok_or_else(|| not_found(&file_name))
+    }
+
+    fn write(&self, file_name: &RemoteFileName, data: &[u8]) -> std::io::Result<()> {
+        let file_name = sanitize(file_name)?;
+        self.files.write().unwrap().insert(file_name, data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, file_name: &RemoteFileName) -> std::io::Result<()> {
+        let file_name = sanitize(file_name)?;
+        match self.files.write().unwrap().remove(&file_name) {
+            Some(_) => Ok(()),
+            None => Err(not_found(&file_name)),
+        }
+    }
+
+    fn rename(&self, from: &RemoteFileName, to: &RemoteFileName) -> std::io::Result<()> {
+        let from = sanitize(from)?;
+        let to = sanitize(to)?;
+        let mut files = self.files.write().unwrap();
+        let data = files.remove(&from).ok_or_else(|| not_found(&from))?;
+        files.insert(to, data);
+        Ok(())
+    }
+}
+
+pub(super) fn sanitize(file_name: &RemoteFileName) -> std::io::Result<RemoteFileName> {
+    SandboxedAssetRoot::sanitize(file_name).map_err(invalid_path)
+}
+
+pub(super) fn not_found(file_name: &RemoteFileName) -> Error {
+    Error::new(ErrorKind::NotFound, format!("No remotefile {:?}", file_name.0))
+}
@@ -0,0 +1,458 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{codec::{compress, decompress, RemoteFileCodec}, delta::RemoteFileDelta, hash_bytes, RemoteFileName, RemoteFileRejection};
+
+// Size of a single RemoteFileChunk payload. Small enough that a lost packet only costs one
+// fragment resend, large enough that a 20 MB tileset is ~1300 messages.
+pub(crate) const CHUNK_SIZE: usize = 16 * 1024;
+
+// Number of chunks a single outgoing transfer may queue per update, so big files trickle out
+// instead of flooding the send buffer in one frame
+pub(crate) const CHUNKS_PER_UPDATE: usize = 8;
+
+// A slice of a file that is being transferred. Chunks can arrive in any order, the receiver
+// reassembles them using the offset and total_len. The file is compressed as a whole before it's
+// split, so offset and total_len are positions in the compressed payload.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileChunk {
+    pub(crate) transfer_id: u64,
+    pub(crate) file_name: RemoteFileName,
+    // Hash of the complete file, used to verify the reassembled data and to resume transfers
+    pub(crate) hash: String,
+    // Hash of the version the sender edited, empty if it had none. Only set on uploads.
+    pub(crate) base_hash: String,
+    // The server's signature of the file name and hash, see origin.rs. Empty on uploads.
+    pub(crate) server_signature: Vec<u8>,
+    pub(crate) codec: RemoteFileCodec,
+    // Size of the complete file once decompressed
+    pub(crate) file_len: u64,
+    pub(crate) offset: u64,
+    pub(crate) total_len: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+// Sent with a RemoteFileHash when the client already holds part of the file from an
+// interrupted transfer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteFileResume {
+    pub(crate) hash: String,
+    pub(crate) codec: RemoteFileCodec,
+    pub(crate) offset: u64,
+}
+
+// ################################################################################################
+
+// A version of a file compressed for sending. Cheap to clone, so every transfer of the same
+// version and codec can share one instead of compressing the file again.
+#[derive(Clone)]
+pub(crate) struct RemoteFilePayload {
+    hash: String,
+    file_len: u64,
+    codec: RemoteFileCodec,
+    data: Arc<Vec<u8>>,
+}
+
+impl RemoteFilePayload {
+    pub(crate) fn new(file_name: &RemoteFileName, data: &[u8], codec: RemoteFileCodec) -> Self {
+        let (codec, payload) = compress(codec, data);
+        if codec != RemoteFileCodec::None {
+            info!("Compressed remotefile {:?} with {:?}: {} -> {} bytes", file_name.0, codec, data.len(), payload.len());
+        }
+        Self { hash: hash_bytes(data), file_len: data.len() as u64, codec, data: Arc::new(payload) }
+    }
+
+    // Whether a transfer still uses it, besides the holder of this one
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.data) > 1
+    }
+}
+
+// The sending side of a transfer. A file is either sent in chunks, or as a single delta against
+// the receiver's version, queued the same way so a newer version never overtakes an older one.
+pub(crate) struct OutgoingTransfer {
+    pub(crate) transfer_id: u64,
+    pub(crate) file_name: RemoteFileName,
+    pub(crate) hash: String,
+    base_hash: String,
+    server_signature: Vec<u8>,
+    pub(crate) codec: RemoteFileCodec,
+    // Sent for a room the client is heading to, only once nothing else is waiting
+    pub(crate) prefetch: bool,
+    file_len: u64,
+    // The compressed payload
+    data: Arc<Vec<u8>>,
+    offset: usize,
+    started: bool,
+    delta: Option<RemoteFileDelta>,
+}
+
+impl OutgoingTransfer {
+    pub(crate) fn new(transfer_id: u64, file_name: RemoteFileName, payload: RemoteFilePayload) -> Self {
+        Self {
+            transfer_id,
+            file_name,
+            hash: payload.hash,
+            base_hash: String::new(),
+            server_signature: Vec::new(),
+            codec: payload.codec,
+            prefetch: false,
+            file_len: payload.file_len,
+            data: payload.data,
+            offset: 0,
+            started: false,
+            delta: None,
+        }
+    }
+
+    pub(crate) fn delta(transfer_id: u64, delta: RemoteFileDelta) -> Self {
+        Self {
+            transfer_id,
+            file_name: delta.file_name.clone(),
+            hash: delta.hash.clone(),
+            base_hash: delta.base_hash.clone(),
+            server_signature: delta.server_signature.clone(),
+            codec: delta.codec,
+            prefetch: false,
+            file_len: 0,
+            data: Arc::new(Vec::new()),
+            offset: 0,
+            started: false,
+            delta: Some(RemoteFileDelta { transfer_id, ..delta }),
+        }
+    }
+
+    pub(crate) fn with_base_hash(mut self, base_hash: String) -> Self {
+        self.base_hash = base_hash;
+        self
+    }
+
+    pub(crate) fn with_server_signature(mut self, server_signature: Vec<u8>) -> Self {
+        self.server_signature = server_signature;
+        self
+    }
+
+    // Skip the part of the file the receiver already has. The offset is rounded down to a chunk
+    // boundary so the receiver's chunk bookkeeping stays aligned, and at least the last chunk is
+    // always resent so the receiver can complete the transfer.
+    pub(crate) fn resume_from(mut self, offset: u64) -> Self {
+        let offset = (offset as usize).min(self.data.len().saturating_sub(1));
+        self.offset = offset - offset % CHUNK_SIZE;
+        self
+    }
+
+    // Empty files still send one (empty) chunk so the receiver learns about them
+    pub(crate) fn is_finished(&self) -> bool {
+        self.started && self.offset >= self.data.len()
+    }
+
+    // The delta of a delta transfer, which is then finished
+    pub(crate) fn take_delta(&mut self) -> Option<RemoteFileDelta> {
+        let delta = self.delta.take()?;
+        self.started = true;
+        Some(delta)
+    }
+
+    pub(crate) fn next_chunk(&mut self) -> Option<RemoteFileChunk> {
+        if self.is_finished() || self.delta.is_some() {
+            return None;
+        }
+        let end = (self.offset + CHUNK_SIZE).min(self.data.len());
+        let chunk = RemoteFileChunk {
+            transfer_id: self.transfer_id,
+            file_name: self.file_name.clone(),
+            hash: self.hash.clone(),
+            base_hash: self.base_hash.clone(),
+            server_signature: self.server_signature.clone(),
+            codec: self.codec,
+            file_len: self.file_len,
+            offset: self.offset as u64,
+            total_len: self.data.len() as u64,
+            data: self.data[self.offset..end].to_vec(),
+        };
+        self.offset = end;
+        self.started = true;
+        Some(chunk)
+    }
+}
+
+// ################################################################################################
+
+// The receiving side of a transfer. Kept around after a disconnect, so the transfer can be
+// resumed from resume_offset() once the connection is back.
+pub(crate) struct IncomingTransfer {
+    // The sender's latest transfer of this version, a resumed transfer gets a new id
+    transfer_id: u64,
+    pub(crate) hash: String,
+    pub(crate) codec: RemoteFileCodec,
+    file_len: u64,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    received_count: usize,
+}
+
+impl IncomingTransfer {
+    // Start reassembling the transfer a chunk belongs to. Files bigger than max_size, compressed
+    // or not, are refused before anything is allocated for them.
+    pub(crate) fn new(chunk: &RemoteFileChunk, max_size: u64) -> Result<Self, RemoteFileRejection> {
+        let size = chunk.total_len.max(chunk.file_len);
+        if size > max_size {
+            return Err(RemoteFileRejection::TooLarge { size, max_size });
+        }
+        let num_chunks = (chunk.total_len as usize).div_ceil(CHUNK_SIZE).max(1);
+        Ok(Self {
+            transfer_id: chunk.transfer_id,
+            hash: chunk.hash.clone(),
+            codec: chunk.codec,
+            file_len: chunk.file_len,
+            data: vec![0; chunk.total_len as usize],
+            received: vec![false; num_chunks],
+            received_count: 0,
+        })
+    }
+
+    // Whether a chunk belongs to this transfer, or to a newer version of the file
+    pub(crate) fn matches(&self, chunk: &RemoteFileChunk) -> bool {
+        self.hash == chunk.hash
+            && self.codec == chunk.codec
+            && self.file_len == chunk.file_len
+            && self.data.len() as u64 == chunk.total_len
+    }
+
+    // Store a chunk, returns true once every chunk has been received. Chunks that don't fit the
+    // transfer, or that are left over from an earlier transfer of the same version, are ignored.
+    pub(crate) fn insert(&mut self, chunk: &RemoteFileChunk) -> bool {
+        if !self.matches(chunk) || chunk.transfer_id < self.transfer_id {
+            return self.is_complete();
+        }
+        // Every chunk is CHUNK_SIZE long, except the last one
+        let total_len = self.data.len() as u64;
+        let expected_len = total_len.saturating_sub(chunk.offset).min(CHUNK_SIZE as u64);
+        let fits = chunk.offset % CHUNK_SIZE as u64 == 0
+            && chunk.offset <= total_len
+            && chunk.data.len() as u64 == expected_len;
+        if !fits {
+            return self.is_complete();
+        }
+        self.transfer_id = chunk.transfer_id;
+        let offset = chunk.offset as usize;
+        let index = offset / CHUNK_SIZE;
+        if index < self.received.len() && !self.received[index] {
+            self.data[offset..offset + chunk.data.len()].copy_from_slice(&chunk.data);
+            self.received[index] = true;
+            self.received_count += 1;
+        }
+        self.is_complete()
+    }
+
+    // The sender's transfer ids start over when it restarts, so after a reconnect any transfer of
+    // this version continues it
+    pub(crate) fn reconnected(&mut self) {
+        self.transfer_id = 0;
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.received_count == self.received.len()
+    }
+
+    // Bytes received so far and the total, of the compressed payload
+    pub(crate) fn progress(&self) -> (u64, u64) {
+        let received = (self.received_count * CHUNK_SIZE).min(self.data.len());
+        (received as u64, self.data.len() as u64)
+    }
+
+    // Length of the contiguous prefix that has been received
+    pub(crate) fn resume_offset(&self) -> u64 {
+        let chunks = self.received.iter().take_while(|received| **received).count();
+        (chunks * CHUNK_SIZE).min(self.data.len()) as u64
+    }
+
+    // Returns the reassembled and decompressed file, or None if it does not match the announced hash
+    pub(crate) fn finish(self) -> Option<Vec<u8>> {
+        let data = match decompress(self.codec, &self.data, self.file_len as usize) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to decompress remotefile payload: {:?}", e);
+                return None;
+            }
+        };
+        if self.codec != RemoteFileCodec::None {
+            info!("Decompressed remotefile payload with {:?}: {} -> {} bytes", self.codec, self.data.len(), data.len());
+        }
+        (hash_bytes(&data) == self.hash).then_some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn outgoing(transfer_id: u64, data: &[u8]) -> OutgoingTransfer {
+        let file_name = RemoteFileName("map.tmx".to_string());
+        let payload = RemoteFilePayload::new(&file_name, data, RemoteFileCodec::None);
+        OutgoingTransfer::new(transfer_id, file_name, payload)
+    }
+
+    fn chunks(transfer: &mut OutgoingTransfer) -> Vec<RemoteFileChunk> {
+        std::iter::from_fn(|| transfer.next_chunk()).collect()
+    }
+
+    #[test]
+    fn splits_into_chunks() {
+        let data = file(CHUNK_SIZE * 2 + 100);
+        let chunks = chunks(&mut outgoing(1, &data));
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(), vec![0, CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64]);
+        assert_eq!(chunks[2].data.len(), 100);
+        assert!(chunks.iter().all(|chunk| chunk.total_len == data.len() as u64));
+    }
+
+    #[test]
+    fn empty_file_sends_one_chunk() {
+        let chunks = chunks(&mut outgoing(1, &[]));
+        assert_eq!(chunks.len(), 1);
+        let mut incoming = IncomingTransfer::new(&chunks[0], 1024).unwrap();
+        assert!(incoming.insert(&chunks[0]));
+        assert_eq!(incoming.finish(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let data = file(CHUNK_SIZE * 3 + 1);
+        let mut chunks = chunks(&mut outgoing(1, &data));
+        chunks.reverse();
+        let mut incoming = IncomingTransfer::new(&chunks[0], u64::MAX).unwrap();
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert!(!incoming.insert(chunk));
+        }
+        // Duplicates don't count twice
+        assert!(!incoming.insert(&rest[0]));
+        assert!(incoming.insert(last));
+        assert_eq!(incoming.finish(), Some(data));
+    }
+
+    #[test]
+    fn resumes_from_received_prefix() {
+        let data = file(CHUNK_SIZE * 4);
+        let first = chunks(&mut outgoing(1, &data));
+        let mut incoming = IncomingTransfer::new(&first[0], u64::MAX).unwrap();
+        incoming.insert(&first[0]);
+        incoming.insert(&first[1]);
+        incoming.insert(&first[3]);
+        assert_eq!(incoming.resume_offset(), 2 * CHUNK_SIZE as u64);
+
+        // Only the chunks after the prefix are sent again, under a new transfer id
+        let resumed = chunks(&mut outgoing(2, &data).resume_from(incoming.resume_offset()));
+        assert_eq!(resumed.first().map(|chunk| chunk.offset), Some(2 * CHUNK_SIZE as u64));
+        assert_eq!(resumed.len(), 2);
+        assert!(resumed.iter().all(|chunk| incoming.matches(chunk)));
+        let complete = resumed.iter().fold(false, |_, chunk| incoming.insert(chunk));
+        assert!(complete);
+        assert_eq!(incoming.finish(), Some(data));
+    }
+
+    #[test]
+    fn resume_always_resends_last_chunk() {
+        let data = file(CHUNK_SIZE * 2);
+        let resumed = chunks(&mut outgoing(2, &data).resume_from(data.len() as u64));
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].offset, CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn ignores_stale_transfer() {
+        let data = file(CHUNK_SIZE * 2);
+        let old = chunks(&mut outgoing(1, &data));
+        let new = chunks(&mut outgoing(2, &data));
+        let mut incoming = IncomingTransfer::new(&new[0], u64::MAX).unwrap();
+        incoming.insert(&new[0]);
+        assert!(!incoming.insert(&old[1]));
+        assert!(incoming.insert(&new[1]));
+    }
+
+    #[test]
+    fn delta_transfer_sends_only_the_delta() {
+        let delta = RemoteFileDelta {
+            transfer_id: 0,
+            file_name: RemoteFileName("map.tmx".to_string()),
+            base_hash: "base".to_string(),
+            hash: "new".to_string(),
+            server_signature: Vec::new(),
+            block_size: 512,
+            codec: RemoteFileCodec::None,
+            ops: Vec::new(),
+        };
+        let mut transfer = OutgoingTransfer::delta(3, delta);
+        assert!(!transfer.is_finished());
+        assert_eq!(transfer.next_chunk(), None);
+        assert_eq!(transfer.take_delta().map(|delta| delta.transfer_id), Some(3));
+        assert!(transfer.is_finished());
+        assert_eq!(transfer.take_delta(), None);
+        assert_eq!(transfer.next_chunk(), None);
+    }
+
+    #[test]
+    fn refuses_oversized_transfer() {
+        let chunks = chunks(&mut outgoing(1, &file(CHUNK_SIZE + 1)));
+        assert!(IncomingTransfer::new(&chunks[0], CHUNK_SIZE as u64).is_err());
+        assert!(IncomingTransfer::new(&chunks[0], CHUNK_SIZE as u64 + 1).is_ok());
+    }
+
+    #[test]
+    fn ignores_chunks_outside_transfer() {
+        let data = file(CHUNK_SIZE + 10);
+        let chunks = chunks(&mut outgoing(1, &data));
+        let mut incoming = IncomingTransfer::new(&chunks[0], u64::MAX).unwrap();
+        let misaligned = RemoteFileChunk { offset: 5, ..chunks[0].clone() };
+        let past_end = RemoteFileChunk { offset: 2 * CHUNK_SIZE as u64, data: vec![0; 10], ..chunks[1].clone() };
+        let too_long = RemoteFileChunk { data: vec![0; 11], ..chunks[1].clone() };
+        let other_version = RemoteFileChunk { hash: "other".to_string(), ..chunks[1].clone() };
+        for chunk in [misaligned, past_end, too_long, other_version] {
+            assert!(!incoming.insert(&chunk));
+        }
+        assert_eq!(incoming.progress().0, 0);
+        incoming.insert(&chunks[0]);
+        assert!(incoming.insert(&chunks[1]));
+        assert_eq!(incoming.finish(), Some(data));
+    }
+
+    #[test]
+    fn compressed_transfers_share_their_payload() {
+        let file_name = RemoteFileName("map.tmx".to_string());
+        let data: Vec<u8> = b"<tile id=\"1\"/>\n".iter().copied().cycle().take(CHUNK_SIZE * 4).collect();
+        let payload = RemoteFilePayload::new(&file_name, &data, RemoteFileCodec::Zstd);
+        assert!(!payload.is_shared());
+        let mut first = OutgoingTransfer::new(1, file_name.clone(), payload.clone());
+        let second = OutgoingTransfer::new(2, file_name, payload.clone());
+        assert!(payload.is_shared());
+        drop(second);
+
+        let chunks = chunks(&mut first);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].codec, RemoteFileCodec::Zstd);
+        assert_eq!(chunks[0].file_len, data.len() as u64);
+        let mut incoming = IncomingTransfer::new(&chunks[0], data.len() as u64).unwrap();
+        assert!(incoming.insert(&chunks[0]));
+        assert_eq!(incoming.finish(), Some(data));
+    }
+
+    #[test]
+    fn refuses_payloads_that_decompress_past_their_size() {
+        let data = vec![0; CHUNK_SIZE * 4];
+        let file_name = RemoteFileName("map.tmx".to_string());
+        let chunks = chunks(&mut OutgoingTransfer::new(1, file_name.clone(), RemoteFilePayload::new(&file_name, &data, RemoteFileCodec::Zstd)));
+        // The whole file is refused up front, a lie about its size only fails when decoding
+        assert!(IncomingTransfer::new(&chunks[0], data.len() as u64 - 1).is_err());
+        let lie = RemoteFileChunk { file_len: 10, ..chunks[0].clone() };
+        let mut incoming = IncomingTransfer::new(&lie, data.len() as u64).unwrap();
+        assert!(incoming.insert(&lie));
+        assert_eq!(incoming.finish(), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc::{channel, Receiver}, Mutex},
+};
+
+use bevy::prelude::*;
+use glob::{MatchOptions, Pattern};
+use notify::{event::{EventKind, ModifyKind, RenameMode}, RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::{source::register_remote_file_source, RemoteFileName, RemoteFileStore, SandboxedAssetRoot};
+
+// Watches the whole asset root, so every kind of asset is uploaded when it's edited on a client,
+// and files deleted or renamed on either side reach everyone else
+#[derive(Resource)]
+pub struct RemoteFileWatcher {
+    root: PathBuf,
+    events: Mutex<Receiver<notify::Result<notify::Event>>>,
+    // Kept alive for as long as the resource exists, dropping it stops the watch
+    _watcher: Mutex<RecommendedWatcher>,
+}
+
+impl RemoteFileWatcher {
+    pub(crate) fn new(root: &Path) -> notify::Result<Self> {
+        let root = root.canonicalize()?;
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        Ok(Self {
+            root,
+            events: Mutex::new(receiver),
+            _watcher: Mutex::new(watcher),
+        })
+    }
+
+    // Files that were written, removed or renamed since the last call, in the order it happened
+    pub(crate) fn changes(&self) -> Vec<RemoteFileChange> {
+        let mut changes: Vec<RemoteFileChange> = Vec::new();
+        let events = self.events.lock().unwrap();
+        for event in events.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("RemoteFile watcher error: {:?}", e);
+                    continue;
+                }
+            };
+            let file_names: Vec<RemoteFileName> = event.paths.iter().filter_map(|path| self.file_name(path)).collect();
+            let change = match (&event.kind, &file_names[..]) {
+                (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                    RemoteFileChange::Renamed { from: from.clone(), to: to.clone() }
+                }
+                (EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any | ModifyKind::Name(_)), [file_name])
+                    | (EventKind::Remove(_), [file_name]) => {
+                    // Renames reported as separate halves look like a removal and a new file
+                    if self.root.join(&file_name.0).is_file() {
+                        RemoteFileChange::Written(file_name.clone())
+                    } else if matches!(event.kind, EventKind::Create(_)) {
+                        continue;
+                    } else {
+                        RemoteFileChange::Removed(file_name.clone())
+                    }
+                }
+                _ => continue,
+            };
+            // Editors often write a file several times in a row
+            if changes.last() != Some(&change) {
+                changes.push(change);
+            }
+        }
+        changes
+    }
+
+    fn file_name(&self, path: &Path) -> Option<RemoteFileName> {
+        if path.is_dir() {
+            return None;
+        }
+        let relative = path.strip_prefix(&self.root).ok()?;
+        SandboxedAssetRoot::sanitize(&RemoteFileName(relative.to_str()?.to_string())).ok()
+    }
+}
+
+// The directory a client edits files in. Changes made there are uploaded, while the files the
+// server sends go to the RemoteFileCache.
+#[derive(Resource, Clone, Debug)]
+pub struct RemoteFileWorkspace(pub SandboxedAssetRoot);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RemoteFileChange {
+    Written(RemoteFileName),
+    Removed(RemoteFileName),
+    Renamed { from: RemoteFileName, to: RemoteFileName },
+}
+
+// Asset paths that are never uploaded, e.g. files that are different on every client
+#[derive(Resource, Default)]
+pub struct RemoteFileIgnore {
+    patterns: Vec<Pattern>,
+}
+
+impl RemoteFileIgnore {
+    pub fn is_ignored(&self, file_name: &RemoteFileName) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.patterns.iter().any(|pattern| pattern.matches_with(&file_name.0, options))
+    }
+}
+
+pub trait RemoteFileAppExt {
+    // Don't upload local changes to files matching the glob, relative to assets/
+    fn ignore_remote_files(&mut self, pattern: &str) -> &mut Self;
+
+    // Serve the store as the `remote://` asset source, must be called before the AssetPlugin is added
+    fn register_remote_file_source<S: RemoteFileStore>(&mut self, store: S) -> &mut Self;
+}
+
+impl RemoteFileAppExt for App {
+    fn ignore_remote_files(&mut self, pattern: &str) -> &mut Self {
+        match Pattern::new(pattern) {
+            Ok(pattern) => self.world_mut().get_resource_or_insert_with(RemoteFileIgnore::default).patterns.push(pattern),
+            Err(e) => error!("Invalid remotefile ignore pattern {:?}: {:?}", pattern, e),
+        }
+        self
+    }
+
+    fn register_remote_file_source<S: RemoteFileStore>(&mut self, store: S) -> &mut Self {
+        register_remote_file_source(self, store);
+        self
+    }
+}
@@ -1,11 +1,17 @@
+use std::collections::BTreeMap;
+
 use bevy::{ecs::entity::MapEntities, prelude::*};
 use bevy_ecs_tilemap_plugin::helpers::tiled;
 use bevy_mod_scripting::prelude::{CodeAsset, LuaFile, Script, ScriptCollection};
-use interest_management::client::{ComponentSyncMode, ConnectionManager, Interpolated, Predicted};
-use lightyear::{prelude::{server::{Replicate, SyncTarget}, AppComponentExt, ChannelDirection, NetworkRelevanceMode, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
+use bevy_mod_scripting_plugin::console_integration::ScriptStateChanged;
+use interest_management::{client::{ComponentSyncMode, ConnectionManager, Interpolated, Predicted}, server::{get_grid_position, get_room_id_from_grid_position, Global}, shared::Position};
+use lightyear::{prelude::{server::{Replicate, SyncTarget}, AppComponentExt, AppMessageExt, ChannelDirection, NetworkRelevanceMode, ReplicationGroup}, shared::replication::network_target::NetworkTarget};
 use serde::{Deserialize, Serialize};
 
-use crate::remote_file::remotefile_modified;
+use crate::{console::ServerConsoleLine, player::Channel1, remote_file::{remote_asset_path, RemoteFileLoading}};
+
+// Longest key or value a script may keep in the ScriptState
+const MAX_SCRIPT_STATE_LEN: usize = 4096;
 
 // Script
 #[derive(Bundle)]
@@ -61,6 +67,15 @@ impl Default for ScriptFile {
     }
 }
 
+// A script called set_state(key, value), sent by every client running it. A None value removes
+// the key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScriptStateSet {
+    pub script: String,
+    pub key: String,
+    pub value: Option<String>,
+}
+
 // and deriving the `MapEntities` trait for the component.
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq, Reflect)]
 pub struct ScriptParent(pub Entity);
@@ -86,6 +101,8 @@ impl Plugin for ScriptSharedPlugin {
             .add_map_entities()
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
+
+        app.register_message::<ScriptStateSet>(ChannelDirection::ClientToServer);
     }
 }
 
@@ -96,6 +113,100 @@ pub struct ScriptServerPlugin;
 impl Plugin for ScriptServerPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<ConnectionManager>();
+        app.init_resource::<ScriptState>();
+        app.add_systems(Update, (script_state_console, script_state_received));
+    }
+}
+
+// Values kept on the server for each script, by script file name and then key. It's saved with
+// the world state, so it outlives the level the script runs in and server restarts. Scripts run
+// on the clients and change it with set_state(key, value), it's also changed by the `state`
+// command and server systems. Scripts can't read it back.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScriptState(BTreeMap<String, BTreeMap<String, String>>);
+
+impl ScriptState {
+    pub fn get(&self, script: &str, key: &str) -> Option<&str> {
+        self.0.get(script)?.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, script: &str, key: &str, value: impl Into<String>) {
+        self.0.entry(script.to_string()).or_default().insert(key.to_string(), value.into());
+    }
+
+    pub fn remove(&mut self, script: &str, key: &str) -> Option<String> {
+        let values = self.0.get_mut(script)?;
+        let value = values.remove(key);
+        if values.is_empty() {
+            self.0.remove(script);
+        }
+        value
+    }
+
+    pub fn values(&self, script: &str) -> impl Iterator<Item = (&str, &str)> {
+        self.0.get(script).into_iter().flatten().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+// `state <script>` lists the values of a script, `state <script> <key> [value]` sets one or,
+// without a value, removes it
+fn script_state_console(mut lines: EventReader<ServerConsoleLine>, mut state: ResMut<ScriptState>) {
+    for line in lines.read() {
+        let Some(args) = line.command("state") else {
+            continue;
+        };
+        match args[..] {
+            [script] => {
+                for (key, value) in state.values(script) {
+                    info!("{} {} = {:?}", script, key, value);
+                }
+            }
+            [script, key] => {
+                let value = state.remove(script, key);
+                info!("Removed {} {} (was {:?})", script, key, value);
+            }
+            [script, key, ..] => {
+                let value = args[2..].join(" ");
+                info!("Set {} {} = {:?}", script, key, value);
+                state.set(script, key, value);
+            }
+            _ => warn!("Usage: state <script> [<key> [value]]"),
+        }
+    }
+}
+
+// A script may only change its own values, from a client in the room of a level running it
+fn script_state_received(
+    mut reader: EventReader<lightyear::server::events::MessageEvent<ScriptStateSet>>,
+    mut state: ResMut<ScriptState>,
+    script_query: Query<(&ScriptParent, &ScriptFileName)>,
+    level_query: Query<&Position>,
+    global: Res<Global>,
+) {
+    for event in reader.read() {
+        let client_id = *event.context();
+        let message = &event.message;
+        let room_ids = global.client_id_to_room_ids.get(&client_id).cloned().unwrap_or_default();
+        let running = script_query.iter()
+            .filter(|(_, script_file_name)| script_file_name.0 == message.script)
+            .filter_map(|(parent, _)| level_query.get(parent.0).ok())
+            .any(|position| room_ids.contains(&get_room_id_from_grid_position(get_grid_position(position.0))));
+        if !running {
+            warn!("Client {:?} set state of script {:?}, which isn't running in its rooms", client_id, message.script);
+            continue;
+        }
+        let too_long = message.key.len() > MAX_SCRIPT_STATE_LEN
+            || message.value.as_ref().is_some_and(|value| value.len() > MAX_SCRIPT_STATE_LEN);
+        if too_long {
+            warn!("Client {:?} set state of script {:?} longer than {} bytes", client_id, message.script, MAX_SCRIPT_STATE_LEN);
+            continue;
+        }
+        match &message.value {
+            Some(value) => state.set(&message.script, &message.key, value.clone()),
+            None => {
+                state.remove(&message.script, &message.key);
+            }
+        }
     }
 }
 
@@ -108,23 +219,47 @@ impl Plugin for ScriptClientPlugin {
         app
         .add_systems(
             Update,
-        (script_spawn, remotefile_modified::<LuaFile>)
+        (script_spawn, script_state_send)
         );
     }
 }
 
+// Pass set_state calls of the scripts on to the server
+fn script_state_send(mut changes: EventReader<ScriptStateChanged>, mut client: ResMut<ConnectionManager>) {
+    for change in changes.read() {
+        let mut message = ScriptStateSet {
+            script: change.script.clone(),
+            key: change.key.clone(),
+            value: change.value.clone(),
+        };
+        client.send_message::<Channel1, ScriptStateSet>(&mut message).unwrap_or_else(|e| {
+            error!("Failed to send message: {:?}", e);
+        });
+    }
+}
+
+// Load the script once every file of its level's room matches the server
 fn script_spawn(
     mut commands: Commands,
-    mut script_query: Query<
+    script_query: Query<
         (&ScriptParent, &ScriptFileName),
-        Or<(Added<Interpolated>, Added<Predicted>)>,
+        Or<(With<Interpolated>, With<Predicted>)>,
     >,
+    level_query: Query<&Position, Without<ScriptCollection<LuaFile>>>,
+    loading: Res<RemoteFileLoading>,
     asset_server: Res<AssetServer>,
 ) {
-    for (parent, script_file_name) in &mut script_query {
+    for (parent, script_file_name) in &script_query {
+        let Ok(position) = level_query.get(parent.0) else {
+            continue;
+        };
+        let room_id = get_room_id_from_grid_position(get_grid_position(position.0));
+        if !loading.is_room_ready(room_id) {
+            continue;
+        }
         info!("Spawning script: {:?}", script_file_name.0);
         
-        let handle = asset_server.load::<LuaFile>(script_file_name.0.clone());
+        let handle = asset_server.load::<LuaFile>(remote_asset_path(&script_file_name.0));
         let script = Script::<LuaFile>::new(script_file_name.0.clone(), handle);
 
         commands.entity(parent.0).insert(ScriptCollection::<LuaFile> {